@@ -0,0 +1,42 @@
+// Cost of getting one config update into N sessions' hands as text: serializing it once per
+// session, versus once per broadcast and sharing the result as an `Arc<str>`, which is what
+// `WsManager` does.
+//
+// Run with `cargo bench --bench serialization`. Baseline from a development machine:
+//
+//   sessions    per_session    once_shared
+//   1           ~230 ns        ~220 ns
+//   10          ~2.2 µs        ~370 ns
+//   100         ~21 µs         ~1.7 µs
+//   1000        ~205 µs        ~16 µs
+
+use std::sync::Arc;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use subscriber_microservice::Config;
+
+const SESSION_COUNTS: &[usize] = &[1, 10, 100, 1000];
+
+fn serialization(c: &mut Criterion) {
+    let config = Config::builder().ball_color("rebeccapurple").movement_bounds(800, 600).build();
+    let mut group = c.benchmark_group("broadcast_serialization");
+    for &sessions in SESSION_COUNTS {
+        group.bench_with_input(BenchmarkId::new("per_session", sessions), &sessions, |b, &sessions| {
+            b.iter(|| {
+                let frames: Vec<String> = (0..sessions).map(|_| serde_json::to_string(&config).unwrap()).collect();
+                black_box(frames)
+            });
+        });
+        group.bench_with_input(BenchmarkId::new("once_shared", sessions), &sessions, |b, &sessions| {
+            b.iter(|| {
+                let shared: Arc<str> = serde_json::to_string(&config).unwrap().into();
+                let frames: Vec<Arc<str>> = (0..sessions).map(|_| shared.clone()).collect();
+                black_box(frames)
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, serialization);
+criterion_main!(benches);
@@ -0,0 +1,173 @@
+use std::future::{ready, Future, Ready};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::HeaderMap;
+use ipnet::IpNet;
+use log::debug;
+
+use crate::ApiError;
+
+// Which client addresses may reach a route, as CIDR ranges. A denied range always wins; past
+// that, an empty allowlist lets everyone in and a non-empty one only the addresses it covers.
+#[derive(Debug, Clone, Default)]
+pub struct IpRules {
+    allowed: Vec<IpNet>,
+    denied: Vec<IpNet>,
+    trust_forwarded_for: bool, // Take the client address from `X-Forwarded-For` instead of the peer
+}
+
+impl IpRules {
+    // Parse the ranges; a bare address counts as a range of one.
+    pub fn new(allowed: &[String], denied: &[String], trust_forwarded_for: bool) -> Result<Self, String> {
+        Ok(Self { allowed: parse_ranges(allowed)?, denied: parse_ranges(denied)?, trust_forwarded_for })
+    }
+
+    // Whether any ranges are set, i.e. whether the rules can turn anyone away.
+    pub fn is_active(&self) -> bool {
+        !self.allowed.is_empty() || !self.denied.is_empty()
+    }
+
+    pub fn allows(&self, ip: Option<IpAddr>) -> bool {
+        match ip {
+            Some(ip) if self.denied.iter().any(|range| range.contains(&ip)) => false,
+            Some(ip) => self.allowed.is_empty() || self.allowed.iter().any(|range| range.contains(&ip)),
+            // An unknown address can't be in the allowlist
+            None => self.allowed.is_empty(),
+        }
+    }
+
+    // The client's address: the last `X-Forwarded-For` entry when the proxy in front is trusted,
+    // since that's the one it added itself, and the peer address otherwise.
+    pub fn client_ip(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> Option<IpAddr> {
+        if !self.trust_forwarded_for {
+            return peer;
+        }
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .last()
+            .and_then(|entry| entry.trim().parse().ok());
+        forwarded.or(peer)
+    }
+}
+
+fn parse_ranges(values: &[String]) -> Result<Vec<IpNet>, String> {
+    values
+        .iter()
+        .map(|value| {
+            value
+                .parse::<IpNet>()
+                .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("invalid address range {:?}", value))
+        })
+        .collect()
+}
+
+// Middleware applying shared `IpRules` to the routes it wraps. Clients outside them get
+// `403 Forbidden` without reaching the handler.
+#[derive(Clone)]
+pub struct IpAccess(Arc<IpRules>);
+
+impl IpAccess {
+    pub fn new(rules: Arc<IpRules>) -> Self {
+        Self(rules)
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for IpAccess
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = IpAccessMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(IpAccessMiddleware { service: Rc::new(service), rules: self.0.clone() }))
+    }
+}
+
+pub struct IpAccessMiddleware<S> {
+    service: Rc<S>,
+    rules: Arc<IpRules>,
+}
+
+impl<S, B> Service<ServiceRequest> for IpAccessMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let ip = self.rules.client_ip(req.headers(), req.peer_addr().map(|addr| addr.ip()));
+        if !self.rules.allows(ip) {
+            let client = ip.map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+            debug!("Turned away {} on {}", client, req.path());
+            // Answered here rather than returned as an error, so outer middleware still sees a response
+            return Box::pin(ready(Ok(req.error_response(ApiError::AddressNotAllowed(client)).map_into_right_body())));
+        }
+        let service = self.service.clone();
+        Box::pin(async move { service.call(req).await.map(ServiceResponse::map_into_left_body) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    use super::*;
+
+    fn rules(allowed: &[&str], denied: &[&str]) -> IpRules {
+        let list = |values: &[&str]| values.iter().map(|value| value.to_string()).collect::<Vec<_>>();
+        IpRules::new(&list(allowed), &list(denied), false).unwrap()
+    }
+
+    #[test]
+    fn cidr_ranges_match_the_addresses_they_cover() {
+        let rules = rules(&["10.0.0.0/8", "2001:db8::/32", "192.168.1.7"], &[]);
+        assert!(rules.allows("10.20.30.40".parse().ok()));
+        assert!(rules.allows("2001:db8::1".parse().ok()));
+        assert!(rules.allows("192.168.1.7".parse().ok()));
+        assert!(!rules.allows("192.168.1.8".parse().ok()));
+        assert!(!rules.allows("11.0.0.1".parse().ok()));
+    }
+
+    #[test]
+    fn denied_range_wins_over_the_allowlist() {
+        let rules = rules(&["10.0.0.0/8"], &["10.1.0.0/16"]);
+        assert!(rules.allows("10.2.0.1".parse().ok()));
+        assert!(!rules.allows("10.1.0.1".parse().ok()));
+    }
+
+    #[test]
+    fn malformed_range_is_an_error() {
+        assert!(IpRules::new(&["10.0.0.0/33".to_string()], &[], false).is_err());
+    }
+
+    #[actix_web::test]
+    async fn middleware_lets_allowed_clients_through_and_turns_denied_ones_away() {
+        let access = IpAccess::new(Arc::new(rules(&[], &["203.0.113.0/24"])));
+        let app = init_service(App::new().service(web::resource("/").wrap(access).to(HttpResponse::Ok))).await;
+
+        let allowed = TestRequest::get().uri("/").peer_addr("198.51.100.1:4000".parse().unwrap()).to_request();
+        assert_eq!(call_service(&app, allowed).await.status(), StatusCode::OK);
+        let denied = TestRequest::get().uri("/").peer_addr("203.0.113.9:4000".parse().unwrap()).to_request();
+        assert_eq!(call_service(&app, denied).await.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use actix::Addr;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use log::{info, warn};
+use tokio::sync::Notify;
+
+use crate::appstate::{read_lock, AppState};
+use crate::error::ApiError;
+use crate::events::ChangeSource;
+use crate::fetch::{commit_config, force_fetch_config};
+use crate::request_id::current_request_id;
+use crate::settings::Settings;
+use crate::websocket::{
+    ask_manager, CloseProfiles, ControlAction, DisplayControl, GetHistory, ListSessions, PauseBroadcasts, RestoreHistory, ResumeBroadcasts, SessionInfo, TargetedBroadcast, WsManager,
+};
+use crate::Config;
+
+// Check the request carries `Authorization: Bearer <ADMIN_TOKEN>`. Admin endpoints are disabled
+// entirely when no token is configured.
+pub fn require_admin(req: &HttpRequest, settings: &Settings) -> Result<(), ApiError> {
+    let Some(token) = &settings.admin_token else {
+        return Err(ApiError::AdminDisabled);
+    };
+    let provided = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match provided {
+        Some(provided) if constant_time_eq(provided.as_bytes(), token.as_bytes()) => Ok(()),
+        _ => Err(ApiError::Unauthorized),
+    }
+}
+
+// Compare without short-circuiting so response timing doesn't reveal how much of the token matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Fetch from the upstream right away, outside the poll loop and without resetting its timer.
+// Responds with the config now in effect, which is the stored one if the upstream reported no change.
+pub async fn refresh_config(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    require_admin(&req, &data.settings)?;
+    info!("Config refresh requested by admin (request {})", current_request_id().unwrap_or_default());
+    let fetched = force_fetch_config(data.clone())
+        .await
+        .map_err(|e| ApiError::UpstreamFailed(e.to_string()))?;
+    let config = match fetched {
+        Some(config) => config,
+        None => read_lock(&data.config).clone().ok_or(ApiError::ConfigUnavailable)?,
+    };
+    Ok(HttpResponse::Ok().json(config))
+}
+
+// Hold WebSocket broadcasts, e.g. while a rollout steps through intermediate configs.
+// The shared config keeps updating; only the fan-out waits.
+pub async fn pause_broadcasts(req: HttpRequest, data: web::Data<AppState>, ws_manager: web::Data<Addr<WsManager>>) -> Result<HttpResponse, ApiError> {
+    require_admin(&req, &data.settings)?;
+    ask_manager(&ws_manager, PauseBroadcasts, Duration::from_millis(data.settings.ws_manager_timeout_ms), "pause broadcasts").await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "paused": true })))
+}
+
+// Resume broadcasts, sending the latest config held during the pause as a single update.
+pub async fn resume_broadcasts(req: HttpRequest, data: web::Data<AppState>, ws_manager: web::Data<Addr<WsManager>>) -> Result<HttpResponse, ApiError> {
+    require_admin(&req, &data.settings)?;
+    let flushed = ask_manager(&ws_manager, ResumeBroadcasts, Duration::from_millis(data.settings.ws_manager_timeout_ms), "resume broadcasts").await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "paused": false, "flushed": flushed })))
+}
+
+// Body of `POST /admin/broadcast`.
+#[derive(Debug, Deserialize)]
+pub struct BroadcastRequest {
+    tag: Option<String>, // Only sessions that connected with this tag; omitted means all
+    config: Config,
+}
+
+// Push a config to a group of sessions without changing the shared config.
+pub async fn targeted_broadcast(req: HttpRequest, data: web::Data<AppState>, ws_manager: web::Data<Addr<WsManager>>, body: web::Json<BroadcastRequest>) -> Result<HttpResponse, ApiError> {
+    require_admin(&req, &data.settings)?;
+    let BroadcastRequest { tag, config } = body.into_inner();
+    config.validate().map_err(ApiError::InvalidConfig)?;
+    let delivered = ask_manager(&ws_manager, TargetedBroadcast { tag, config }, Duration::from_millis(data.settings.ws_manager_timeout_ms), "broadcast").await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "delivered": delivered })))
+}
+
+// Body of `POST /admin/display`.
+#[derive(Debug, Deserialize)]
+pub struct DisplayRequest {
+    action: ControlAction,
+}
+
+// Freeze or restart the balls on every connected display without touching the config.
+pub async fn control_display(req: HttpRequest, data: web::Data<AppState>, ws_manager: web::Data<Addr<WsManager>>, body: web::Json<DisplayRequest>) -> Result<HttpResponse, ApiError> {
+    require_admin(&req, &data.settings)?;
+    let action = body.into_inner().action;
+    let delivered = ask_manager(&ws_manager, DisplayControl(action), Duration::from_millis(data.settings.ws_manager_timeout_ms), "send display control").await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "action": action, "delivered": delivered })))
+}
+
+// Every connected WebSocket session with its handshake details, oldest first.
+pub async fn list_sessions(req: HttpRequest, data: web::Data<AppState>, ws_manager: web::Data<Addr<WsManager>>) -> Result<HttpResponse, ApiError> {
+    require_admin(&req, &data.settings)?;
+    let sessions = ask_manager(&ws_manager, ListSessions, Duration::from_millis(data.settings.ws_manager_timeout_ms), "list sessions").await?;
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+// The runtime state served by `GET /admin/state/export`. `POST /admin/state/import` takes the
+// same document back but only restores the config and history; sessions and counters are
+// informational.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    config: Option<Config>, // The config in effect, if one is loaded
+    #[serde(default)]
+    history: Vec<Config>,   // Replayed broadcasts, oldest first
+    #[serde(default, skip_deserializing)]
+    sessions: Vec<SessionInfo>,
+    #[serde(default, skip_deserializing)]
+    counters: StateCounters,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct StateCounters {
+    upstream_healthy: bool,
+    upstream_consecutive_failures: u32,
+    sessions: usize,
+}
+
+// Dump the config, replay history, sessions, and upstream counters, e.g. to reproduce an issue
+// elsewhere or seed a fresh instance.
+pub async fn export_state(req: HttpRequest, data: web::Data<AppState>, ws_manager: web::Data<Addr<WsManager>>) -> Result<HttpResponse, ApiError> {
+    require_admin(&req, &data.settings)?;
+    let history = ask_manager(&ws_manager, GetHistory, Duration::from_millis(data.settings.ws_manager_timeout_ms), "read the history").await?;
+    let sessions = ask_manager(&ws_manager, ListSessions, Duration::from_millis(data.settings.ws_manager_timeout_ms), "list sessions").await?;
+    let upstream = data.upstream_health();
+    let counters = StateCounters {
+        upstream_healthy: upstream.is_healthy(),
+        upstream_consecutive_failures: upstream.consecutive_failures(),
+        sessions: sessions.len(),
+    };
+    let config = read_lock(&data.config).clone();
+    Ok(HttpResponse::Ok().json(StateSnapshot { config, history, sessions, counters }))
+}
+
+// Restore the config and replay history from an exported snapshot. The config is validated and
+// broadcast like a fetched one; the history replaces the current one.
+pub async fn import_state(req: HttpRequest, data: web::Data<AppState>, ws_manager: web::Data<Addr<WsManager>>, body: web::Json<StateSnapshot>) -> Result<HttpResponse, ApiError> {
+    require_admin(&req, &data.settings)?;
+    let StateSnapshot { config, history, .. } = body.into_inner();
+    for entry in config.iter().chain(&history) {
+        entry.validate().map_err(ApiError::InvalidConfig)?;
+    }
+    warn!("Importing a state snapshot (request {})", current_request_id().unwrap_or_default());
+
+    // Keep fetches from committing in between
+    let _in_flight = data.fetch_lock.lock().await;
+    // History first, so the imported config isn't appended to the history it's about to replace
+    let restored = ask_manager(&ws_manager, RestoreHistory(history), Duration::from_millis(data.settings.ws_manager_timeout_ms), "restore the history").await?;
+    if let Some(config) = config {
+        commit_config(&data, config, ChangeSource::Admin);
+    }
+    let config = read_lock(&data.config).clone();
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "config": config, "history": restored })))
+}
+
+// Body of `POST /admin/upstream`.
+#[derive(Debug, Deserialize)]
+pub struct UpstreamRequest {
+    url: String,
+    #[serde(default)]
+    refresh: bool, // Fetch from the new upstream right away instead of at the next poll
+}
+
+// Switch the poll loop to a different upstream without a restart, e.g. for failover drills.
+pub async fn set_upstream(req: HttpRequest, data: web::Data<AppState>, body: web::Json<UpstreamRequest>) -> Result<HttpResponse, ApiError> {
+    require_admin(&req, &data.settings)?;
+    let UpstreamRequest { url, refresh } = body.into_inner();
+    check_upstream_url(&url)?;
+    warn!("Upstream switched to {} by admin", url);
+    if !data.source.set_upstream(url.clone()) {
+        return Err(ApiError::BadRequest("The config source has no upstream URL to change.".to_string()));
+    }
+    // The last answer came from the old upstream, so the next poll must ask the new one
+    *data.last_fetched.lock().unwrap() = None;
+    if refresh {
+        force_fetch_config(data.clone())
+            .await
+            .map_err(|e| ApiError::UpstreamFailed(e.to_string()))?;
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "upstream": url, "refreshed": refresh })))
+}
+
+// Refuse upstream URLs that don't parse or aren't http(s).
+fn check_upstream_url(url: &str) -> Result<(), ApiError> {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => Ok(()),
+        Ok(_) => Err(ApiError::BadRequest(format!("Upstream URL must be http or https: {}", url))),
+        Err(e) => Err(ApiError::BadRequest(format!("Invalid upstream URL {:?}: {}", url, e))),
+    }
+}
+
+// Replace the set of profiles without a restart, from a body mapping each name to its upstream,
+// e.g. `{"canary":"http://canary:8080/config"}`. Sessions following a profile that's left out
+// are closed; the rest stay connected, and profiles whose URL changed are polled from the new
+// one from their next poll.
+pub async fn set_profiles(req: HttpRequest, data: web::Data<AppState>, ws_manager: web::Data<Addr<WsManager>>, body: web::Json<BTreeMap<String, String>>) -> Result<HttpResponse, ApiError> {
+    require_admin(&req, &data.settings)?;
+    let urls = body.into_inner();
+    if urls.contains_key("") {
+        return Err(ApiError::BadRequest("Profile names can't be empty.".to_string()));
+    }
+    for url in urls.values() {
+        check_upstream_url(url)?;
+    }
+    warn!("Profiles set to {:?} by admin", urls);
+    let removed = data.profiles.replace(&urls).map_err(|e| ApiError::Internal(format!("Failed to set up the profiles: {}", e)))?;
+    let names: Vec<String> = removed.keys().cloned().collect();
+    let closed = if names.is_empty() {
+        0
+    } else {
+        ask_manager(&ws_manager, CloseProfiles(names.clone()), Duration::from_millis(data.settings.ws_manager_timeout_ms), "close the removed profiles' sessions").await?
+    };
+    // Dropped only now, so their sessions are told why before their broadcasts end
+    drop(removed);
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "profiles": data.profiles.names(), "removed": names, "closed": closed })))
+}
+
+// Start a graceful shutdown and return 202 right away; the server drains in the background.
+// The binary registers the `Notify` and waits on it; see `main.rs`.
+pub async fn shutdown(req: HttpRequest, data: web::Data<AppState>, shutdown: web::Data<Notify>) -> Result<HttpResponse, ApiError> {
+    require_admin(&req, &data.settings)?;
+    warn!("Shutdown requested by admin");
+    shutdown.notify_one();
+    Ok(HttpResponse::Accepted().json(serde_json::json!({ "shutting_down": true })))
+}
+
+// Return the last upstream payload byte-for-byte, with its original content type.
+pub async fn last_raw_payload(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    require_admin(&req, &data.settings)?;
+    match data.source.last_raw() {
+        Some(raw) => {
+            let mut response = HttpResponse::Ok();
+            if let Some(content_type) = raw.content_type {
+                response.content_type(content_type);
+            }
+            Ok(response.body(raw.body))
+        }
+        None => Err(ApiError::NotFound("No payload fetched yet.".to_string())),
+    }
+}
@@ -1,8 +1,145 @@
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+use std::time::{Duration, Instant};
 
+use log::warn;
+
+use crate::display::{CachedPage, DisplayRenderer, Displays, PageTemplate};
+use crate::error::ApiError;
+use crate::events::{ConfigEvents, EVENT_CAPACITY};
+use crate::health::{HealthCheck, UpstreamHealth};
+use crate::mapping::FieldMapping;
+use crate::metrics::{Metrics, CONFIG_LOCK_CONTENTION_TOTAL, UPSTREAM_CONSECUTIVE_FAILURES, UPSTREAM_HEALTHY};
+use crate::profiles::Profiles;
+use crate::ratelimit::UpgradeLimiter;
+use crate::reload::LiveSettings;
+use crate::settings::Settings;
+use crate::source::ConfigSource;
 use crate::Config;
 
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(1); // How often `read_config` tries the lock again
 
 pub struct AppState {
-    pub config: Arc<Mutex<Option<Config>>>,
+    pub config: Arc<RwLock<Option<Config>>>,        // Read on every request and connect, written only by fetches
+    pub settings: Settings,                         // As read at startup
+    pub live: LiveSettings,                         // The settings a SIGHUP can change; read these instead of `settings`
+    pub source: Box<dyn ConfigSource>,              // Where the poll loop gets configs from
+    pub fetch_lock: tokio::sync::Mutex<()>,         // Held for the whole of a fetch, so fetches never overlap
+    pub upstream: UpstreamHealth,                   // Consecutive fetch failures, for `/ready` and `/metrics`
+    pub health_check: Option<HealthCheck>,          // Separate upstream health probe; replaces `upstream` as the health signal when set
+    pub page_cache: Mutex<Option<CachedPage>>,      // Last rendered demo page and the configs it was rendered from
+    pub page_template: Option<PageTemplate>,        // Operator-supplied demo page; the built-in one when unset
+    pub displays: Displays,                         // Pages rendered from the config, each served at `/<name>`
+    pub last_fetched: Mutex<Option<Instant>>,       // When the upstream last answered; polls within `upstream_cache_ttl_secs` of it reuse the config in effect
+    pub events: ConfigEvents,                       // Announces every committed config change; subscribe to react to them
+    pub metrics: Arc<dyn Metrics>,                  // Where instrumentation goes; picked by `METRICS_BACKEND`
+    pub upgrades: UpgradeLimiter,                   // Slots for WebSocket upgrades in progress, per `WS_MAX_CONCURRENT_UPGRADES`
+    pub profiles: Profiles,                         // Named configs with upstreams of their own, polled next to the shared one
+}
+
+impl AppState {
+    pub fn new(settings: Settings, source: Box<dyn ConfigSource>) -> Self {
+        Self {
+            config: Arc::new(RwLock::new(None)),
+            fetch_lock: tokio::sync::Mutex::new(()),
+            upstream: UpstreamHealth::new(settings.upstream_failure_threshold),
+            health_check: None,
+            events: tokio::sync::broadcast::channel(EVENT_CAPACITY).0,
+            metrics: settings.metrics_backend.build(),
+            live: LiveSettings::new(&settings),
+            displays: Displays::builtin(&settings),
+            profiles: Profiles::new(&settings, FieldMapping::default()),
+            upgrades: UpgradeLimiter::new(
+                settings.ws_max_concurrent_upgrades,
+                settings.ws_upgrade_queue_size,
+                Duration::from_millis(settings.ws_upgrade_queue_ms),
+            ),
+            settings,
+            source,
+            page_cache: Mutex::new(None),
+            page_template: None,
+            last_fetched: Mutex::new(None),
+        }
+    }
+
+    // Report to `metrics` instead of the backend `METRICS_BACKEND` picked.
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    // Render the demo page from `template` instead of the built-in page.
+    pub fn with_page_template(mut self, template: PageTemplate) -> Self {
+        self.page_template = Some(template);
+        self
+    }
+
+    // Serve `renderer` at `/<name>` as well, or instead of the built-in display of that name.
+    pub fn with_display(mut self, name: impl Into<String>, renderer: Arc<dyn DisplayRenderer>) -> Self {
+        self.displays.register(name, renderer);
+        self
+    }
+
+    // Follow `profiles` instead of none, e.g. ones whose payloads need the shared field mapping.
+    pub fn with_profiles(mut self, profiles: Profiles) -> Self {
+        self.profiles = profiles;
+        self
+    }
+
+    // Judge the upstream by `health_check`'s probes rather than by config fetches.
+    pub fn with_health_check(mut self, health_check: HealthCheck) -> Self {
+        self.health_check = Some(health_check);
+        self
+    }
+
+    // The health `/ready` and `/metrics` report: the health check's if there is one, else the fetches'.
+    pub fn upstream_health(&self) -> &UpstreamHealth {
+        self.health_check.as_ref().map_or(&self.upstream, HealthCheck::health)
+    }
+
+    // The config in effect, for request handlers that shouldn't stall behind a writer: waits at
+    // most `config_lock_timeout_ms` for the lock, then gives up with `ApiError::Busy` and counts
+    // the contention. A timeout of 0 waits as long as it takes. A writer that panicked can't have
+    // left a half-written config behind, since commits only swap the whole value, so a poisoned
+    // lock is read as usual.
+    pub async fn read_config(&self, path: &'static str) -> Result<Option<Config>, ApiError> {
+        let timeout = Duration::from_millis(self.settings.config_lock_timeout_ms);
+        if timeout.is_zero() {
+            return Ok(read_lock(&self.config).clone());
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.config.try_read() {
+                Ok(config) => return Ok(config.clone()),
+                Err(TryLockError::Poisoned(e)) => {
+                    warn!("Config lock poisoned by a panicked writer, reading it anyway");
+                    return Ok(e.into_inner().clone());
+                }
+                Err(TryLockError::WouldBlock) if Instant::now() >= deadline => {
+                    warn!("Config lock held for over {:?}, giving up on {}", timeout, path);
+                    self.metrics.increment_counter(CONFIG_LOCK_CONTENTION_TOTAL, &[("path", path)]);
+                    return Err(ApiError::Busy);
+                }
+                Err(TryLockError::WouldBlock) => tokio::time::sleep(LOCK_RETRY_INTERVAL).await,
+            }
+        }
+    }
+
+    // Bring the upstream gauges up to date after a fetch or health check.
+    pub fn report_upstream_health(&self) {
+        let health = self.upstream_health();
+        self.metrics.set_gauge(UPSTREAM_HEALTHY, f64::from(u8::from(health.is_healthy())));
+        self.metrics.set_gauge(UPSTREAM_CONSECUTIVE_FAILURES, f64::from(health.consecutive_failures()));
+    }
+}
+
+// Read `lock` from sync code, which can't wait on `AppState::read_config`. As there, a poisoned
+// lock is read as usual: commits only swap the whole value, so a panicked writer can't have left
+// half of one behind.
+pub fn read_lock<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Write `lock`, poisoned or not, for the same reason as `read_lock`.
+pub fn write_lock<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
 }
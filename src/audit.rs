@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::events::{ChangeSource, ConfigChanged};
+use crate::Config;
+
+// Log target audit entries are written under, so they can be filtered, e.g. `RUST_LOG=info,audit=info`.
+pub const AUDIT_TARGET: &str = "audit";
+
+// One committed config change, as written to the log and the audit file.
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    pub timestamp_ms: u128,                     // When the change was seen, in milliseconds since the Unix epoch
+    pub source: ChangeSource,
+    pub changes: BTreeMap<String, FieldChange>, // Only the fields that changed
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct FieldChange {
+    pub old: Value, // `null` when the field was unset
+    pub new: Value, // `null` when the field was cleared
+}
+
+impl AuditEntry {
+    pub fn new(event: &ConfigChanged) -> Self {
+        let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+        Self { timestamp_ms, source: event.source, changes: field_changes(event.previous.as_ref(), &event.config) }
+    }
+}
+
+// Each field whose value differs in `after`, with its value before and after. Every field of the
+// first config loaded counts as changed.
+fn field_changes(before: Option<&Config>, after: &Config) -> BTreeMap<String, FieldChange> {
+    let old_fields = match before.map(serde_json::to_value) {
+        Some(Ok(Value::Object(fields))) => fields,
+        _ => Default::default(),
+    };
+    let changes = match before {
+        Some(before) => before.diff(after),
+        None => match serde_json::to_value(after) {
+            Ok(Value::Object(fields)) => fields,
+            _ => Default::default(),
+        },
+    };
+    changes
+        .into_iter()
+        .map(|(field, new)| {
+            let old = old_fields.get(&field).cloned().unwrap_or(Value::Null);
+            (field, FieldChange { old, new })
+        })
+        .collect()
+}
+
+// Write an audit entry for every config change announced on `events`, to the log at info level
+// and, with `file` set, as a line of JSON appended to it. Opening the file fails right away, so a
+// bad path stops startup instead of losing the trail. Must be called from within a Tokio runtime.
+pub fn start(file: Option<&str>, events: broadcast::Receiver<ConfigChanged>) -> io::Result<()> {
+    let file = file.map(|path| OpenOptions::new().create(true).append(true).open(path).map(File::from_std)).transpose()?;
+    tokio::spawn(audit_all(file, events));
+    Ok(())
+}
+
+async fn audit_all(mut file: Option<File>, mut events: broadcast::Receiver<ConfigChanged>) {
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                let entry = AuditEntry::new(&event);
+                let line = match serde_json::to_string(&entry) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        warn!(target: AUDIT_TARGET, "Failed to serialize an audit entry: {}", e);
+                        continue;
+                    }
+                };
+                // As structured fields for `tracing` subscribers, which the logger sees as `key=value` pairs
+                let changes = serde_json::to_string(&entry.changes).unwrap_or_default();
+                tracing::info!(target: AUDIT_TARGET, timestamp_ms = entry.timestamp_ms as u64, source = entry.source.label(), changes = %changes, "config changed");
+                if let Some(out) = &mut file {
+                    // Flushed per entry, so the trail is on disk even if the process dies right after
+                    let written = async { out.write_all(format!("{}\n", line).as_bytes()).await?; out.flush().await }.await;
+                    if let Err(e) = written {
+                        warn!(target: AUDIT_TARGET, "Failed to append to the audit file: {}", e);
+                    }
+                }
+            }
+            Err(RecvError::Lagged(skipped)) => warn!(target: AUDIT_TARGET, "Audit log fell behind and missed {} config changes", skipped),
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn entry_lists_only_the_changed_field() {
+        let event = ConfigChanged {
+            config: Config::builder().ball_size(80).build(),
+            previous: Some(Config::default()),
+            source: ChangeSource::Admin,
+        };
+        let entry = AuditEntry::new(&event);
+
+        assert_eq!(entry.source, ChangeSource::Admin);
+        assert_eq!(entry.changes.len(), 1);
+        assert_eq!(entry.changes["ball_size"], FieldChange { old: json!(50), new: json!(80) });
+    }
+}
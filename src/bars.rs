@@ -0,0 +1,86 @@
+use crate::display::{ball_size, escape_attribute, layer_style, DisplayRenderer, DEFAULT_BACKGROUND_COLOR};
+use crate::websocket::PROTOCOL_VERSION;
+use crate::Config;
+
+const BAR_WIDTH_PX: u32 = 20; // Width of every bar
+const BAR_HEIGHT_SCALE: u32 = 4; // Pixels of bar height per pixel of ball diameter
+
+// The bars page: one bar per ball, as tall as that ball is wide (scaled up) and in the ball color.
+// On every broadcast the page fetches itself again and swaps in the new bars, so it renders like
+// the first load, overrides included.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BarsRenderer;
+
+impl DisplayRenderer for BarsRenderer {
+    fn render(&self, config: &Config) -> String {
+        // Validated like `ball_color`, so it's safe to put in the style attribute as is
+        let background_color = config.background_color.as_deref().unwrap_or(DEFAULT_BACKGROUND_COLOR);
+        let config_attr = escape_attribute(&serde_json::to_string(config).unwrap_or_else(|_| "{}".to_string()));
+        let transition = config.transition_ms.map(|ms| format!("transition: height {}ms, background-color {}ms, opacity {}ms;", ms, ms, ms)).unwrap_or_default();
+        let bars = (0..config.number_of_balls)
+            .map(|i| {
+                let height = u32::from(ball_size(config, i)) * BAR_HEIGHT_SCALE;
+                format!("<div class='bar' style='height: {}px; background-color: {};{}'></div>", height, config.ball_color, layer_style(config))
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        format!(
+            "<!DOCTYPE html>
+        <html>
+        <head>
+            <title>Bars Display</title>
+            <style>
+                body {{ margin: 0; }}
+                #bars {{ display: flex; align-items: flex-end; gap: 4px; height: 100vh; padding: 0 8px; box-sizing: border-box; }}
+                .bar {{ width: {BAR_WIDTH_PX}px; {transition} }}
+            </style>
+        </head>
+        <body style='background-color: {background_color};' data-config='{config_attr}'>
+            <div id='bars'>
+            {bars}
+            </div>
+            <script>
+                // Render the page again with the same path and overrides, and take its bars
+                function refresh() {{
+                    fetch(location.pathname + location.search)
+                        .then(function(response) {{ return response.ok ? response.text() : null; }})
+                        .then(function(html) {{
+                            if (!html) {{
+                                return;
+                            }}
+                            var page = new DOMParser().parseFromString(html, 'text/html');
+                            document.body.style.backgroundColor = page.body.style.backgroundColor;
+                            document.body.dataset.config = page.body.dataset.config;
+                            document.getElementById('bars').replaceWith(page.getElementById('bars'));
+                        }})
+                        .catch(function(error) {{
+                            console.error('Refreshing the bars failed:', error);
+                        }});
+                }}
+
+                function connect() {{
+                    var conn = new WebSocket((location.protocol === 'https:' ? 'wss://' : 'ws://') + location.host + '/ws/');
+                    conn.onopen = function() {{
+                        conn.send(JSON.stringify({{ type: 'hello', version: {PROTOCOL_VERSION}, formats: ['json'] }}));
+                    }};
+                    conn.onmessage = function(evt) {{
+                        var message = JSON.parse(evt.data);
+                        // Only config updates change the bars
+                        if (message.error || ['error', 'hello_ack', 'echo', 'replay', 'control'].indexOf(message.type) >= 0) {{
+                            return;
+                        }}
+                        refresh();
+                    }};
+                    conn.onclose = function() {{
+                        setTimeout(connect, 2000);
+                    }};
+                }}
+
+                connect();
+            </script>
+        </body>
+        </html>"
+        )
+    }
+}
@@ -0,0 +1,367 @@
+use std::fmt::Debug;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+// The JSON shape is a contract with the demo page and external consumers: snake_case field
+// names, enums as snake_case strings. Pin any new field's or variant's wire name explicitly.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct Config {
+    pub ball_color: String,    // Color of the balls (e.g., "green", "red", "blue")
+    pub ball_size: u8,         // Diameter of the balls in pixels
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ball_size_min: Option<u8>, // With `ball_size_max`, each ball gets its own diameter in this range instead of `ball_size`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ball_size_max: Option<u8>, // Upper end of the range, inclusive
+    pub ball_speed: u8,        // Speed of the balls' movement (pixels per animation frame)
+    pub number_of_balls: u8,   // Total number of balls to display
+    #[serde(default)]
+    pub movement: Movement,    // How the balls move; configs without it keep the random walk
+    #[serde(default)]
+    pub layout: Layout,        // Where the balls sit; a grid pins them in place, overriding `movement`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub movement_bounds: Option<MovementBounds>, // Area the balls move in; the whole window when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transition_ms: Option<u16>, // How long color and size changes take to animate; instant when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background_color: Option<String>, // Color of the page behind the balls; white when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ball_opacity: Option<f32>, // From 0.0 (invisible) to 1.0; fully opaque when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ball_z_index: Option<i32>, // Stacking level of the balls against the rest of the page; later balls still cover earlier ones
+}
+
+// Movement styles the demo page knows how to animate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Movement {
+    #[default]
+    Random, // Jump to a random position on every step
+    Bounce, // Travel in straight lines, reflecting off the bounds
+    Orbit,  // Circle around the center of the bounds
+}
+
+// How the balls are arranged on the page.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layout {
+    #[default]
+    Random, // Wherever `movement` takes them
+    Grid,   // Pinned to evenly spaced rows and columns filling the bounds
+}
+
+// Size of the area, anchored at the top-left corner of the page, that balls move in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct MovementBounds {
+    pub width: u32,  // Pixels
+    pub height: u32, // Pixels
+}
+
+pub const MAX_BALL_SIZE: u8 = 200;
+
+// A valid config to start from: a few medium-sized blue balls at a moderate speed.
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ball_color: "blue".to_string(),
+            ball_size: 50,
+            ball_size_min: None,
+            ball_size_max: None,
+            ball_speed: 5,
+            number_of_balls: 5,
+            movement: Movement::default(),
+            layout: Layout::default(),
+            movement_bounds: None,
+            transition_ms: None,
+            background_color: None,
+            ball_opacity: None,
+            ball_z_index: None,
+        }
+    }
+}
+
+// Builds a `Config` from the defaults, overriding only the fields that are set,
+// e.g. `Config::builder().ball_color("red").build()`.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    pub fn ball_color(mut self, ball_color: impl Into<String>) -> Self {
+        self.config.ball_color = ball_color.into();
+        self
+    }
+
+    pub fn ball_size(mut self, ball_size: u8) -> Self {
+        self.config.ball_size = ball_size;
+        self
+    }
+
+    pub fn ball_size_range(mut self, min: u8, max: u8) -> Self {
+        self.config.ball_size_min = Some(min);
+        self.config.ball_size_max = Some(max);
+        self
+    }
+
+    pub fn ball_speed(mut self, ball_speed: u8) -> Self {
+        self.config.ball_speed = ball_speed;
+        self
+    }
+
+    pub fn number_of_balls(mut self, number_of_balls: u8) -> Self {
+        self.config.number_of_balls = number_of_balls;
+        self
+    }
+
+    pub fn movement(mut self, movement: Movement) -> Self {
+        self.config.movement = movement;
+        self
+    }
+
+    pub fn layout(mut self, layout: Layout) -> Self {
+        self.config.layout = layout;
+        self
+    }
+
+    pub fn movement_bounds(mut self, width: u32, height: u32) -> Self {
+        self.config.movement_bounds = Some(MovementBounds { width, height });
+        self
+    }
+
+    pub fn transition_ms(mut self, transition_ms: u16) -> Self {
+        self.config.transition_ms = Some(transition_ms);
+        self
+    }
+
+    pub fn background_color(mut self, background_color: impl Into<String>) -> Self {
+        self.config.background_color = Some(background_color.into());
+        self
+    }
+
+    pub fn ball_opacity(mut self, ball_opacity: f32) -> Self {
+        self.config.ball_opacity = Some(ball_opacity);
+        self
+    }
+
+    pub fn ball_z_index(mut self, ball_z_index: i32) -> Self {
+        self.config.ball_z_index = Some(ball_z_index);
+        self
+    }
+
+    pub fn build(self) -> Config {
+        self.config
+    }
+}
+
+impl Config {
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    // Check the bounds every config must respect before it is rendered. Every rule is checked, so
+    // the error lists all the problems at once.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        if !valid_color(&self.ball_color) {
+            errors.push(format!("invalid ball_color: {:?}", self.ball_color));
+        }
+        if let Some(background_color) = self.background_color.as_ref().filter(|color| !valid_color(color)) {
+            errors.push(format!("invalid background_color: {:?}", background_color));
+        }
+        if self.ball_size == 0 || self.ball_size > MAX_BALL_SIZE {
+            errors.push(format!("ball_size must be between 1 and {}", MAX_BALL_SIZE));
+        }
+        match (self.ball_size_min, self.ball_size_max) {
+            (None, None) => (),
+            (Some(min), Some(max)) if min == 0 || max > MAX_BALL_SIZE => {
+                errors.push(format!("ball_size_min and ball_size_max must be between 1 and {}", MAX_BALL_SIZE));
+            }
+            (Some(min), Some(max)) if min > max => errors.push("ball_size_min must not exceed ball_size_max".to_string()),
+            (Some(_), Some(_)) => (),
+            _ => errors.push("ball_size_min and ball_size_max must be set together".to_string()),
+        }
+        if let Some(opacity) = self.ball_opacity.filter(|opacity| !(0.0..=1.0).contains(opacity)) {
+            errors.push(format!("ball_opacity must be between 0.0 and 1.0, got {}", opacity));
+        }
+        if self.ball_speed == 0 {
+            errors.push("ball_speed must be at least 1".to_string());
+        }
+        if let Some(bounds) = self.movement_bounds {
+            if bounds.width == 0 || bounds.height == 0 {
+                errors.push("movement_bounds must have a non-zero width and height".to_string());
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    // The smallest and largest ball diameters, when balls are sized from a range.
+    pub fn ball_size_range(&self) -> Option<(u8, u8)> {
+        self.ball_size_min.zip(self.ball_size_max)
+    }
+
+    // The largest diameter any ball has.
+    pub fn largest_ball_size(&self) -> u8 {
+        self.ball_size_range().map_or(self.ball_size, |(_, max)| max)
+    }
+
+    // Fields whose serialized value differs in `other`, mapped to their new values. Optional fields
+    // `other` clears are left out of its JSON, so they're mapped to `null`.
+    pub fn diff(&self, other: &Config) -> Map<String, Value> {
+        let (Ok(Value::Object(before)), Ok(Value::Object(after))) = (serde_json::to_value(self), serde_json::to_value(other)) else {
+            return Map::new();
+        };
+        let cleared: Vec<String> = before.keys().filter(|field| !after.contains_key(*field)).cloned().collect();
+        let mut changes: Map<String, Value> = after.into_iter().filter(|(field, value)| before.get(field) != Some(value)).collect();
+        changes.extend(cleared.into_iter().map(|field| (field, Value::Null)));
+        changes
+    }
+
+    // This config with the listed fields' values taken from `other` instead. Unknown field names
+    // are ignored.
+    pub fn with_fields_from(&self, other: &Config, fields: &[String]) -> Config {
+        let (Ok(Value::Object(mut merged)), Ok(Value::Object(source))) = (serde_json::to_value(self), serde_json::to_value(other)) else {
+            return self.clone();
+        };
+        for field in fields {
+            match source.get(field) {
+                Some(value) => merged.insert(field.clone(), value.clone()),
+                None => merged.remove(field),
+            };
+        }
+        serde_json::from_value(Value::Object(merged)).unwrap_or_else(|_| self.clone())
+    }
+}
+
+// Colors end up inside style attributes, so only allow characters valid in CSS color values.
+fn valid_color(color: &str) -> bool {
+    !color.is_empty() && color.chars().all(|c| c.is_ascii_alphanumeric() || "#(),.% ".contains(c))
+}
+
+// `value`'s debug form cut to at most `max_chars` characters, the last of them an ellipsis when
+// cut, so a large config can't flood the logs. 0 never cuts.
+pub fn truncate_for_log(value: &impl Debug, max_chars: usize) -> String {
+    let full = format!("{:?}", value);
+    if max_chars == 0 || full.chars().nth(max_chars).is_none() {
+        return full;
+    }
+    let end = full.char_indices().nth(max_chars - 1).map_or(0, |(end, _)| end);
+    format!("{}…", &full[..end])
+}
+
+// An update carrying only some fields, e.g. `{"ball_color":"red"}`, for upstreams that send
+// just what changed. `movement_bounds`, `transition_ms`, `background_color`, and the ball size range
+// can be set this way but not cleared.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PartialConfig {
+    pub ball_color: Option<String>,
+    pub ball_size: Option<u8>,
+    pub ball_size_min: Option<u8>,
+    pub ball_size_max: Option<u8>,
+    pub ball_speed: Option<u8>,
+    pub number_of_balls: Option<u8>,
+    pub movement: Option<Movement>,
+    pub layout: Option<Layout>,
+    pub movement_bounds: Option<MovementBounds>,
+    pub transition_ms: Option<u16>,
+    pub background_color: Option<String>,
+    pub ball_opacity: Option<f32>,
+    pub ball_z_index: Option<i32>,
+}
+
+impl PartialConfig {
+    // Apply the update on top of `base`, keeping its value for every field the update leaves out.
+    pub fn merge_onto(self, base: &Config) -> Config {
+        Config {
+            ball_color: self.ball_color.unwrap_or_else(|| base.ball_color.clone()),
+            ball_size: self.ball_size.unwrap_or(base.ball_size),
+            ball_size_min: self.ball_size_min.or(base.ball_size_min),
+            ball_size_max: self.ball_size_max.or(base.ball_size_max),
+            ball_speed: self.ball_speed.unwrap_or(base.ball_speed),
+            number_of_balls: self.number_of_balls.unwrap_or(base.number_of_balls),
+            movement: self.movement.unwrap_or(base.movement),
+            layout: self.layout.unwrap_or(base.layout),
+            movement_bounds: self.movement_bounds.or(base.movement_bounds),
+            transition_ms: self.transition_ms.or(base.transition_ms),
+            background_color: self.background_color.or_else(|| base.background_color.clone()),
+            ball_opacity: self.ball_opacity.or(base.ball_opacity),
+            ball_z_index: self.ball_z_index.or(base.ball_z_index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logged_config_is_cut_to_the_limit() {
+        let config = Config::builder().ball_color("x".repeat(500)).build();
+
+        let logged = truncate_for_log(&config, 40);
+        assert_eq!(logged.chars().count(), 40);
+        assert!(logged.ends_with('…'));
+        assert_eq!(truncate_for_log(&config, 0), format!("{:?}", config));
+        assert_eq!(truncate_for_log(&"short", 40), "\"short\"");
+    }
+
+    #[test]
+    fn validate_reports_every_error_at_once() {
+        let config = Config { ball_color: "red;".to_string(), ball_size: 0, ball_speed: 0, ..Config::default() };
+
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].starts_with("invalid ball_color"));
+        assert!(errors[1].starts_with("ball_size must be"));
+        assert!(errors[2].starts_with("ball_speed must be"));
+    }
+
+    // The wire shape the demo page and external consumers rely on; a failure here is a schema change
+    #[test]
+    fn serialized_shape_is_stable() {
+        let config = Config::builder()
+            .ball_color("#ff0000")
+            .ball_size(30)
+            .ball_size_range(10, 40)
+            .ball_speed(7)
+            .number_of_balls(12)
+            .movement(Movement::Orbit)
+            .layout(Layout::Grid)
+            .movement_bounds(800, 600)
+            .transition_ms(400)
+            .background_color("black")
+            .ball_opacity(0.5)
+            .ball_z_index(2)
+            .build();
+
+        let golden = r##"{"ball_color":"#ff0000","ball_size":30,"ball_size_min":10,"ball_size_max":40,"ball_speed":7,"number_of_balls":12,"movement":"orbit","layout":"grid","movement_bounds":{"width":800,"height":600},"transition_ms":400,"background_color":"black","ball_opacity":0.5,"ball_z_index":2}"##;
+        assert_eq!(serde_json::to_string(&config).unwrap(), golden);
+        assert_eq!(serde_json::from_str::<Config>(golden).unwrap(), config);
+    }
+
+    #[test]
+    fn diff_lists_only_the_changed_field() {
+        let before = Config::builder().background_color("black").build();
+        let after = Config { ball_color: "red".to_string(), ..before.clone() };
+
+        let changes = before.diff(&after);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes["ball_color"], "red");
+    }
+
+    #[test]
+    fn diff_maps_cleared_fields_to_null() {
+        let before = Config::builder().background_color("black").transition_ms(400).build();
+
+        let changes = before.diff(&Config::default());
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes["background_color"], Value::Null);
+        assert_eq!(changes["transition_ms"], Value::Null);
+    }
+}
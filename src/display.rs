@@ -0,0 +1,1045 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::{fs, io};
+
+use actix_web::body::SizedStream;
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tinytemplate::TinyTemplate;
+
+use crate::access::IpAccess;
+use crate::bars::BarsRenderer;
+use crate::config::{Layout, Movement, PartialConfig};
+use crate::settings::Settings;
+use crate::websocket::PROTOCOL_VERSION;
+use crate::{appstate::AppState, ApiError, Config};
+
+const BALLS_PER_CHUNK: u8 = 16; // Balls rendered per chunk of a streamed page
+pub(crate) const DEFAULT_BACKGROUND_COLOR: &str = "white"; // Page background when the config doesn't set `background_color`
+
+pub const BALLS_DISPLAY: &str = "balls";
+pub const BARS_DISPLAY: &str = "bars";
+
+// Turns a config into one kind of page. Every display is rendered from the same config and kept
+// current by the same WebSocket broadcasts; `display_page` picks one by path.
+pub trait DisplayRenderer: Send + Sync {
+    fn render(&self, config: &Config) -> String;
+}
+
+// The displays served, by name; each one is served at `/<name>`.
+#[derive(Clone, Default)]
+pub struct Displays {
+    renderers: BTreeMap<String, Arc<dyn DisplayRenderer>>,
+}
+
+impl Displays {
+    // The built-in displays: the balls page and the bars page.
+    pub fn builtin(settings: &Settings) -> Self {
+        let mut displays = Self::default();
+        displays.register(BALLS_DISPLAY, Arc::new(BallsRenderer::from_settings(settings)));
+        displays.register(BARS_DISPLAY, Arc::new(BarsRenderer));
+        displays
+    }
+
+    // Serve `renderer` at `/<name>`, replacing any display already registered under that name.
+    pub fn register(&mut self, name: impl Into<String>, renderer: Arc<dyn DisplayRenderer>) {
+        self.renderers.insert(name.into(), renderer);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn DisplayRenderer>> {
+        self.renderers.get(name).cloned()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.renderers.keys().map(String::as_str)
+    }
+}
+
+// The built-in balls page, with balls dropped (under a notice) to keep it within `max_bytes`.
+// When not even an empty page fits, the page is returned over the limit, for `fit` to refuse.
+#[derive(Debug, Clone, Copy)]
+pub struct BallsRenderer {
+    script: PageScript,
+    max_bytes: usize,
+}
+
+impl BallsRenderer {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self { script: PageScript::from_settings(settings), max_bytes: settings.display_max_html_bytes }
+    }
+}
+
+impl DisplayRenderer for BallsRenderer {
+    fn render(&self, config: &Config) -> String {
+        render_within(config, self.script, self.max_bytes)
+    }
+}
+
+// Server settings baked into the demo page's script.
+#[derive(Debug, Clone, Copy)]
+struct PageScript {
+    max_balls: u8,               // Most balls the page shows
+    reconnect_base_ms: u64,      // First WebSocket reconnect delay, doubled on every failed attempt
+    reconnect_cap_ms: u64,       // Upper bound for the reconnect delay
+    reconnect_jitter: f64,       // Fraction of each delay that's randomized, from 0 to 1
+    reconnect_max_attempts: u32, // Failed reconnects before the page only polls; 0 never gives up
+}
+
+impl PageScript {
+    fn from_settings(settings: &Settings) -> Self {
+        Self {
+            max_balls: settings.display_max_balls,
+            reconnect_base_ms: settings.display_reconnect_base_ms.max(1),
+            reconnect_cap_ms: settings.display_reconnect_cap_ms,
+            // Formatted into the script, so it must stay a plain number
+            reconnect_jitter: if settings.display_reconnect_jitter.is_finite() { settings.display_reconnect_jitter.clamp(0.0, 1.0) } else { 0.5 },
+            reconnect_max_attempts: settings.display_reconnect_max_attempts,
+        }
+    }
+}
+
+const TEMPLATE_NAME: &str = "page";
+
+// Request header carrying a partial config to render the page with, for that request only.
+pub const CONFIG_OVERRIDE_HEADER: &str = "x-config-override";
+const MAX_OVERRIDE_HEADER_BYTES: usize = 4096; // Longest override header accepted
+
+// An operator-supplied demo page in TinyTemplate syntax, rendered with a `TemplateContext`, e.g.
+// `{{ for ball in balls }}<div style='left: {ball.left}px; background: {ball.color}'></div>{{ endfor }}`.
+#[derive(Debug, Clone)]
+pub struct PageTemplate {
+    source: String,
+}
+
+impl PageTemplate {
+    // Load the template at `path` and try it on the default config, so a syntax error or an unknown
+    // name fails at startup rather than on the first request.
+    pub fn load(path: &str) -> io::Result<Self> {
+        let template = Self { source: fs::read_to_string(path)? };
+        template.render(&Config::default()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(template)
+    }
+
+    fn render(&self, config: &Config) -> Result<String, String> {
+        let mut templates = TinyTemplate::new();
+        templates.add_template(TEMPLATE_NAME, &self.source).map_err(|e| e.to_string())?;
+        templates.render(TEMPLATE_NAME, &TemplateContext::new(config)).map_err(|e| e.to_string())
+    }
+}
+
+// The last demo page rendered, reused while requests ask for the same config.
+#[derive(Debug, Clone)]
+pub struct CachedPage {
+    pub base: Config,   // The stored config, before the request's overrides; rendered from while the config lock is contended
+    pub config: Config, // The config the page shows
+    pub html: Bytes,
+}
+
+// What a custom page template can refer to.
+#[derive(Debug, Serialize)]
+struct TemplateContext<'a> {
+    config: &'a Config,
+    config_json: String,      // The config as JSON, e.g. for a script: `{config_json | unescaped}`
+    balls: Vec<TemplateBall>, // One per ball, laid out like the built-in page before its script runs
+    protocol_version: u32,    // What to send in a WebSocket `hello`
+}
+
+#[derive(Debug, Serialize)]
+struct TemplateBall {
+    index: u8,
+    left: u32, // Pixels
+    top: u32,  // Pixels
+    size: u8,  // Diameter in pixels
+    color: String,
+}
+
+impl<'a> TemplateContext<'a> {
+    fn new(config: &'a Config) -> Self {
+        let balls = (0..config.number_of_balls)
+            .map(|index| {
+                let (left, top) = ball_position(config, index);
+                TemplateBall { index, left, top, size: ball_size(config, index), color: config.ball_color.clone() }
+            })
+            .collect();
+        Self {
+            config,
+            config_json: serde_json::to_string(config).unwrap_or_else(|_| "{}".to_string()),
+            balls,
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+}
+
+// Optional query-string overrides for a single render of the balls page, e.g. `/?ball_color=red`.
+// They are never stored or broadcast.
+#[derive(Debug, Default, Deserialize)]
+pub struct DisplayOverrides {
+    ball_color: Option<String>,
+    ball_size: Option<u8>,
+    ball_speed: Option<u8>,
+    number_of_balls: Option<u8>,
+    movement: Option<Movement>,
+    layout: Option<Layout>,
+    transition_ms: Option<u16>,
+    background_color: Option<String>,
+    ball_opacity: Option<f32>,
+    ball_z_index: Option<i32>,
+}
+
+impl DisplayOverrides {
+    // Layer the overrides on top of the stored config, falling back to it for missing params.
+    pub fn apply(self, config: Config) -> Config {
+        Config {
+            ball_color: self.ball_color.unwrap_or(config.ball_color),
+            ball_size: self.ball_size.unwrap_or(config.ball_size),
+            ball_size_min: config.ball_size_min,
+            ball_size_max: config.ball_size_max,
+            ball_speed: self.ball_speed.unwrap_or(config.ball_speed),
+            number_of_balls: self.number_of_balls.unwrap_or(config.number_of_balls),
+            movement: self.movement.unwrap_or(config.movement),
+            layout: self.layout.unwrap_or(config.layout),
+            movement_bounds: config.movement_bounds,
+            transition_ms: self.transition_ms.or(config.transition_ms),
+            background_color: self.background_color.or(config.background_color),
+            ball_opacity: self.ball_opacity.or(config.ball_opacity),
+            ball_z_index: self.ball_z_index.or(config.ball_z_index),
+        }
+    }
+}
+
+// The partial config in the request's `X-Config-Override` header, if it has one, e.g.
+// `{"ball_color":"red"}`.
+fn header_override(req: &HttpRequest) -> Result<Option<PartialConfig>, ApiError> {
+    let Some(value) = req.headers().get(CONFIG_OVERRIDE_HEADER) else {
+        return Ok(None);
+    };
+    if value.len() > MAX_OVERRIDE_HEADER_BYTES {
+        return Err(ApiError::BadRequest(format!("{} must be at most {} bytes", CONFIG_OVERRIDE_HEADER, MAX_OVERRIDE_HEADER_BYTES)));
+    }
+    serde_json::from_slice(value.as_bytes())
+        .map(Some)
+        .map_err(|e| ApiError::BadRequest(format!("Invalid {} header: {}", CONFIG_OVERRIDE_HEADER, e)))
+}
+
+// The config a display is rendered with: `config` under the request's overrides, the header's
+// partial config first and then the query string's fields on top, with the number of balls
+// clamped to `max_balls`.
+fn requested_config(req: &HttpRequest, config: Config, overrides: DisplayOverrides, max_balls: u8) -> Result<Config, ApiError> {
+    let config = match header_override(req)? {
+        Some(partial) => partial.merge_onto(&config),
+        None => config,
+    };
+    let mut config = overrides.apply(config);
+
+    // Overrides come straight from the request, so hold them to the same bounds as upstream configs
+    config.validate().map_err(ApiError::InvalidConfig)?;
+
+    // Keep the rendered DOM bounded whatever the config says
+    if config.number_of_balls > max_balls {
+        warn!("Clamping number_of_balls from {} to {} for rendering", config.number_of_balls, max_balls);
+        config.number_of_balls = max_balls;
+    }
+    Ok(config)
+}
+
+// Register the demo pages behind `access`: the balls page at `/` and `/balls`, which keeps its own
+// handler for the page template, streaming, and caching, and every other display at `/<name>`.
+// Nothing is registered with `SERVE_DEMO_PAGE` off, so API-only deployments answer 404 there.
+pub fn configure(cfg: &mut web::ServiceConfig, state: &AppState, access: &IpAccess) {
+    if !state.settings.serve_demo_page {
+        return;
+    }
+    cfg.service(web::resource("/").wrap(access.clone()).route(web::get().to(display_balls)))
+        .service(web::resource("/balls").wrap(access.clone()).route(web::get().to(display_balls)));
+    for name in state.displays.names().filter(|name| *name != BALLS_DISPLAY) {
+        cfg.service(web::resource(format!("/{}", name)).wrap(access.clone()).route(web::get().to(display_page)));
+    }
+}
+
+// Serve the display registered under the request's path, e.g. `/bars`. Takes the same overrides
+// as the balls page.
+pub async fn display_page(req: HttpRequest, data: web::Data<AppState>, overrides: web::Query<DisplayOverrides>) -> Result<HttpResponse, ApiError> {
+    let name = req.path().trim_start_matches('/');
+    let renderer = data.displays.get(name).ok_or_else(|| ApiError::NotFound(format!("No display is named {:?}.", name)))?;
+    let Some(config) = data.read_config("/{display}").await? else {
+        return Ok(not_ready_page(data.settings.display_not_ready_refresh_secs));
+    };
+    let config = requested_config(&req, config, overrides.into_inner(), data.settings.display_max_balls)?;
+    let html = fit(renderer.render(&config), data.settings.display_max_html_bytes)?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+pub async fn display_balls(req: HttpRequest, data: web::Data<AppState>, overrides: web::Query<DisplayOverrides>) -> Result<HttpResponse, ApiError> {
+    // Clone the config out so the read guard is dropped before any rendering happens. If a writer
+    // holds it for too long, the config the last page was rendered from is better than nothing
+    let base = match data.read_config("/").await {
+        // A shell can be served before the first config; its script fills it in from the WebSocket
+        Ok(None) if !data.settings.server_render_balls => Config::default(),
+        Ok(Some(config)) => config,
+        Ok(None) => return Ok(not_ready_page(data.settings.display_not_ready_refresh_secs)),
+        Err(e) => match data.page_cache.try_lock().ok().and_then(|cache| cache.as_ref().map(|page| page.base.clone())) {
+            Some(base) => {
+                debug!("Rendering / from the config of the last page rendered");
+                base
+            }
+            None => return Err(e),
+        },
+    };
+    let mut config = requested_config(&req, base.clone(), overrides.into_inner(), data.settings.display_max_balls)?;
+    let script = PageScript::from_settings(&data.settings);
+    // Without server-side balls the page starts empty, and the first config over the WebSocket,
+    // which always has `number_of_balls`, makes the script create them all
+    if !data.settings.server_render_balls {
+        config.number_of_balls = 0;
+    }
+
+    // Big pages are streamed instead of being rendered, and cached, whole
+    let stream_from = data.settings.display_stream_min_balls;
+    if stream_from > 0 && config.number_of_balls >= stream_from && data.page_template.is_none() {
+        if let Some(response) = stream_page(config.clone(), script, data.settings.display_max_html_bytes) {
+            return Ok(response);
+        }
+    }
+
+    // The page only depends on the config, so reuse the last render until the config changes. The
+    // lock is only held to look and to store, so requests don't queue up behind a render; two
+    // requests missing at once both render, and the later one's page is kept
+    let cached = data.page_cache.lock().unwrap().as_ref().filter(|page| page.config == config).map(|page| page.html.clone());
+    let html = match cached {
+        Some(html) => html,
+        None => {
+            let max_bytes = data.settings.display_max_html_bytes;
+            let html = match &data.page_template {
+                Some(template) => render_template(template, &config, max_bytes)?,
+                None => fit(BallsRenderer { script, max_bytes }.render(&config), max_bytes)?,
+            };
+            if data.settings.display_validate_html && data.page_template.is_none() {
+                check_page(&html).map_err(|e| ApiError::Internal(format!("Rendered a malformed page: {}", e)))?;
+            }
+            let html = Bytes::from(html);
+            *data.page_cache.lock().unwrap() = Some(CachedPage { base, config, html: html.clone() });
+            html
+        }
+    };
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+// Served in place of a display while no config has loaded, with no `DEFAULT_CONFIG` to fall back
+// on: a `503` page that reloads itself every `refresh_secs`, so a screen left open shows the
+// display once it's ready.
+fn not_ready_page(refresh_secs: u64) -> HttpResponse {
+    let html = format!(
+        "<!DOCTYPE html>
+        <html>
+        <head>
+            <title>Display not ready</title>
+            <meta http-equiv='refresh' content='{refresh_secs}'>
+        </head>
+        <body style='background-color: {DEFAULT_BACKGROUND_COLOR}; font-family: sans-serif;'>
+            <p>The display's configuration hasn't loaded yet. This page will try again in {refresh_secs} seconds.</p>
+        </body>
+        </html>"
+    );
+    HttpResponse::ServiceUnavailable()
+        .insert_header((header::RETRY_AFTER, refresh_secs.to_string()))
+        .content_type("text/html")
+        .body(html)
+}
+
+// Stream the page a few balls at a time, with its length worked out up front so it's still sent
+// with a `Content-Length`. `None` if it's over `max_bytes`, leaving `render_within` to trim it.
+fn stream_page(config: Config, script: PageScript, max_bytes: usize) -> Option<HttpResponse> {
+    let (head, tail) = page_shell(&config, script, None);
+    let balls = config.number_of_balls;
+    // The balls are separated by newlines, as in `render_page`
+    let balls_len: usize = (0..balls).map(|i| ball_html(&config, i).len()).sum::<usize>() + usize::from(balls.saturating_sub(1));
+    let len = head.len() + balls_len + tail.len();
+    if len > max_bytes {
+        return None;
+    }
+
+    let chunks = (0..balls).step_by(usize::from(BALLS_PER_CHUNK)).map(move |start| {
+        let end = start.saturating_add(BALLS_PER_CHUNK).min(balls);
+        let mut chunk = String::new();
+        for i in start..end {
+            if i > 0 {
+                chunk.push('\n');
+            }
+            chunk.push_str(&ball_html(&config, i));
+        }
+        chunk
+    });
+    let body = std::iter::once(head).chain(chunks).chain(std::iter::once(tail)).map(|chunk| Ok::<_, Infallible>(Bytes::from(chunk)));
+    Some(HttpResponse::Ok().content_type("text/html").body(SizedStream::new(len as u64, tokio_stream::iter(body))))
+}
+
+// Refuse a page over `max_bytes` with 413.
+fn fit(html: String, max_bytes: usize) -> Result<String, ApiError> {
+    if html.len() > max_bytes {
+        return Err(ApiError::PayloadTooLarge(format!("The page doesn't fit in {} bytes.", max_bytes)));
+    }
+    Ok(html)
+}
+
+// Render a custom page, refusing it with 413 rather than trimming it when it's over `max_bytes`.
+fn render_template(template: &PageTemplate, config: &Config, max_bytes: usize) -> Result<String, ApiError> {
+    let html = template.render(config).map_err(|e| ApiError::Internal(format!("Failed to render the page template: {}", e)))?;
+    fit(html, max_bytes)
+}
+
+// Render the page in at most `max_bytes`. When all the balls don't fit, as many as do are
+// rendered under a notice saying so; when not even an empty page fits, it's returned as is.
+fn render_within(config: &Config, script: PageScript, max_bytes: usize) -> String {
+    let html = render_page(config, script, None);
+    if html.len() <= max_bytes {
+        return html;
+    }
+
+    let notice = format!(
+        "Showing fewer than the {} configured balls to keep this page under {} bytes.",
+        config.number_of_balls, max_bytes
+    );
+    let mut reduced = Config { number_of_balls: 0, ..config.clone() };
+    let empty = render_page(&reduced, script, Some(&notice));
+    if empty.len() > max_bytes {
+        return empty;
+    }
+    let overhead = empty.len();
+    // The last ball's position has the most digits, so no ball takes more space than it
+    let per_ball = ball_html(config, config.number_of_balls.saturating_sub(1)).len() + 1;
+    let fits = ((max_bytes - overhead) / per_ball).min(usize::from(config.number_of_balls));
+    reduced.number_of_balls = fits as u8;
+    let mut html = render_page(&reduced, script, Some(&notice));
+    // The embedded config's digits can tip it over; drop balls until it fits
+    while html.len() > max_bytes && reduced.number_of_balls > 0 {
+        reduced.number_of_balls -= 1;
+        html = render_page(&reduced, script, Some(&notice));
+    }
+    warn!(
+        "Rendering {} of {} balls to keep the page under {} bytes",
+        reduced.number_of_balls, config.number_of_balls, max_bytes
+    );
+    html
+}
+
+const STATIC_BALLS_PER_ROW: u8 = 10; // Balls per row in the static layout shown before the script runs
+const STATIC_BALL_GAP_PX: u32 = 8;    // Space around balls in the static layout
+
+// The `index`th ball, placed in a fixed grid so the page shows something without JavaScript;
+// the script moves it from there. In the grid layout it starts where the script keeps it.
+fn ball_html(config: &Config, index: u8) -> String {
+    let (left, top) = ball_offsets(config, index);
+    let size = ball_size(config, index);
+    format!(
+        "<div class='ball' style='position: absolute; left: {}; top: {}; width: {}px; height: {}px; border-radius: {}px; background-color: {};{}'></div>",
+        left, top, size, size, size / 2, config.ball_color, layer_style(config)
+    )
+}
+
+// The opacity and z-index declarations for `config`'s balls, each only when set; empty or
+// starting with a space.
+pub(crate) fn layer_style(config: &Config) -> String {
+    let mut style = String::new();
+    if let Some(opacity) = config.ball_opacity {
+        style.push_str(&format!(" opacity: {};", opacity));
+    }
+    if let Some(z_index) = config.ball_z_index {
+        style.push_str(&format!(" z-index: {};", z_index));
+    }
+    style
+}
+
+// The `index`th ball's diameter: `ball_size`, or one spread over the configured range. The spread
+// is a hash of the index rather than random, so equal configs still render equal pages, which
+// the page cache and the streamed page's length rely on; the script picks random sizes later.
+pub(crate) fn ball_size(config: &Config, index: u8) -> u8 {
+    let Some((min, max)) = config.ball_size_range() else {
+        return config.ball_size;
+    };
+    let mut hash = u32::from(index).wrapping_add(1).wrapping_mul(0x9E37_79B9);
+    hash ^= hash >> 16;
+    let span = u32::from(max - min) + 1;
+    min + (hash % span) as u8
+}
+
+// The `index`th ball's left and top offsets as CSS lengths. A grid layout without bounds fills
+// the window, which only percentages can say before the script runs.
+fn ball_offsets(config: &Config, index: u8) -> (String, String) {
+    if config.layout != Layout::Grid || config.movement_bounds.is_some() {
+        let (left, top) = ball_position(config, index);
+        return (format!("{}px", left), format!("{}px", top));
+    }
+    let (x, y) = grid_cell(config.number_of_balls, index);
+    let radius = f64::from(ball_size(config, index)) / 2.0;
+    (format!("max(0px, {:.3}% - {}px)", x * 100.0, radius), format!("max(0px, {:.3}% - {}px)", y * 100.0, radius))
+}
+
+// Where the `index`th ball sits, as its left and top offsets in pixels: its grid cell within
+// `movement_bounds` in the grid layout, and the static grid otherwise.
+fn ball_position(config: &Config, index: u8) -> (u32, u32) {
+    if let (Layout::Grid, Some(bounds)) = (config.layout, config.movement_bounds) {
+        let (x, y) = grid_cell(config.number_of_balls, index);
+        let radius = f64::from(ball_size(config, index)) / 2.0;
+        let left = (x * f64::from(bounds.width) - radius).max(0.0);
+        let top = (y * f64::from(bounds.height) - radius).max(0.0);
+        return (left as u32, top as u32);
+    }
+    let cell = u32::from(config.largest_ball_size()) + STATIC_BALL_GAP_PX;
+    let left = STATIC_BALL_GAP_PX + u32::from(index % STATIC_BALLS_PER_ROW) * cell;
+    let top = STATIC_BALL_GAP_PX + u32::from(index / STATIC_BALLS_PER_ROW) * cell;
+    (left, top)
+}
+
+// The center of the `index`th of `count` balls' cell, as fractions of the area's width and height.
+// Balls fill rows of `ceil(sqrt(count))` columns; the page script's `gridCell` matches this.
+fn grid_cell(count: u8, index: u8) -> (f64, f64) {
+    let count = u32::from(count.max(1));
+    let columns = (1..=count).find(|columns| columns * columns >= count).unwrap_or(1);
+    let rows = count.div_ceil(columns);
+    let index = u32::from(index);
+    ((f64::from(index % columns) + 0.5) / f64::from(columns), (f64::from(index / columns) + 0.5) / f64::from(rows))
+}
+
+// Escape `value` for use inside a single-quoted HTML attribute.
+pub(crate) fn escape_attribute(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '\'' => escaped.push_str("&#39;"),
+            '<' => escaped.push_str("&lt;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Render the balls page for `config`, with `notice` shown in a banner if given. The script keeps
+// later updates to at most `script.max_balls` balls. The balls start in a fixed layout and the
+// script animates them, so the output is the same for equal inputs.
+fn render_page(config: &Config, script: PageScript, notice: Option<&str>) -> String {
+    let (head, tail) = page_shell(config, script, notice);
+    let balls_html = (0..config.number_of_balls).map(|i| ball_html(config, i)).collect::<Vec<_>>().join("\n");
+    let html = format!("{}{}{}", head, balls_html, tail);
+    debug_assert_eq!(check_page(&html), Ok(config.number_of_balls as usize), "malformed demo page");
+    html
+}
+
+// Check the structure of a rendered page, since the template is assembled by string formatting:
+// one `html`, `head`, `body`, and `script` element each, in that order and properly closed,
+// and balanced `div`s outside the script. Returns how many balls the page contains.
+pub fn check_page(html: &str) -> Result<usize, String> {
+    let script_start = single(html, "<script>")?;
+    let script_end = single(html, "</script>")?;
+    if script_end < script_start {
+        return Err("</script> comes before <script>".to_string());
+    }
+    let markup = format!("{}{}", &html[..script_start], &html[script_end..]);
+    let order = ["<html>", "<head>", "</head>", "<body", "<script>", "</body>", "</html>"];
+    let mut last = 0;
+    for tag in order {
+        let at = if tag == "<script>" { script_start } else { single(&markup, tag)? };
+        if at < last {
+            return Err(format!("{} is out of place", tag));
+        }
+        last = at;
+    }
+    let (opened, closed) = (markup.matches("<div").count(), markup.matches("</div>").count());
+    if opened != closed {
+        return Err(format!("{} <div>s but {} </div>s", opened, closed));
+    }
+    Ok(markup.matches("<div class='ball'").count())
+}
+
+// Where the only occurrence of `tag` is.
+fn single(html: &str, tag: &str) -> Result<usize, String> {
+    match html.match_indices(tag).map(|(at, _)| at).collect::<Vec<_>>()[..] {
+        [at] => Ok(at),
+        [] => Err(format!("no {}", tag)),
+        _ => Err(format!("more than one {}", tag)),
+    }
+}
+
+// The page around the balls: everything before the first ball and after the last one.
+fn page_shell(config: &Config, script: PageScript, notice: Option<&str>) -> (String, String) {
+    // Only ever our own text, so it needs no escaping
+    let notice_html = notice.map(|notice| format!("<div id='notice'>{}</div>", notice)).unwrap_or_default();
+    // Validated like `ball_color`, so it's safe to put in the stylesheet as is
+    let background_color = config.background_color.as_deref().unwrap_or(DEFAULT_BACKGROUND_COLOR);
+    // The config the page was rendered from, for the script and anything else reading the page
+    let config_attr = escape_attribute(&serde_json::to_string(config).unwrap_or_else(|_| "{}".to_string()));
+
+    let head = format!(
+        "<!DOCTYPE html>
+        <html>
+        <head>
+            <title>Balls Display</title>
+            <style>
+                body {{ margin: 0; overflow: hidden; background-color: {background_color}; }}
+                .ball {{ position: absolute; border-radius: 50%; }}
+                #ws-status {{ position: fixed; top: 4px; right: 8px; z-index: 1; font: 12px sans-serif; color: #999; }}
+                #ws-status.connected {{ color: #2a2; }}
+                #ws-status.reconnecting {{ color: #c22; }}
+                #ws-status.polling {{ color: #c80; }}
+                #notice {{ position: fixed; top: 4px; left: 8px; z-index: 1; font: 12px sans-serif; color: #c80; }}
+            </style>
+        </head>
+        <body data-config='{config_attr}'>
+            <div id='ws-status'>connecting</div>
+            {notice_html}
+            "
+    );
+    let PageScript { max_balls, reconnect_base_ms, reconnect_cap_ms, reconnect_jitter, reconnect_max_attempts } = script;
+    let tail = format!(
+        "
+            <script>
+                var reconnectBaseMs = {reconnect_base_ms}; // First reconnect delay, doubled on every failed attempt
+                var reconnectCapMs = {reconnect_cap_ms}; // Upper bound for the reconnect delay
+                var reconnectJitter = {reconnect_jitter}; // Fraction of each delay that's randomized
+                var reconnectMaxAttempts = {reconnect_max_attempts}; // Failed reconnects before giving up and only polling; 0 never gives up
+                var reconnectAttempts = 0;
+                var pollFallbackAfter = 3; // Failed reconnects before falling back to polling `/config`
+                var pollIntervalMs = 5000; // How often `/config` is polled while the WebSocket is down
+                var pollTimer = null;
+                var statusEl = document.getElementById('ws-status');
+
+                function setStatus(state) {{
+                    statusEl.textContent = state;
+                    statusEl.className = state;
+                }}
+
+                function connect() {{
+                    setStatus('connecting');
+                    // The page's own host, which the browser already brackets if it's an IPv6 literal
+                    var conn = new WebSocket((location.protocol === 'https:' ? 'wss://' : 'ws://') + location.host + '/ws/');
+                    conn.onopen = function() {{
+                        console.log('WebSocket connection established');
+                        conn.send(JSON.stringify({{ type: 'hello', version: {PROTOCOL_VERSION}, formats: ['json'] }}));
+                        reconnectAttempts = 0;
+                        stopPolling();
+                        setStatus('connected');
+                    }};
+                    conn.onerror = function(error) {{
+                        console.error('WebSocket Error:', error);
+                    }};
+                    conn.onmessage = function(evt) {{
+                        var message = JSON.parse(evt.data);
+                        if (message.error) {{
+                            console.warn('Server error:', message.error.message);
+                            return;
+                        }}
+                        if (message.type === 'error') {{
+                            console.warn('Server error:', message.message);
+                            return;
+                        }}
+                        if (message.type === 'hello_ack') {{
+                            return;
+                        }}
+                        if (message.type === 'echo') {{
+                            console.debug('Server received:', message.received);
+                            return;
+                        }}
+                        if (message.type === 'replay') {{
+                            return; // Recent history; the page only shows the current config
+                        }}
+                        if (message.type === 'control') {{
+                            setMovementPaused(message.action === 'pause');
+                            return;
+                        }}
+                        // Patches carry only the changed fields; anything else is a full config
+                        var changes = message.type === 'patch' ? message.changes : withClearedFields(message);
+                        console.log('Received config changes from ' + (message.source || 'unknown source') + ':', changes);
+                        applyConfig(changes);
+                    }};
+                    conn.onclose = function(evt) {{
+                        console.log('WebSocket connection closed');
+                        // The server no longer speaks this page's protocol; reload to get a current copy
+                        if (evt.code === 1008) {{
+                            location.reload();
+                            return;
+                        }}
+                        scheduleReconnect();
+                    }};
+                }}
+
+                // Exponential backoff with jitter so a restarted server isn't hit by every page at once
+                function scheduleReconnect() {{
+                    if (reconnectMaxAttempts > 0 && reconnectAttempts >= reconnectMaxAttempts) {{
+                        console.log('Giving up on the WebSocket after ' + reconnectAttempts + ' attempts');
+                        startPolling();
+                        setStatus('polling');
+                        return;
+                    }}
+                    var delay = Math.min(reconnectCapMs, reconnectBaseMs * Math.pow(2, reconnectAttempts));
+                    delay = delay * (1 - reconnectJitter) + Math.random() * delay * reconnectJitter;
+                    reconnectAttempts++;
+                    if (reconnectAttempts >= pollFallbackAfter) {{
+                        startPolling();
+                    }}
+                    setStatus(pollTimer ? 'polling' : 'reconnecting');
+                    console.log('Reconnecting in ' + Math.round(delay) + 'ms');
+                    setTimeout(connect, delay);
+                }}
+
+                // Degraded mode: keep the page updating over plain HTTP while WebSocket reconnects keep failing
+                function startPolling() {{
+                    if (pollTimer) {{
+                        return;
+                    }}
+                    console.warn('WebSocket unavailable, polling /config every ' + pollIntervalMs + 'ms');
+                    pollConfig();
+                    pollTimer = setInterval(pollConfig, pollIntervalMs);
+                }}
+
+                function stopPolling() {{
+                    clearInterval(pollTimer);
+                    pollTimer = null;
+                }}
+
+                // Fetch the full config and apply only the fields that differ, as a patch would
+                function pollConfig() {{
+                    fetch('/config')
+                        .then(function(response) {{ return response.ok ? response.json() : null; }})
+                        .then(function(config) {{
+                            if (!config) {{
+                                return;
+                            }}
+                            var changes = {{}};
+                            Object.keys(withClearedFields(config)).forEach(function(key) {{
+                                if (JSON.stringify(config[key]) !== JSON.stringify(currentConfig[key])) {{
+                                    changes[key] = config[key];
+                                }}
+                            }});
+                            if (Object.keys(changes).length > 0) {{
+                                console.log('Polled config changes:', changes);
+                                applyConfig(changes);
+                            }}
+                        }})
+                        .catch(function(error) {{
+                            console.error('Polling /config failed:', error);
+                        }});
+                }}
+
+                var currentConfig = JSON.parse(document.body.dataset.config); // Config as last received from the server
+
+                // A full config leaves out the optional fields it doesn't set; mark the ones the page
+                // still holds as `null`, the way a patch clears them
+                function withClearedFields(config) {{
+                    Object.keys(currentConfig).forEach(function(key) {{
+                        if (!(key in config)) {{
+                            config[key] = null;
+                        }}
+                    }});
+                    return config;
+                }}
+                var maxBalls = {max_balls}; // Most balls the page shows, as enforced by the server (`DISPLAY_MAX_BALLS`)
+
+                // CSS transition for the ball properties a config change can touch, from `transition_ms`
+                function ballTransition() {{
+                    var ms = currentConfig.transition_ms;
+                    return ms ? ['background-color', 'width', 'height', 'border-radius', 'opacity'].map(p => p + ' ' + ms + 'ms').join(', ') : '';
+                }}
+                document.querySelectorAll('.ball').forEach(div => {{
+                    div.style.transition = ballTransition();
+                }});
+
+                // Opacity and stacking from `ball_opacity` and `ball_z_index`; unset ones fall back to the stylesheet
+                function setBallLayer(div) {{
+                    div.style.opacity = currentConfig.ball_opacity == null ? '' : currentConfig.ball_opacity;
+                    div.style.zIndex = currentConfig.ball_z_index == null ? '' : currentConfig.ball_z_index;
+                }}
+
+                // A diameter for one ball: random within `ball_size_min`..`ball_size_max` when both are set
+                function pickBallSize() {{
+                    var min = currentConfig.ball_size_min, max = currentConfig.ball_size_max;
+                    if (min == null || max == null) {{
+                        return currentConfig.ball_size;
+                    }}
+                    return min + Math.floor(Math.random() * (max - min + 1));
+                }}
+
+                // Apply only the fields present in `changes` on top of the current config
+                function applyConfig(changes) {{
+                    Object.assign(currentConfig, changes);
+                    // `null` clears a field, so the checks below fall back to its default
+                    Object.keys(changes).forEach(function(key) {{
+                        if (changes[key] === null) {{
+                            delete currentConfig[key];
+                        }}
+                    }});
+                    var config = currentConfig;
+                    // Set the new transition first, so the changes below already animate with it
+                    if ('transition_ms' in changes) {{
+                        document.querySelectorAll('.ball').forEach(div => {{
+                            div.style.transition = ballTransition();
+                        }});
+                    }}
+                    if ('background_color' in changes) {{
+                        document.body.style.backgroundColor = config.background_color || '{DEFAULT_BACKGROUND_COLOR}';
+                    }}
+                    // Update ball characteristics based on the new config
+                    if ('ball_color' in changes) {{
+                        document.querySelectorAll('.ball').forEach(div => {{
+                            div.style.backgroundColor = config.ball_color;
+                        }});
+                    }}
+                    if ('ball_opacity' in changes || 'ball_z_index' in changes) {{
+                        document.querySelectorAll('.ball').forEach(setBallLayer);
+                    }}
+                    if ('ball_size' in changes || 'ball_size_min' in changes || 'ball_size_max' in changes) {{
+                        document.querySelectorAll('.ball').forEach(div => {{
+                            var size = pickBallSize();
+                            div.style.width = size + 'px';
+                            div.style.height = size + 'px';
+                            div.style.borderRadius = (size / 2) + 'px';
+                        }});
+                    }}
+                    // Adjust the number of balls as needed
+                    if ('number_of_balls' in changes) {{
+                        updateNumberOfBalls(config.number_of_balls, config.ball_color);
+                    }}
+                    // Re-pin the grid right away, even while movement is paused
+                    if (config.layout === 'grid' && ('layout' in changes || 'number_of_balls' in changes || 'movement_bounds' in changes)) {{
+                        moveBalls();
+                    }}
+                    // Update the movement speed based on the new configuration
+                    if ('ball_speed' in changes) {{
+                        currentSpeed = config.ball_speed || defaultSpeed;
+                        if (!movementPaused) {{
+                            clearInterval(moveInterval); // Clear the existing interval
+                            moveInterval = setInterval(moveBalls, 1000 / currentSpeed); // Set a new interval with updated speed
+                        }}
+                    }}
+                }}
+
+                var movementPaused = false; // Set by the server's `control` messages
+
+                // Freeze the balls in place, or start them moving again at the current speed
+                function setMovementPaused(paused) {{
+                    if (paused === movementPaused) {{
+                        return;
+                    }}
+                    movementPaused = paused;
+                    clearInterval(moveInterval);
+                    if (!paused) {{
+                        moveInterval = setInterval(moveBalls, 1000 / currentSpeed);
+                    }}
+                }}
+
+                var defaultSpeed = 5; // Default speed for ball movement
+                var currentSpeed = defaultSpeed; // Current speed, initially set to default
+                var moveInterval = setInterval(moveBalls, 1000 / currentSpeed); // Initialize ball movement
+
+                var stepPx = 10; // Distance a bouncing ball travels per step
+                var orbitStep = 0.1; // Radians an orbiting ball advances per step
+
+                // The center of ball `i` of `count`'s grid cell, as fractions of the bounds; matches the server's `grid_cell`
+                function gridCell(i, count) {{
+                    var columns = Math.ceil(Math.sqrt(count));
+                    var rows = Math.ceil(count / columns);
+                    return {{ x: (i % columns + 0.5) / columns, y: (Math.floor(i / columns) + 0.5) / rows }};
+                }}
+
+                // Move every ball one step in the configured movement style, within the configured bounds;
+                // the grid layout pins each ball to its cell instead
+                function moveBalls() {{
+                    var bounds = currentConfig.movement_bounds || {{ width: window.innerWidth, height: window.innerHeight }};
+                    var balls = document.querySelectorAll('.ball');
+                    balls.forEach(function(div, i) {{
+                        var maxX = Math.max(0, bounds.width - div.offsetWidth);
+                        var maxY = Math.max(0, bounds.height - div.offsetHeight);
+                        var x, y;
+                        if (currentConfig.layout === 'grid') {{
+                            var cell = gridCell(i, balls.length);
+                            x = Math.max(0, cell.x * bounds.width - div.offsetWidth / 2);
+                            y = Math.max(0, cell.y * bounds.height - div.offsetHeight / 2);
+                        }} else if (currentConfig.movement === 'bounce') {{
+                            var dx = parseFloat(div.dataset.dx) || (Math.random() < 0.5 ? -1 : 1);
+                            var dy = parseFloat(div.dataset.dy) || (Math.random() < 0.5 ? -1 : 1);
+                            x = (parseFloat(div.style.left) || 0) + dx * stepPx;
+                            y = (parseFloat(div.style.top) || 0) + dy * stepPx;
+                            if (x < 0 || x > maxX) {{ dx = -dx; x = Math.min(Math.max(x, 0), maxX); }}
+                            if (y < 0 || y > maxY) {{ dy = -dy; y = Math.min(Math.max(y, 0), maxY); }}
+                            div.dataset.dx = dx;
+                            div.dataset.dy = dy;
+                        }} else if (currentConfig.movement === 'orbit') {{
+                            // Each ball gets its own ring, spread between the center and the edge of the bounds
+                            var angle = (parseFloat(div.dataset.angle) || (2 * Math.PI * i / balls.length)) + orbitStep;
+                            var radius = Math.min(maxX, maxY) / 2 * (i + 1) / balls.length;
+                            x = maxX / 2 + radius * Math.cos(angle);
+                            y = maxY / 2 + radius * Math.sin(angle);
+                            div.dataset.angle = angle;
+                        }} else {{
+                            x = Math.floor(Math.random() * maxX);
+                            y = Math.floor(Math.random() * maxY);
+                        }}
+                        div.style.left = x + 'px';
+                        div.style.top = y + 'px';
+                    }});
+                }}
+
+                moveBalls(); // Scatter the server-rendered balls
+                connect();
+
+                function updateNumberOfBalls(newNumberOfBalls, ballColor) {{
+                    const ballsContainer = document.body;
+                    const existingBalls = document.querySelectorAll('.ball');
+                    const currentNumberOfBalls = existingBalls.length;
+                    // Updates aren't clamped by the server, so hold them to the same cap as the first render
+                    newNumberOfBalls = Math.min(newNumberOfBalls, maxBalls);
+
+                    // Add balls if new number is greater
+                    for (let i = currentNumberOfBalls; i < newNumberOfBalls; i++) {{
+                        const div = document.createElement('div');
+                        div.className = 'ball';
+                        div.style.position = 'absolute';
+                        var ballSize = pickBallSize();
+                        div.style.width = ballSize + 'px';
+                        div.style.height = ballSize + 'px';
+                        div.style.borderRadius = (ballSize / 2) + 'px';
+                        div.style.backgroundColor = ballColor;
+                        div.style.transition = ballTransition();
+                        setBallLayer(div);
+                        // Set initial position
+                        div.style.left = (Math.random() * window.innerWidth) + 'px';
+                        div.style.top = (Math.random() * window.innerHeight) + 'px';
+                        ballsContainer.appendChild(div);
+                    }}
+
+                    // Remove balls if new number is smaller
+                    for (let i = currentNumberOfBalls - 1; i >= newNumberOfBalls; i--) {{
+                        existingBalls[i].remove();
+                    }}
+                }}
+            </script>
+        </body>
+        </html>",
+    );
+    (head, tail)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+
+    use actix_web::{test, App};
+
+    use super::*;
+    use crate::access::IpAccess;
+    use crate::events::ChangeSource;
+    use crate::fetch::commit_config;
+    use crate::source::InMemorySource;
+
+    fn app_state(config: Config) -> web::Data<AppState> {
+        let state = AppState::new(Settings::from_env(), Box::new(InMemorySource::new(config.clone())));
+        *state.config.write().unwrap() = Some(config);
+        web::Data::new(state)
+    }
+
+    #[actix_web::test]
+    async fn default_page_is_well_formed() {
+        let config = Config::default();
+        let data = app_state(config.clone());
+        let app = test::init_service(App::new().app_data(data).route("/", web::get().to(display_balls))).await;
+
+        let body = test::call_and_read_body(&app, test::TestRequest::get().uri("/").to_request()).await;
+        let html = String::from_utf8(body.to_vec()).unwrap();
+        assert_eq!(check_page(&html), Ok(usize::from(config.number_of_balls)));
+        assert_eq!(html.matches("<body").count(), 1);
+        assert_eq!(html.matches("<script>").count(), 1);
+    }
+
+    #[actix_web::test]
+    async fn serve_demo_page_decides_between_the_page_and_404() {
+        for (serve, status) in [(true, 200), (false, 404)] {
+            let mut settings = Settings::from_env();
+            settings.serve_demo_page = serve;
+            let state = AppState::new(settings, Box::new(InMemorySource::default()));
+            *state.config.write().unwrap() = Some(Config::default());
+            let data = web::Data::new(state);
+            let access = IpAccess::new(Arc::default());
+            let app = test::init_service(App::new().app_data(data.clone()).configure(|cfg| configure(cfg, &data, &access))).await;
+
+            let resp = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+            assert_eq!(resp.status(), status, "SERVE_DEMO_PAGE={}", serve);
+            if serve {
+                assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), "text/html");
+                let html = test::read_body(resp).await;
+                assert!(String::from_utf8_lossy(&html).contains("<body"));
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn page_is_reused_until_the_config_changes() {
+        let data = app_state(Config::default());
+        let app = test::init_service(App::new().app_data(data.clone()).route("/", web::get().to(display_balls))).await;
+
+        let first = test::call_and_read_body(&app, test::TestRequest::get().uri("/").to_request()).await;
+        let second = test::call_and_read_body(&app, test::TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(first, second);
+        assert_eq!(data.page_cache.lock().unwrap().as_ref().map(|page| page.html.clone()), Some(first.clone()));
+
+        commit_config(&data, Config::builder().ball_color("red").build(), ChangeSource::Admin);
+        let third = test::call_and_read_body(&app, test::TestRequest::get().uri("/").to_request()).await;
+        assert_ne!(first, third);
+        assert!(String::from_utf8_lossy(&third).contains("background-color: red"));
+    }
+
+    #[actix_web::test]
+    async fn invalid_overrides_get_400_and_no_override_is_stored() {
+        let data = app_state(Config::default());
+        let mut events = data.events.subscribe();
+        let app = test::init_service(App::new().app_data(data.clone()).route("/", web::get().to(display_balls))).await;
+
+        let query = test::TestRequest::get().uri("/?ball_color=red%3B").to_request();
+        let header = test::TestRequest::get().uri("/").insert_header((CONFIG_OVERRIDE_HEADER, r#"{"ball_color":"red;"}"#)).to_request();
+        for req in [query, header] {
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 400);
+            let error: serde_json::Value = test::read_body_json(resp).await;
+            assert_eq!(error["error"]["code"], "invalid_config");
+        }
+
+        let req = test::TestRequest::get().uri("/?number_of_balls=3").insert_header((CONFIG_OVERRIDE_HEADER, r#"{"ball_color":"green"}"#)).to_request();
+        let html = test::call_and_read_body(&app, req).await;
+        let html = String::from_utf8_lossy(&html);
+        assert_eq!(check_page(&html), Ok(3));
+        assert!(html.contains("background-color: green"));
+        assert_eq!(*data.config.read().unwrap(), Some(Config::default()));
+        assert!(events.try_recv().is_err(), "an override was broadcast");
+    }
+
+    #[actix_web::test]
+    async fn contended_lock_renders_the_last_config_with_the_requests_overrides() {
+        let mut settings = Settings::from_env();
+        settings.config_lock_timeout_ms = 20;
+        let state = AppState::new(settings, Box::new(InMemorySource::default()));
+        *state.config.write().unwrap() = Some(Config::default());
+        let data = web::Data::new(state);
+        let app = test::init_service(App::new().app_data(data.clone()).route("/", web::get().to(display_balls))).await;
+        test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+
+        // A writer that holds the lock until told to let go
+        let (config, (locked_tx, locked_rx), (release_tx, release_rx)) = (data.config.clone(), mpsc::channel(), mpsc::channel::<()>());
+        let writer = thread::spawn(move || {
+            let _guard = config.write().unwrap();
+            locked_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        locked_rx.recv().unwrap();
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/?ball_color=green").to_request()).await;
+        release_tx.send(()).unwrap();
+        writer.join().unwrap();
+        assert_eq!(resp.status(), 200);
+        let body = test::read_body(resp).await;
+        assert!(String::from_utf8_lossy(&body).contains("background-color: green"));
+    }
+}
@@ -0,0 +1,154 @@
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpResponse, ResponseError};
+use std::fmt;
+
+// Errors returned by the HTTP endpoints. Every variant renders as
+// `{"error":{"code":"...","message":"..."}}` with a matching status code.
+#[derive(Debug)]
+pub enum ApiError {
+    ConfigUnavailable,          // No config has been loaded yet
+    InvalidConfig(Vec<String>), // A config (or override) failed validation; every rule it broke
+    BadRequest(String),
+    PayloadTooLarge(String),
+    Unauthorized,               // Missing or wrong admin token
+    AdminDisabled,              // No admin token is configured
+    OriginNotAllowed(String),   // A WebSocket handshake from an origin outside the allowlist
+    AddressNotAllowed(String),  // A client address outside `DISPLAY_ALLOWED_IPS`, or inside `DISPLAY_DENIED_IPS`
+    NotFound(String),
+    UpgradeRequired(String),    // A plain request to a WebSocket endpoint
+    UpstreamFailed(String),     // Fetching from the config upstream failed
+    RateLimited(u64),           // Too many requests; seconds until the client may retry
+    Busy,                       // The config was locked for longer than `CONFIG_LOCK_TIMEOUT_MS`
+    Timeout(String),            // The WebSocket manager didn't answer within `WS_MANAGER_TIMEOUT_MS`
+    Overloaded(String),         // Too many WebSocket upgrades in progress, per `WS_MAX_CONCURRENT_UPGRADES`
+    Internal(String),
+}
+
+impl ApiError {
+    // Stable, machine-readable identifier for the error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::ConfigUnavailable => "config_unavailable",
+            ApiError::InvalidConfig(_) => "invalid_config",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::AdminDisabled => "admin_disabled",
+            ApiError::OriginNotAllowed(_) => "origin_not_allowed",
+            ApiError::AddressNotAllowed(_) => "address_not_allowed",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::UpgradeRequired(_) => "upgrade_required",
+            ApiError::UpstreamFailed(_) => "upstream_failed",
+            ApiError::RateLimited(_) => "rate_limited",
+            ApiError::Busy => "busy",
+            ApiError::Timeout(_) => "timeout",
+            ApiError::Overloaded(_) => "overloaded",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    // The error envelope, also used for errors sent over WebSocket. Validation errors also list
+    // each problem on its own under `errors`.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            ApiError::InvalidConfig(errors) => serde_json::json!({
+                "error": { "code": self.code(), "message": self.to_string(), "errors": errors }
+            }),
+            _ => serde_json::json!({
+                "error": { "code": self.code(), "message": self.to_string() }
+            }),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::ConfigUnavailable => write!(f, "Configuration not available."),
+            ApiError::Unauthorized => write!(f, "A valid admin token is required."),
+            ApiError::AdminDisabled => write!(f, "Admin endpoints are disabled."),
+            ApiError::OriginNotAllowed(origin) => write!(f, "Origin {} is not allowed.", origin),
+            ApiError::AddressNotAllowed(ip) => write!(f, "Address {} is not allowed.", ip),
+            ApiError::RateLimited(secs) => write!(f, "Too many requests; retry in {}s.", secs),
+            ApiError::Busy => write!(f, "The config is being updated; retry shortly."),
+            ApiError::InvalidConfig(errors) => write!(f, "{}", errors.join("; ")),
+            ApiError::BadRequest(message)
+            | ApiError::PayloadTooLarge(message)
+            | ApiError::NotFound(message)
+            | ApiError::UpgradeRequired(message)
+            | ApiError::UpstreamFailed(message)
+            | ApiError::Timeout(message)
+            | ApiError::Overloaded(message)
+            | ApiError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::ConfigUnavailable | ApiError::Busy | ApiError::Timeout(_) | ApiError::Overloaded(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::InvalidConfig(_) | ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::AdminDisabled | ApiError::OriginNotAllowed(_) | ApiError::AddressNotAllowed(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::UpgradeRequired(_) => StatusCode::UPGRADE_REQUIRED,
+            ApiError::UpstreamFailed(_) => StatusCode::BAD_GATEWAY,
+            ApiError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        match self {
+            ApiError::Unauthorized => {
+                response.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+            }
+            ApiError::RateLimited(secs) => {
+                response.insert_header((header::RETRY_AFTER, secs.to_string()));
+            }
+            ApiError::UpgradeRequired(_) => {
+                response.insert_header((header::UPGRADE, "websocket"));
+            }
+            ApiError::Busy | ApiError::Timeout(_) | ApiError::Overloaded(_) => {
+                response.insert_header((header::RETRY_AFTER, "1"));
+            }
+            _ => (),
+        }
+        response.json(self.to_json())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_variant_maps_to_its_status_and_code() {
+        let cases = [
+            (ApiError::ConfigUnavailable, StatusCode::SERVICE_UNAVAILABLE, "config_unavailable"),
+            (ApiError::InvalidConfig(vec!["bad".to_string()]), StatusCode::BAD_REQUEST, "invalid_config"),
+            (ApiError::BadRequest(String::new()), StatusCode::BAD_REQUEST, "bad_request"),
+            (ApiError::PayloadTooLarge(String::new()), StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large"),
+            (ApiError::Unauthorized, StatusCode::UNAUTHORIZED, "unauthorized"),
+            (ApiError::AdminDisabled, StatusCode::FORBIDDEN, "admin_disabled"),
+            (ApiError::OriginNotAllowed(String::new()), StatusCode::FORBIDDEN, "origin_not_allowed"),
+            (ApiError::AddressNotAllowed(String::new()), StatusCode::FORBIDDEN, "address_not_allowed"),
+            (ApiError::NotFound(String::new()), StatusCode::NOT_FOUND, "not_found"),
+            (ApiError::UpgradeRequired(String::new()), StatusCode::UPGRADE_REQUIRED, "upgrade_required"),
+            (ApiError::UpstreamFailed(String::new()), StatusCode::BAD_GATEWAY, "upstream_failed"),
+            (ApiError::RateLimited(3), StatusCode::TOO_MANY_REQUESTS, "rate_limited"),
+            (ApiError::Busy, StatusCode::SERVICE_UNAVAILABLE, "busy"),
+            (ApiError::Timeout(String::new()), StatusCode::SERVICE_UNAVAILABLE, "timeout"),
+            (ApiError::Overloaded(String::new()), StatusCode::SERVICE_UNAVAILABLE, "overloaded"),
+            (ApiError::Internal(String::new()), StatusCode::INTERNAL_SERVER_ERROR, "internal_error"),
+        ];
+        for (error, status, code) in cases {
+            let response = error.error_response();
+            assert_eq!(response.status(), status, "{:?}", error);
+            assert_eq!(error.code(), code);
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::Config;
+
+// How many changes a slow subscriber may fall behind before it misses the oldest ones.
+pub const EVENT_CAPACITY: usize = 16;
+
+// A new config was committed to the shared state. Only sent when it differs from the previous one.
+#[derive(Debug, Clone)]
+pub struct ConfigChanged {
+    pub config: Config,
+    pub previous: Option<Config>, // What it replaced; `None` for the first config loaded
+    pub source: ChangeSource,
+}
+
+// What triggered a config change; sent to WebSocket clients with each patch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeSource {
+    Poll,  // The regular upstream poll, including the initial load
+    Admin, // A change an operator made, e.g. `POST /admin/refresh` or a state import
+    Redis, // A config another instance published, applied by a `REDIS_ROLE=subscriber`
+}
+
+impl ChangeSource {
+    // The name clients and the audit log see, as serialized.
+    pub fn label(self) -> &'static str {
+        match self {
+            ChangeSource::Poll => "poll",
+            ChangeSource::Admin => "admin",
+            ChangeSource::Redis => "redis",
+        }
+    }
+}
+
+// Where committed config changes are announced. The WebSocket fan-out, the webhook, the metrics,
+// and the audit log each subscribe on their own, so the fetch path doesn't need to know who's
+// listening.
+pub type ConfigEvents = broadcast::Sender<ConfigChanged>;
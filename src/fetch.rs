@@ -0,0 +1,377 @@
+use std::ops::ControlFlow;
+use std::str::FromStr;
+use std::time::Instant;
+
+use actix_web::web;
+use log::{debug, info, warn};
+use rand::Rng;
+use tokio::time::{sleep, Duration};
+
+use crate::appstate::{read_lock, write_lock, AppState};
+use crate::events::{ChangeSource, ConfigChanged};
+use crate::metrics::UPSTREAM_CLOCK_SKEW_SECONDS;
+use crate::source::{ConfigError, UpstreamMode};
+use crate::Config;
+
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1); // First wait after a failed long poll or a dropped stream, doubled up to the poll interval
+const LONG_POLL_MIN_INTERVAL: Duration = Duration::from_millis(250); // Least time between long polls, in case the upstream answers without holding
+
+// What a fetch does when another one (from the poll loop or an admin refresh) is still running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchOverlap {
+    Wait, // Queue up behind it, then fetch
+    Skip, // Leave it to the running fetch and report no change
+}
+
+impl FromStr for FetchOverlap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wait" => Ok(FetchOverlap::Wait),
+            "skip" => Ok(FetchOverlap::Skip),
+            other => Err(format!("unknown mode {:?}", other)),
+        }
+    }
+}
+
+// Poll the config source, commit the config to the shared state, and announce it on
+// `AppState::events` if it changed. Returns `None` when the upstream reported no change, when
+// this fetch was skipped because another one was running, or when the last fetch is still within
+// `upstream_cache_ttl_secs`.
+pub async fn fetch_and_update_config(app_state: web::Data<AppState>) -> Result<Option<Config>, ConfigError> {
+    fetch(app_state, ChangeSource::Poll).await
+}
+
+// Like `fetch_and_update_config`, for fetches an operator asked for: always asks the upstream,
+// however recent the last fetch was, and announces any change as coming from `admin`.
+pub async fn force_fetch_config(app_state: web::Data<AppState>) -> Result<Option<Config>, ConfigError> {
+    fetch(app_state, ChangeSource::Admin).await
+}
+
+async fn fetch(app_state: web::Data<AppState>, source: ChangeSource) -> Result<Option<Config>, ConfigError> {
+    // A streaming upstream pushes every change as it happens, so there's never anything to fetch
+    if app_state.settings.upstream_mode == UpstreamMode::Stream {
+        debug!("Configs are streamed from the upstream, not fetching");
+        return Ok(None);
+    }
+
+    // Only one fetch at a time, or a slow one could commit its result over a newer one's
+    let _in_flight = match app_state.settings.fetch_overlap {
+        FetchOverlap::Wait => app_state.fetch_lock.lock().await,
+        FetchOverlap::Skip => match app_state.fetch_lock.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                debug!("A config fetch is already running, skipping this one");
+                return Ok(None);
+            }
+        },
+    };
+
+    // Spare an expensive upstream the round trip while the last answer is fresh enough. A long poll
+    // is the wait for a change, so skipping it would only spin
+    let ttl = app_state.live.upstream_cache_ttl();
+    if source == ChangeSource::Poll && !ttl.is_zero() && app_state.settings.upstream_mode == UpstreamMode::Poll {
+        if let Some(fetched_at) = *app_state.last_fetched.lock().unwrap() {
+            if fetched_at.elapsed() < ttl {
+                debug!("Last upstream fetch is under {:?} old, skipping this one", ttl);
+                return Ok(None);
+            }
+        }
+    }
+
+    let current = read_lock(&app_state.config).clone();
+    // An out-of-bounds config would break every page render, so it counts as a failed fetch
+    let fetched = app_state.source.fetch(current.as_ref()).await.and_then(|fetched| fetched.map(validated).transpose());
+    match &fetched {
+        Ok(_) => app_state.upstream.record_success(),
+        Err(_) => app_state.upstream.record_failure(),
+    }
+    app_state.report_upstream_health();
+    check_clock_skew(&app_state);
+    let config = match fetched? {
+        Some(config) => config,
+        None => {
+            // Still an answer from the upstream, so the config in effect is as good as new
+            if let Some(fetched_at) = &mut *app_state.last_fetched.lock().unwrap() {
+                *fetched_at = Instant::now();
+            }
+            return Ok(None);
+        }
+    };
+    if !ttl.is_zero() {
+        *app_state.last_fetched.lock().unwrap() = Some(Instant::now());
+    }
+
+    let config = keep_locked_fields(&app_state, current.as_ref(), config);
+    commit_config(&app_state, config.clone(), source);
+    Ok(Some(config))
+}
+
+// Locked fields belong to the operator: keep their `current` values whatever the upstream sends.
+pub(crate) fn keep_locked_fields(app_state: &AppState, current: Option<&Config>, config: Config) -> Config {
+    let locked = &app_state.settings.locked_fields;
+    let Some(current) = current.filter(|_| !locked.is_empty()) else {
+        return config;
+    };
+    let kept = config.with_fields_from(current, locked);
+    if kept != config {
+        debug!("Ignoring upstream changes to locked fields {:?}", locked);
+    }
+    kept
+}
+
+// Commit every config the source streams, counting each in `received`, until the stream ends.
+// With `once`, the stream is closed after the first config. Only one stream is ever open, and
+// nothing else fetches while configs are streamed, so unlike `fetch` this takes no lock.
+async fn follow_stream(app_state: &AppState, once: bool, received: &mut usize) -> Result<(), ConfigError> {
+    let current = read_lock(&app_state.config).clone();
+    let mut invalid = None;
+    app_state
+        .source
+        .stream(current, &mut |config| {
+            // Ends the stream like a payload that can't be parsed would, keeping the current config
+            let config = match validated(config) {
+                Ok(config) => config,
+                Err(e) => {
+                    invalid = Some(e);
+                    return ControlFlow::Break(());
+                }
+            };
+            *received += 1;
+            app_state.upstream.record_success();
+            app_state.report_upstream_health();
+            // The skew is measured when the stream opens
+            if *received == 1 {
+                check_clock_skew(app_state);
+            }
+            let current = read_lock(&app_state.config).clone();
+            let config = keep_locked_fields(app_state, current.as_ref(), config);
+            commit_config(app_state, config, ChangeSource::Poll);
+            if once {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+        .await?;
+    invalid.map_or(Ok(()), Err)
+}
+
+// `config`, if it respects the bounds every config must; the same checks overrides and configs
+// from Redis are held to.
+fn validated(config: Config) -> Result<Config, ConfigError> {
+    config.validate().map_err(ConfigError::Invalid)?;
+    Ok(config)
+}
+
+// Report how far the upstream's clock is from ours, warning past `upstream_clock_skew_warn_secs`:
+// a large skew hints at a cache serving stale answers or a misconfigured host.
+fn check_clock_skew(app_state: &AppState) {
+    let Some(skew) = app_state.source.clock_skew() else {
+        return;
+    };
+    app_state.metrics.set_gauge(UPSTREAM_CLOCK_SKEW_SECONDS, skew as f64);
+    let threshold = app_state.settings.upstream_clock_skew_warn_secs;
+    if threshold > 0 && skew.unsigned_abs() > threshold {
+        warn!("Upstream clock is {}s {} ours", skew.unsigned_abs(), if skew > 0 { "ahead of" } else { "behind" });
+    }
+}
+
+// Make `config` the one in effect, announcing it on `AppState::events` if it differs from the
+// previous one.
+pub fn commit_config(app_state: &AppState, config: Config, source: ChangeSource) {
+    let previous = write_lock(&app_state.config).replace(config.clone());
+
+    // Let the WebSocket fan-out, webhook, metrics, and audit log know; an unchanged config isn't news
+    if previous.as_ref() != Some(&config) && app_state.events.send(ConfigChanged { config, previous, source }).is_err() {
+        debug!("No subscribers for config changes");
+    }
+}
+
+// Fetch the config once before serving, failing startup if it can't be loaded in time.
+pub async fn load_initial_config(app_state: web::Data<AppState>) -> std::io::Result<()> {
+    let timeout = Duration::from_secs(app_state.settings.ready_timeout_secs);
+    let initial = async {
+        match app_state.settings.upstream_mode {
+            UpstreamMode::Stream => follow_stream(&app_state, true, &mut 0).await,
+            UpstreamMode::Poll | UpstreamMode::LongPoll => fetch_and_update_config(app_state.clone()).await.map(|_| ()),
+        }
+    };
+    match tokio::time::timeout(timeout, initial).await {
+        Ok(Ok(_)) => {
+            info!("Initial config loaded");
+            Ok(())
+        }
+        Ok(Err(e)) => Err(std::io::Error::other(format!("Failed to load initial config: {}", e))),
+        Err(_) => Err(std::io::Error::other(format!("Timed out after {:?} loading initial config", timeout))),
+    }
+}
+
+// Try the upstream once at startup, logging what's wrong in terms an operator can act on. The
+// config isn't committed, and a failure doesn't stop the service; the poll loop keeps trying.
+pub async fn self_check(app_state: &AppState) -> Result<(), ConfigError> {
+    let checked = match app_state.settings.upstream_mode {
+        UpstreamMode::Stream => app_state.source.stream(None, &mut |_| ControlFlow::Break(())).await,
+        UpstreamMode::Poll | UpstreamMode::LongPoll => app_state.source.fetch(None).await.map(|_| ()),
+    };
+    match &checked {
+        Ok(()) => info!("Upstream self-check passed"),
+        Err(e) => warn!("Upstream self-check failed: {}", e.diagnosis()),
+    }
+    checked
+}
+
+// Pick a random delay in `0..=max` so replicas started together don't all poll at once.
+pub fn initial_stagger(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    rand::thread_rng().gen_range(Duration::ZERO..=max)
+}
+
+// Follow the config source forever, as `UPSTREAM_MODE` says, starting after a random stagger.
+pub async fn poll_config(app_state: web::Data<AppState>) {
+    // Scrapes before the first fetch see the upstream as healthy, like `/ready` does
+    app_state.report_upstream_health();
+    let stagger = initial_stagger(Duration::from_millis(app_state.settings.initial_stagger_max_ms));
+    debug!("Delaying first config fetch by {:?}", stagger);
+    sleep(stagger).await;
+
+    if app_state.settings.upstream_mode == UpstreamMode::Stream {
+        return stream_config(app_state).await;
+    }
+    let mut retry_delay = RECONNECT_MIN_DELAY;
+    loop {
+        let fetched = fetch_and_update_config(app_state.clone()).await;
+        if let Err(e) = &fetched {
+            // Logged per attempt at warn; `UpstreamHealth` escalates once the upstream is down
+            warn!("Failed to fetch config: {}", e);
+        }
+        if let Some(health_check) = &app_state.health_check {
+            health_check.probe().await;
+            app_state.report_upstream_health();
+        }
+        let delay = match (app_state.settings.upstream_mode, fetched) {
+            (UpstreamMode::LongPoll, Ok(_)) => {
+                retry_delay = RECONNECT_MIN_DELAY;
+                LONG_POLL_MIN_INTERVAL
+            }
+            (UpstreamMode::LongPoll, Err(_)) => next_retry_delay(&app_state, &mut retry_delay),
+            _ => app_state.live.poll_interval(),
+        };
+        sleep(delay).await;
+    }
+}
+
+// Poll every profile's upstream forever, every `POLL_INTERVAL_SECS`, whatever `UPSTREAM_MODE`
+// the shared config follows. Reads the set of profiles afresh each time, so changes to it apply
+// from the next poll.
+pub async fn poll_profiles(app_state: web::Data<AppState>) {
+    loop {
+        app_state.profiles.poll().await;
+        sleep(app_state.live.poll_interval()).await;
+    }
+}
+
+// Keep a stream from the source open, reconnecting with backoff whenever it ends.
+async fn stream_config(app_state: web::Data<AppState>) {
+    let mut retry_delay = RECONNECT_MIN_DELAY;
+    loop {
+        let mut received = 0;
+        let result = follow_stream(&app_state, false, &mut received).await;
+        if received > 0 {
+            retry_delay = RECONNECT_MIN_DELAY;
+        }
+        match result {
+            Ok(()) => info!("Upstream closed the config stream after {} configs", received),
+            Err(e) => {
+                app_state.upstream.record_failure();
+                app_state.report_upstream_health();
+                warn!("Config stream failed after {} configs: {}", received, e);
+            }
+        }
+        let delay = next_retry_delay(&app_state, &mut retry_delay);
+        debug!("Reconnecting to the config stream in {:?}", delay);
+        sleep(delay).await;
+    }
+}
+
+// The wait before the next reconnect, doubling `retry_delay` for the one after, up to the poll interval.
+fn next_retry_delay(app_state: &AppState, retry_delay: &mut Duration) -> Duration {
+    let delay = *retry_delay;
+    *retry_delay = (delay * 2).min(app_state.live.poll_interval().max(RECONNECT_MIN_DELAY));
+    delay
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::settings::Settings;
+    use crate::source::{ConfigSource, InMemorySource};
+
+    // Serves a fixed config, counting how often it's asked.
+    #[derive(Default)]
+    struct CountingSource {
+        fetches: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl ConfigSource for CountingSource {
+        async fn fetch(&self, _current: Option<&Config>) -> Result<Option<Config>, ConfigError> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            Ok(Some(Config::default()))
+        }
+    }
+
+    fn app_state(current: Config, upstream: Config) -> web::Data<AppState> {
+        let state = AppState::new(Settings::from_env(), Box::new(InMemorySource::new(upstream)));
+        *state.config.write().unwrap() = Some(current);
+        web::Data::new(state)
+    }
+
+    #[actix_web::test]
+    async fn polls_within_the_ttl_reuse_the_last_answer() {
+        let mut settings = Settings::from_env();
+        settings.upstream_cache_ttl_secs = 60;
+        let source = CountingSource::default();
+        let fetches = source.fetches.clone();
+        let data = web::Data::new(AppState::new(settings, Box::new(source)));
+
+        fetch_and_update_config(data.clone()).await.unwrap();
+        fetch_and_update_config(data.clone()).await.unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        force_fetch_config(data.clone()).await.unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_web::test]
+    async fn change_source_follows_the_fetch_path() {
+        let polled = app_state(Config::default(), Config::builder().ball_color("red").build());
+        let mut events = polled.events.subscribe();
+        fetch_and_update_config(polled.clone()).await.unwrap();
+        assert_eq!(events.try_recv().unwrap().source, ChangeSource::Poll);
+
+        let pushed = app_state(Config::default(), Config::builder().ball_color("red").build());
+        let mut events = pushed.events.subscribe();
+        force_fetch_config(pushed.clone()).await.unwrap();
+        assert_eq!(events.try_recv().unwrap().source, ChangeSource::Admin);
+    }
+
+    #[actix_web::test]
+    async fn invalid_upstream_config_is_rejected() {
+        let data = app_state(Config::default(), Config::builder().ball_size(0).ball_color("red").build());
+        let mut events = data.events.subscribe();
+
+        let fetched = fetch_and_update_config(data.clone()).await;
+        assert!(matches!(fetched, Err(ConfigError::Invalid(errors)) if errors.len() == 1));
+        assert_eq!(*data.config.read().unwrap(), Some(Config::default()));
+        assert!(events.try_recv().is_err());
+    }
+}
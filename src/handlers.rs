@@ -0,0 +1,229 @@
+use std::convert::Infallible;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use actix::Addr;
+use actix_web::error::JsonPayloadError;
+use actix_web::http::header::{self, ContentEncoding};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use log::error;
+use serde::Serialize;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+
+use crate::appstate::{read_lock, AppState};
+use crate::error::ApiError;
+use crate::websocket::{ask_manager, ConfigBroadcaster, GetHistory, SessionCount, WsManager};
+use crate::Config;
+
+// Build metadata reported by `/version`, captured at compile time by `build.rs`.
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    version: &'static str,
+    git_commit: &'static str,
+    build_timestamp: u64, // Unix seconds
+}
+
+pub async fn version() -> impl Responder {
+    web::Json(BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: env!("GIT_COMMIT"),
+        build_timestamp: env!("BUILD_TIMESTAMP").parse().unwrap_or(0),
+    })
+}
+
+// Readiness: a config has been loaded and the upstream isn't marked down.
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    ready: bool,
+    config_loaded: bool,
+    upstream_healthy: bool,
+}
+
+pub async fn ready(data: web::Data<AppState>) -> HttpResponse {
+    let config_loaded = read_lock(&data.config).is_some();
+    let upstream_healthy = data.upstream_health().is_healthy();
+    let readiness = Readiness { ready: config_loaded && upstream_healthy, config_loaded, upstream_healthy };
+    if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+// At-a-glance view of the poll loop and connected clients, for dashboards.
+#[derive(Debug, Serialize)]
+pub struct Status {
+    last_attempt: Option<u64>,             // Unix seconds of the last fetch attempt
+    last_success: Option<u64>,             // Unix seconds of the last successful fetch
+    consecutive_failures: u32,
+    connected_clients: usize,
+    upstream_clock_skew_secs: Option<i64>, // How far the upstream's clock was ahead of ours at its last answer
+}
+
+pub async fn status(data: web::Data<AppState>, ws_manager: web::Data<Addr<WsManager>>) -> Result<HttpResponse, ApiError> {
+    let connected_clients = ask_manager(&ws_manager, SessionCount, Duration::from_millis(data.settings.ws_manager_timeout_ms), "query session count").await?;
+    Ok(HttpResponse::Ok().json(Status {
+        last_attempt: data.upstream.last_fetch(),
+        last_success: data.upstream.last_success(),
+        consecutive_failures: data.upstream.consecutive_failures(),
+        connected_clients,
+        upstream_clock_skew_secs: data.source.clock_skew(),
+    }))
+}
+
+// Upstream health and WebSocket session metrics in the Prometheus text exposition format, when
+// the metrics backend keeps them for scraping.
+pub async fn metrics(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let body = data.metrics.render().ok_or_else(|| ApiError::NotFound("Metrics aren't served over HTTP.".to_string()))?;
+    Ok(HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(body))
+}
+
+// Turn JSON body errors into `ApiError`s, with 413 for bodies over the configured limit.
+pub fn json_error_handler(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    match &err {
+        JsonPayloadError::Overflow { .. } | JsonPayloadError::OverflowKnownLength { .. } => ApiError::PayloadTooLarge(err.to_string()),
+        _ => ApiError::BadRequest(err.to_string()),
+    }
+    .into()
+}
+
+// Wire formats `GET /config` can answer in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Json,
+    Yaml,
+}
+
+impl FromStr for ConfigFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ConfigFormat::Json),
+            "yaml" => Ok(ConfigFormat::Yaml),
+            other => Err(format!("unknown format {:?}", other)),
+        }
+    }
+}
+
+impl ConfigFormat {
+    // Pick the first supported media type from the `Accept` header, falling back to `default` when
+    // it names none, e.g. when it's missing or `*/*`.
+    pub fn negotiate(req: &HttpRequest, default: ConfigFormat) -> Self {
+        let accept = req.headers().get(header::ACCEPT).and_then(|v| v.to_str().ok()).unwrap_or("");
+        accept
+            .split(',')
+            .filter_map(|media_type| match media_type.split(';').next().unwrap_or("").trim() {
+                "application/json" => Some(ConfigFormat::Json),
+                "application/yaml" | "text/yaml" => Some(ConfigFormat::Yaml),
+                _ => None,
+            })
+            .next()
+            .unwrap_or(default)
+    }
+}
+
+// Serve the current config in the format the client asked for, cacheable by proxies for
+// `CONFIG_CACHE_MAX_AGE_SECS`. The body depends on `Accept`, so caches must key on it too.
+pub async fn get_config(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let config = data.read_config("/config").await?.ok_or(ApiError::ConfigUnavailable)?;
+    let cache_control = match data.settings.config_cache_max_age_secs {
+        0 => "no-cache".to_string(),
+        max_age => format!("max-age={}", max_age),
+    };
+
+    let mut response = HttpResponse::Ok();
+    response.insert_header((header::CACHE_CONTROL, cache_control)).insert_header((header::VARY, "Accept"));
+
+    match ConfigFormat::negotiate(&req, data.settings.config_default_format) {
+        ConfigFormat::Json => Ok(response.json(config)),
+        ConfigFormat::Yaml => match serde_yaml::to_string(&config) {
+            Ok(yaml) => Ok(response.content_type("application/yaml").body(yaml)),
+            Err(e) => {
+                error!("Failed to serialize config as YAML: {}", e);
+                Err(ApiError::Internal("Failed to serialize config.".to_string()))
+            }
+        },
+    }
+}
+
+// The broadcasts the manager keeps for replay, rebuilt as full configs, oldest first.
+pub async fn config_history(data: web::Data<AppState>, ws_manager: web::Data<Addr<WsManager>>) -> Result<HttpResponse, ApiError> {
+    let history = ask_manager(&ws_manager, GetHistory, Duration::from_millis(data.settings.ws_manager_timeout_ms), "read the history").await?;
+    Ok(HttpResponse::Ok().json(history))
+}
+
+// Stream the config as server-sent events, for clients that can't use WebSockets: the current
+// config on connect, then the full config again after every broadcast.
+pub async fn config_stream(data: web::Data<AppState>, broadcaster: web::Data<ConfigBroadcaster>) -> HttpResponse {
+    let config = data.config.clone();
+    let initial = sse_event(&config);
+    // Broadcasts may be patches; reading the shared config instead always yields the whole config,
+    // and also covers updates a lagging client skipped
+    let updates = BroadcastStream::new(broadcaster.subscribe()).filter_map(move |_| sse_event(&config));
+    let events = tokio_stream::iter(initial).chain(updates).map(Ok::<_, Infallible>);
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        // Keep `Compress` from buffering events
+        .insert_header(ContentEncoding::Identity)
+        .streaming(events)
+}
+
+// The shared config as one SSE `data:` event, or `None` while none is loaded.
+fn sse_event(config: &Arc<RwLock<Option<Config>>>) -> Option<Bytes> {
+    let config = read_lock(config).clone()?;
+    match serde_json::to_string(&config) {
+        Ok(json) => Some(Bytes::from(format!("data: {}\n\n", json))),
+        Err(e) => {
+            error!("Failed to serialize config for SSE: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::App;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::settings::Settings;
+    use crate::source::InMemorySource;
+
+    #[actix_web::test]
+    async fn oversized_json_body_gets_413_as_a_json_error() {
+        let json = web::JsonConfig::default().limit(64).error_handler(json_error_handler);
+        let app = init_service(App::new().app_data(json).route("/admin/import", web::post().to(|_: web::Json<Value>| async { HttpResponse::Ok().finish() }))).await;
+
+        let body = serde_json::json!({ "ball_color": "x".repeat(100) });
+        let res = call_service(&app, TestRequest::post().uri("/admin/import").set_json(body).to_request()).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let error: Value = read_body_json(res).await;
+        assert_eq!(error["error"]["code"], "payload_too_large");
+    }
+
+    #[actix_web::test]
+    async fn poisoned_config_lock_is_still_read() {
+        let state = AppState::new(Settings::from_env(), Box::new(InMemorySource::default()));
+        *state.config.write().unwrap() = Some(Config::default());
+        let config = state.config.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = config.write().unwrap();
+            panic!("writer panics holding the lock");
+        })
+        .join();
+        assert!(state.config.is_poisoned());
+
+        let app = init_service(App::new().app_data(web::Data::new(state)).route("/ready", web::get().to(ready))).await;
+        let res = call_service(&app, TestRequest::get().uri("/ready").to_request()).await;
+        let readiness: Value = read_body_json(res).await;
+        assert_eq!(readiness["config_loaded"], true);
+    }
+}
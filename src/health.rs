@@ -0,0 +1,116 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{error, info, warn};
+
+// Tracks upstream fetch outcomes. The upstream counts as unhealthy once more than
+// `threshold` fetches in a row have failed, until the next success.
+pub struct UpstreamHealth {
+    threshold: u32,
+    consecutive_failures: AtomicU32,
+    healthy: AtomicBool,
+    fetches_total: AtomicU64,
+    fetches_succeeded: AtomicU64,
+    last_fetch_unix: AtomicU64,   // Unix seconds of the last attempt; 0 until the first one
+    last_success_unix: AtomicU64, // Unix seconds of the last successful fetch; 0 until the first one
+}
+
+impl UpstreamHealth {
+    pub fn new(threshold: u32) -> Self {
+        Self {
+            threshold,
+            consecutive_failures: AtomicU32::new(0),
+            healthy: AtomicBool::new(true),
+            fetches_total: AtomicU64::new(0),
+            fetches_succeeded: AtomicU64::new(0),
+            last_fetch_unix: AtomicU64::new(0),
+            last_success_unix: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_success(&self) {
+        let now = self.record_attempt();
+        self.last_success_unix.store(now, Ordering::Relaxed);
+        self.fetches_succeeded.fetch_add(1, Ordering::Relaxed);
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        if !self.healthy.swap(true, Ordering::Relaxed) {
+            info!("Config upstream recovered");
+        }
+    }
+
+    // Count a failed fetch, escalating once when the threshold is crossed rather than per attempt.
+    pub fn record_failure(&self) {
+        self.record_attempt();
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed).saturating_add(1);
+        if failures > self.threshold && self.healthy.swap(false, Ordering::Relaxed) {
+            error!("Config upstream is down: {} consecutive attempts failed", failures);
+        }
+    }
+
+    // Count an attempt and stamp it, returning the stamp.
+    fn record_attempt(&self) -> u64 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        self.last_fetch_unix.store(now, Ordering::Relaxed);
+        self.fetches_total.fetch_add(1, Ordering::Relaxed);
+        now
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures.load(Ordering::Relaxed)
+    }
+
+    // Unix seconds of the last fetch attempt, if there has been one.
+    pub fn last_fetch(&self) -> Option<u64> {
+        Some(self.last_fetch_unix.load(Ordering::Relaxed)).filter(|&secs| secs > 0)
+    }
+
+    // Unix seconds of the last successful fetch, if there has been one.
+    pub fn last_success(&self) -> Option<u64> {
+        Some(self.last_success_unix.load(Ordering::Relaxed)).filter(|&secs| secs > 0)
+    }
+
+    // Fraction of fetches that succeeded since startup, if any were made.
+    pub fn success_rate(&self) -> Option<f64> {
+        let total = self.fetches_total.load(Ordering::Relaxed);
+        let succeeded = self.fetches_succeeded.load(Ordering::Relaxed);
+        (total > 0).then(|| succeeded as f64 / total as f64)
+    }
+}
+
+// Probes a dedicated upstream health endpoint, for when a successful config fetch says little
+// about the origin, e.g. because the config is served from a cache. Any 2xx counts as healthy.
+pub struct HealthCheck {
+    client: reqwest::Client,
+    url: String,
+    health: UpstreamHealth, // Probe outcomes, judged like fetches are
+}
+
+impl HealthCheck {
+    pub fn new(url: String, timeout: Duration, threshold: u32) -> Result<Self, reqwest::Error> {
+        let client = reqwest::Client::builder().timeout(timeout).build()?;
+        Ok(Self { client, url, health: UpstreamHealth::new(threshold) })
+    }
+
+    // Probe the endpoint once and record the outcome.
+    pub async fn probe(&self) {
+        match self.client.get(&self.url).send().await {
+            Ok(resp) if resp.status().is_success() => self.health.record_success(),
+            Ok(resp) => {
+                warn!("Upstream health check {} returned {}", self.url, resp.status());
+                self.health.record_failure();
+            }
+            Err(e) => {
+                warn!("Upstream health check {} failed: {}", self.url, e);
+                self.health.record_failure();
+            }
+        }
+    }
+
+    pub fn health(&self) -> &UpstreamHealth {
+        &self.health
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+use serde_json::{Map, Value};
+
+use crate::Config;
+
+// How the broadcast history keeps its entries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HistoryMode {
+    #[default]
+    Full, // A whole copy of every config
+    Diff, // Only the oldest config whole; each later one as the fields that changed from the one before
+}
+
+impl FromStr for HistoryMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "full" => Ok(HistoryMode::Full),
+            "diff" => Ok(HistoryMode::Diff),
+            other => Err(format!("unknown history mode {:?}; expected full or diff", other)),
+        }
+    }
+}
+
+// A stored entry. The front of the history is always `Full`, so every entry can be rebuilt.
+#[derive(Debug, Clone)]
+enum Entry {
+    Full(Config),
+    Diff(Map<String, Value>), // Changed fields; a removed optional field is `null`
+}
+
+// The latest broadcast configs, oldest first, bounded to `capacity` entries.
+#[derive(Debug, Clone)]
+pub struct ConfigHistory {
+    mode: HistoryMode,
+    capacity: usize,         // Most entries kept; 0 keeps none
+    entries: VecDeque<Entry>,
+    newest: Option<Config>,  // The last entry in full, to diff the next one against
+}
+
+impl ConfigHistory {
+    pub fn new(mode: HistoryMode, capacity: usize) -> Self {
+        Self { mode, capacity, entries: VecDeque::new(), newest: None }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn newest(&self) -> Option<&Config> {
+        self.newest.as_ref()
+    }
+
+    // Append `config`, dropping the oldest entry once the history is full.
+    pub fn push(&mut self, config: Config) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.pop_front();
+        }
+        let entry = match (&self.newest, self.mode) {
+            (Some(previous), HistoryMode::Diff) if !self.entries.is_empty() => Entry::Diff(previous.diff(&config)),
+            _ => Entry::Full(config.clone()),
+        };
+        self.entries.push_back(entry);
+        self.newest = Some(config);
+    }
+
+    // Replace the history with `configs`, oldest first, keeping only the latest `capacity`.
+    // Returns how many were kept.
+    pub fn replace(&mut self, configs: Vec<Config>) -> usize {
+        self.entries.clear();
+        self.newest = None;
+        let skip = configs.len().saturating_sub(self.capacity);
+        for config in configs.into_iter().skip(skip) {
+            self.push(config);
+        }
+        self.entries.len()
+    }
+
+    // Every entry as a full config, oldest first.
+    pub fn to_vec(&self) -> Vec<Config> {
+        let mut configs: Vec<Config> = Vec::with_capacity(self.entries.len());
+        for entry in &self.entries {
+            let config = match (entry, configs.last()) {
+                (Entry::Full(config), _) => config.clone(),
+                (Entry::Diff(changes), Some(previous)) => apply(previous, changes),
+                // Can't happen: the front entry is always full
+                (Entry::Diff(_), None) => continue,
+            };
+            configs.push(config);
+        }
+        configs
+    }
+
+    // Drop the oldest entry, rebuilding the next one in full if it's a diff against it.
+    fn pop_front(&mut self) {
+        let Some(Entry::Full(oldest)) = self.entries.pop_front() else {
+            return;
+        };
+        if let Some(next) = self.entries.front_mut() {
+            if let Entry::Diff(changes) = next {
+                *next = Entry::Full(apply(&oldest, changes));
+            }
+        }
+    }
+}
+
+// `base` with `changes` applied.
+fn apply(base: &Config, changes: &Map<String, Value>) -> Config {
+    let Ok(Value::Object(mut fields)) = serde_json::to_value(base) else {
+        return base.clone();
+    };
+    fields.extend(changes.iter().map(|(field, value)| (field.clone(), value.clone())));
+    serde_json::from_value(Value::Object(fields)).unwrap_or_else(|_| base.clone())
+}
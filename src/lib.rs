@@ -0,0 +1,38 @@
+// Reusable core of the subscriber: config model, upstream fetching, and WebSocket fan-out.
+// The `bin` feature builds the standalone server in `main.rs` on top of it; embedders can
+// disable it and mount `config_ws` in their own actix app.
+
+pub mod access;
+pub mod admin;
+pub mod appstate;
+pub mod audit;
+pub mod bars;
+pub mod config;
+pub mod display;
+pub mod error;
+pub mod events;
+pub mod fetch;
+pub mod handlers;
+pub mod health;
+pub mod history;
+pub mod mapping;
+pub mod metrics;
+pub mod profiles;
+pub mod protocol;
+pub mod ratelimit;
+pub mod redis;
+pub mod reload;
+pub mod request_id;
+pub mod settings;
+pub mod signature;
+pub mod source;
+pub mod stats;
+pub mod trace;
+pub mod webhook;
+pub mod websocket;
+
+pub use config::{Config, ConfigBuilder};
+pub use error::ApiError;
+pub use fetch::{fetch_and_update_config, force_fetch_config};
+pub use source::{ConfigError, ConfigSource};
+pub use websocket::{config_ws, GenericWsMessage, WsManager};
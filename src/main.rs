@@ -1,187 +1,333 @@
-use actix::Addr;
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
-use std::sync::{Arc, Mutex};
-use tokio::time::{sleep, Duration};
-use serde::{Deserialize, Serialize};
-use log:: error;
-use actix::Actor;
-
-use reqwest::Error as ReqwestError;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
 
-mod appstate;
-mod websocket;
-use appstate::AppState;
-use websocket::{config_ws, GenericWsMessage, WsManager};
+use actix::Actor;
+use actix_web::http::KeepAlive;
+use actix_web::middleware::{Compress, Logger};
+use actix_web::rt::net::TcpStream;
+use actix_web::{web, App, HttpServer};
+use clap::Parser;
+use log::{error, info, warn};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-struct Config {
-    ball_color: String,    // Color of the balls (e.g., "green", "red", "blue")
-    ball_size: u8,         // Diameter of the balls in pixels
-    ball_speed: u8,        // Speed of the balls' movement (pixels per animation frame)
-    number_of_balls: u8,   // Total number of balls to display
-}
+use subscriber_microservice::access::{IpAccess, IpRules};
+use subscriber_microservice::admin::{
+    control_display, export_state, import_state, last_raw_payload, list_sessions, pause_broadcasts, refresh_config, resume_broadcasts, set_profiles, set_upstream, shutdown,
+    targeted_broadcast,
+};
+use subscriber_microservice::appstate::{write_lock, AppState};
+use subscriber_microservice::audit;
+use subscriber_microservice::display::{self, PageTemplate};
+use subscriber_microservice::fetch::{load_initial_config, poll_config, poll_profiles, self_check};
+use subscriber_microservice::handlers::{config_history, config_stream, get_config, json_error_handler, metrics, ready, status, version};
+use subscriber_microservice::health::HealthCheck;
+use subscriber_microservice::mapping::FieldMapping;
+use subscriber_microservice::metrics::count_config_changes;
+use subscriber_microservice::profiles::Profiles;
+use subscriber_microservice::ratelimit::{RateLimit, RateLimiter};
+use subscriber_microservice::redis::{self, RedisRole};
+use subscriber_microservice::reload::read_env_file;
+use subscriber_microservice::request_id::RequestIds;
+use subscriber_microservice::settings::Settings;
+use subscriber_microservice::Config;
+use subscriber_microservice::source::HttpSource;
+use subscriber_microservice::stats::stats_ws;
+use subscriber_microservice::trace::TraceContext;
+use subscriber_microservice::webhook;
+use subscriber_microservice::websocket::{config_ws, ws_url, CloseAll, WsManager};
 
+const DEFAULT_LOG_FILTER: &str = "info,actix_web=info";
 
-async fn fetch_and_update_config(app_state: web::Data<AppState>, ws_manager: Addr<WsManager>) -> Result<Config, ReqwestError> {
-    let url = "http://localhost:8080/config";
-    let client = reqwest::Client::new();
-    let resp = client.get(url).send().await?;
-    let config: Config = resp.json().await?;
+/// Serves the live ball config over HTTP and WebSocket, polling it from an upstream.
+///
+/// Flags override the matching environment variables; everything else is configured
+/// through the environment only (see the README).
+#[derive(Debug, Clone, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Address to listen on, e.g. `[::]:8081`; comma-separate to listen on several [env: BIND_ADDRESS] [default: 127.0.0.1:8081]
+    #[arg(long, value_name = "ADDR")]
+    bind: Option<String>,
 
-    // Update the shared state
-    {
-        let mut config_lock = app_state.config.lock().unwrap();
-        *config_lock = Some(config.clone()); // Clone config for internal state update
-    }
+    /// Upstream config URL; repeat or comma-separate for failover [env: CONFIG_UPSTREAM_URLS]
+    #[arg(long = "upstream-url", value_name = "URL", value_delimiter = ',')]
+    upstream_urls: Vec<String>,
 
-    // Send the cloned config to the WsManager for broadcasting
-    ws_manager.do_send(GenericWsMessage { config: config.clone() }); // Clone config for messaging
+    /// Seconds between upstream polls [env: POLL_INTERVAL_SECS] [default: 5]
+    #[arg(long, value_name = "SECS")]
+    poll_interval: Option<u64>,
 
-    Ok(config)
+    /// Log filter, e.g. `debug` or `subscriber_microservice=debug` [env: RUST_LOG] [default: info,actix_web=info]
+    #[arg(long, value_name = "FILTER")]
+    log_level: Option<String>,
 }
 
+impl Cli {
+    // Let flags that were given win over the environment.
+    fn apply(&self, settings: &mut Settings) {
+        if let Some(bind) = &self.bind {
+            settings.bind_address = bind.clone();
+        }
+        if !self.upstream_urls.is_empty() {
+            settings.upstream_urls = self.upstream_urls.clone();
+        }
+        if let Some(poll_interval) = self.poll_interval {
+            settings.poll_interval_secs = poll_interval;
+        }
+    }
+}
 
-async fn display_balls(data: web::Data<AppState>) -> impl Responder {
-    let config_lock = data.config.lock().unwrap();
-    let config = match &*config_lock {
-        Some(config) => config.clone(),
-        None => return HttpResponse::InternalServerError().finish(), // Handle missing config
+// The configured fallback config, if any. It must pass the same validation as fetched configs.
+fn load_default_config(settings: &Settings) -> std::io::Result<Option<Config>> {
+    let json = match (&settings.default_config, &settings.default_config_file) {
+        (Some(json), _) => json.clone(),
+        (None, Some(path)) => std::fs::read_to_string(path)
+            .map_err(|e| std::io::Error::other(format!("Failed to read default config {}: {}", path, e)))?,
+        (None, None) => return Ok(None),
     };
-
-    // Initial rendering of balls based on the server-side configuration
-    let balls_html = (0..config.number_of_balls)
-        .map(|_| {
-            let left = rand::random::<u8>() % 100;
-            let top = rand::random::<u8>() % 100;
-            format!(
-                "<div class='ball' style='position: absolute; width: {}px; height: {}px; border-radius: {}px; background-color: {}; left: {}%; top: {}%;'></div>",
-                config.ball_size, config.ball_size, config.ball_size / 2, config.ball_color, left, top
-            )
-        })
-        .collect::<Vec<_>>()
-        .join("\n");
-
-    let html = format!(
-        "<!DOCTYPE html>
-        <html>
-        <head>
-            <title>Balls Display</title>
-            <style>
-                body {{ margin: 0; overflow: hidden; }}
-                .ball {{ position: absolute; border-radius: 50%; }}
-            </style>
-        </head>
-        <body>
-            {balls_html}
-            <script>
-                var conn = new WebSocket('ws://localhost:8081/ws/');
-                conn.onopen = function() {{
-                    console.log('WebSocket connection established');
-                }};
-                conn.onerror = function(error) {{
-                    console.error('WebSocket Error:', error);
-                }};
-                conn.onmessage = function(evt) {{
-                    var config = JSON.parse(evt.data);
-                    console.log('Received config:', config);
-                    // Update ball characteristics based on the new config
-                    document.querySelectorAll('.ball').forEach(div => {{
-                        div.style.backgroundColor = config.ball_color;
-                        div.style.width = config.ball_size + 'px';
-                        div.style.height = config.ball_size + 'px';
-                        div.style.borderRadius = (config.ball_size / 2) + 'px';
-                    }});
-                    // Adjust the number of balls as needed
-                    updateNumberOfBalls(config.number_of_balls, config.ball_size, config.ball_color);
-                    // Update the movement speed based on the new configuration
-                    currentSpeed = config.ball_speed || defaultSpeed;
-                    clearInterval(moveInterval); // Clear the existing interval
-                    moveInterval = setInterval(moveBalls, 1000 / currentSpeed); // Set a new interval with updated speed
-                }};
-                conn.onclose = function() {{
-                    console.log('WebSocket connection closed');
-                }};
-
-                var defaultSpeed = 5; // Default speed for ball movement
-                var currentSpeed = defaultSpeed; // Current speed, initially set to default
-                var moveInterval = setInterval(moveBalls, 1000 / currentSpeed); // Initialize ball movement
-
-                function moveBalls() {{
-                    document.querySelectorAll('.ball').forEach(function(div) {{
-                        var newX = Math.floor(Math.random() * (window.innerWidth - div.offsetWidth));
-                        var newY = Math.floor(Math.random() * (window.innerHeight - div.offsetHeight));
-                        div.style.left = newX + 'px';
-                        div.style.top = newY + 'px';
-                    }});
-                }}
-
-                function updateNumberOfBalls(newNumberOfBalls, ballSize, ballColor) {{
-                    const ballsContainer = document.body;
-                    const existingBalls = document.querySelectorAll('.ball');
-                    const currentNumberOfBalls = existingBalls.length;
-
-                    // Add balls if new number is greater
-                    for (let i = currentNumberOfBalls; i < newNumberOfBalls; i++) {{
-                        const div = document.createElement('div');
-                        div.className = 'ball';
-                        div.style.position = 'absolute';
-                        div.style.width = ballSize + 'px';
-                        div.style.height = ballSize + 'px';
-                        div.style.borderRadius = (ballSize / 2) + 'px';
-                        div.style.backgroundColor = ballColor;
-                        // Set initial position
-                        div.style.left = (Math.random() * window.innerWidth) + 'px';
-                        div.style.top = (Math.random() * window.innerHeight) + 'px';
-                        ballsContainer.appendChild(div);
-                    }}
-
-                    // Remove balls if new number is smaller
-                    for (let i = currentNumberOfBalls - 1; i >= newNumberOfBalls; i--) {{
-                        existingBalls[i].remove();
-                    }}
-                }}
-            </script>
-        </body>
-        </html>",
-    );
-
-    HttpResponse::Ok().content_type("text/html").body(html)
+    let config: Config = serde_json::from_str(&json).map_err(|e| std::io::Error::other(format!("Invalid default config: {}", e)))?;
+    config.validate().map_err(|errors| std::io::Error::other(format!("Invalid default config: {}", errors.join("; "))))?;
+    Ok(Some(config))
 }
 
+// Re-read the settings file and the environment on every SIGHUP, applying the settings that can
+// change live and logging the rest as needing a restart.
+#[cfg(unix)]
+async fn reload_on_sighup(app_state: web::Data<AppState>, cli: Cli) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("Failed to listen for SIGHUP, settings can't be reloaded: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        let vars = match &app_state.settings.settings_file {
+            Some(path) => match read_env_file(path) {
+                Ok(vars) => vars,
+                Err(e) => {
+                    error!("Failed to reload settings file {}: {}", path, e);
+                    continue;
+                }
+            },
+            None => HashMap::new(),
+        };
+        let mut fresh = Settings::from_vars(&vars);
+        cli.apply(&mut fresh);
+        let pending = app_state.live.apply(&app_state.settings, &fresh);
+        if !pending.is_empty() {
+            warn!("These settings changed but only take effect after a restart: {}", pending.join(", "));
+        }
+        info!("Reloaded settings");
+    }
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    std::env::set_var("RUST_LOG", "actix_web=info");
-    env_logger::init();
+    let cli = Cli::parse();
 
-    let app_state = web::Data::new(AppState {
-        config: Arc::new(Mutex::new(None)),
-    });
+    // Respect an operator-provided RUST_LOG, only falling back to our default when it's unset
+    match &cli.log_level {
+        Some(filter) => env_logger::Builder::new().parse_filters(filter).init(),
+        None => env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(DEFAULT_LOG_FILTER)).init(),
+    }
+    // Send panics through the logger too, so they land next to the session's other lines; the
+    // default hook still prints them, with the backtrace when `RUST_BACKTRACE` asks for one
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        error!("{}", info);
+        default_hook(info);
+    }));
+
+    // Read before the settings themselves, which it may set
+    let vars = match std::env::var("SETTINGS_FILE").ok().filter(|path| !path.is_empty()) {
+        Some(path) => read_env_file(&path)
+            .map_err(|e| std::io::Error::other(format!("Failed to read settings file {}: {}", path, e)))?,
+        None => HashMap::new(),
+    };
+    Settings::check_vars(&vars).map_err(std::io::Error::other)?;
+    let mut settings = Settings::from_vars(&vars);
+    cli.apply(&mut settings);
+    let field_mapping = match &settings.field_mapping_file {
+        Some(path) => FieldMapping::load(path)
+            .map_err(|e| std::io::Error::other(format!("Failed to load field mapping {}: {}", path, e)))?,
+        None => FieldMapping::default(),
+    };
+    let profiles = Profiles::new(&settings, field_mapping.clone());
+    profiles
+        .replace(&settings.config_profiles)
+        .map_err(|e| std::io::Error::other(format!("Failed to set up the config profiles: {}", e)))?;
+    let source = HttpSource::new(&settings, field_mapping).map_err(|e| std::io::Error::other(e.to_string()))?;
+    let default_config = load_default_config(&settings)?;
+    let mut app_state = AppState::new(settings, Box::new(source)).with_profiles(profiles);
+    if let Some(url) = app_state.settings.upstream_health_url.clone() {
+        let timeout = Duration::from_secs(app_state.settings.upstream_timeout_secs);
+        let health_check = HealthCheck::new(url, timeout, app_state.settings.upstream_failure_threshold)
+            .map_err(|e| std::io::Error::other(format!("Failed to set up the upstream health check: {}", e)))?;
+        app_state = app_state.with_health_check(health_check);
+    }
+    if let Some(path) = app_state.settings.display_template_file.clone() {
+        let template = PageTemplate::load(&path)
+            .map_err(|e| std::io::Error::other(format!("Failed to load page template {}: {}", path, e)))?;
+        app_state = app_state.with_page_template(template);
+    }
+    let display_rules = IpRules::new(
+        &app_state.settings.display_allowed_ips,
+        &app_state.settings.display_denied_ips,
+        app_state.settings.trust_forwarded_for,
+    )
+    .map_err(|e| std::io::Error::other(format!("Failed to read the display address ranges: {}", e)))?;
+    let app_state = web::Data::new(app_state);
+    if let Some(config) = default_config {
+        info!("Serving the default config until the first fetch");
+        *write_lock(&app_state.config) = Some(config);
+    }
 
-    // Correctly start the WsManager actor and get its address
-    let ws_manager_addr = WsManager::new().start();
+    // Start the WsManager actor, keeping a handle to its broadcast channel for new sessions
+    let ws_manager = WsManager::with_capacity(app_state.settings.ws_send_queue_capacity).with_replay(app_state.settings.ws_replay_size)
+        .with_history_mode(app_state.settings.ws_history_mode)
+        .with_metrics(app_state.metrics.clone())
+        .with_min_interval(Duration::from_millis(app_state.settings.ws_min_broadcast_interval_ms))
+        .with_field_throttle(app_state.settings.ws_throttled_fields.clone(), Duration::from_millis(app_state.settings.ws_field_throttle_ms))
+        .with_log_limit(app_state.settings.log_max_config_chars)
+        .with_events(app_state.events.subscribe());
+    let broadcaster = ws_manager.broadcaster();
+    let ws_manager_addr = ws_manager.start();
 
-    let app_state_cloned = app_state.clone();
-    let ws_manager_cloned = ws_manager_addr.clone();
-    tokio::spawn(async move {
-        loop {
-            // Assuming fetch_and_update_config is defined and correctly accepts an Addr<WsManager>
-            if let Err(e) = fetch_and_update_config(app_state_cloned.clone(), ws_manager_cloned.clone()).await {
-                error!("Failed to fetch config: {}", e);
-            }
-            sleep(Duration::from_secs(5)).await;
+    // The rest of the config change subscribers
+    tokio::spawn(count_config_changes(app_state.metrics.clone(), app_state.events.subscribe()));
+    audit::start(app_state.settings.audit_log_file.as_deref(), app_state.events.subscribe())
+        .map_err(|e| std::io::Error::other(format!("Failed to open the audit log file: {}", e)))?;
+    if let Some(url) = app_state.settings.webhook_url.clone() {
+        let timeout = Duration::from_secs(app_state.settings.upstream_timeout_secs);
+        webhook::start(url, timeout, app_state.settings.webhook_max_attempts, app_state.events.subscribe())
+            .map_err(|e| std::io::Error::other(format!("Failed to set up the config webhook: {}", e)))?;
+    }
+    // With Redis, one instance polls and publishes, and the others follow it instead of polling
+    let redis_subscriber = match app_state.settings.redis_url.clone() {
+        Some(url) if app_state.settings.redis_role == RedisRole::Publisher => {
+            redis::start_publisher(url, app_state.settings.redis_key.clone(), app_state.events.subscribe());
+            false
         }
-    });
+        Some(_) => true,
+        None => false,
+    };
 
-    HttpServer::new(move || {
+    if app_state.settings.upstream_self_check {
+        // Logged by the check itself; startup carries on either way
+        let _ = self_check(&app_state).await;
+    }
+    if app_state.settings.block_until_ready && redis_subscriber {
+        warn!("BLOCK_UNTIL_READY is ignored by Redis subscribers; the config arrives from Redis once connected");
+    } else if app_state.settings.block_until_ready {
+        load_initial_config(app_state.clone()).await?;
+    }
+
+    if redis_subscriber {
+        tokio::spawn(redis::follow(app_state.clone()));
+    } else {
+        tokio::spawn(poll_config(app_state.clone()));
+    }
+    // Profiles aren't shared through Redis, so every instance polls them itself
+    tokio::spawn(poll_profiles(app_state.clone()));
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(app_state.clone(), cli.clone()));
+
+    let bind_addresses = app_state.settings.bind_addresses();
+    let keep_alive = match app_state.settings.http_keep_alive_secs {
+        0 => KeepAlive::Disabled,
+        secs => KeepAlive::Timeout(Duration::from_secs(secs)),
+    };
+    let tcp_nodelay = app_state.settings.tcp_nodelay;
+    let shutdown_requested = web::Data::new(Notify::new());
+    let drain_ws_manager = ws_manager_addr.clone();
+    let drain_requested = shutdown_requested.clone();
+    // One limiter shared by every worker, so a client's budget doesn't multiply with the worker count
+    let rate_limit = RateLimit::new(Arc::new(RateLimiter::new(
+        app_state.settings.rate_limit_per_sec,
+        app_state.settings.rate_limit_burst,
+    )));
+    let display_access = IpAccess::new(Arc::new(display_rules));
+    // `/ws/` only checks addresses if asked to; empty rules let everyone through
+    let ws_access = if app_state.settings.display_ip_filter_ws { display_access.clone() } else { IpAccess::new(Arc::default()) };
+    let mut server = HttpServer::new(move || {
         App::new()
+            // Tag every request with an `X-Request-Id`, echoed in the response and the access log
+            .wrap(RequestIds)
+            // Keep an incoming `traceparent`, so upstream fetches the request triggers continue its trace
+            .wrap(TraceContext)
+            // Compress responses per `Accept-Encoding`; the bodiless WebSocket upgrade passes through untouched
+            .wrap(Compress::default())
+            // Access log; only the request line and response metadata are logged, never WebSocket frames
+            .wrap(Logger::new(&app_state.settings.access_log_format))
             .app_data(app_state.clone())
-            // Ensure you use `.app_data` for the ws_manager_addr if using Actix Web 3.x or newer
-            .app_data(web::Data::new(ws_manager_addr.clone())) // Correctly pass the WsManager address to the app
-            .route("/ws/", web::get().to(config_ws))
-            .route("/", web::get().to(display_balls))
+            // Applies to every endpoint that accepts a JSON config body
+            .app_data(
+                web::JsonConfig::default()
+                    .limit(app_state.settings.json_body_limit)
+                    .error_handler(json_error_handler),
+            )
+            .app_data(web::Data::new(ws_manager_addr.clone()))
+            .app_data(web::Data::new(broadcaster.clone()))
+            .app_data(shutdown_requested.clone())
+            .service(web::resource("/ws/").wrap(ws_access.clone()).route(web::get().to(config_ws)))
+            .route("/ws/stats", web::get().to(stats_ws))
+            .service(web::resource("/config").wrap(rate_limit.clone()).route(web::get().to(get_config)))
+            .service(web::resource("/config/history").wrap(rate_limit.clone()).route(web::get().to(config_history)))
+            .service(web::resource("/config/stream").wrap(rate_limit.clone()).route(web::get().to(config_stream)))
+            .route("/version", web::get().to(version))
+            .route("/ready", web::get().to(ready))
+            .route("/status", web::get().to(status))
+            .route("/metrics", web::get().to(metrics))
+            .service(web::resource("/debug/last-raw").wrap(rate_limit.clone()).route(web::get().to(last_raw_payload)))
+            .service(
+                web::scope("/admin")
+                    .wrap(rate_limit.clone())
+                    .route("/refresh", web::post().to(refresh_config))
+                    .route("/pause", web::post().to(pause_broadcasts))
+                    .route("/resume", web::post().to(resume_broadcasts))
+                    .route("/broadcast", web::post().to(targeted_broadcast))
+                    .route("/upstream", web::post().to(set_upstream))
+                    .route("/profiles", web::put().to(set_profiles))
+                    .route("/display", web::post().to(control_display))
+                    .route("/sessions", web::get().to(list_sessions))
+                    .route("/state/export", web::get().to(export_state))
+                    .route("/state/import", web::post().to(import_state))
+                    .route("/shutdown", web::post().to(shutdown)),
+            )
+            .configure(|cfg| display::configure(cfg, &app_state, &display_access))
     })
-    .bind("127.0.0.1:8081")?
-    .run()
-    .await
+    .keep_alive(keep_alive)
+    .on_connect(move |conn, _| {
+        if let Some(stream) = conn.downcast_ref::<TcpStream>() {
+            if let Err(e) = stream.set_nodelay(tcp_nodelay) {
+                warn!("Failed to set TCP_NODELAY: {}", e);
+            }
+        }
+    });
+    // One listener per address, e.g. `0.0.0.0:8081,[::]:8081` for both families on hosts where `[::]` is IPv6-only
+    for address in &bind_addresses {
+        server = server.bind(address)?;
+    }
+    for addr in server.addrs() {
+        info!("Listening on {}, WebSocket endpoint {}", addr, ws_url(addr));
+    }
+    let server = server.run();
+
+    // `POST /admin/shutdown`: say goodbye to WebSocket clients, then stop accepting connections
+    // and let in-flight requests finish
+    let handle = server.handle();
+    tokio::spawn(async move {
+        drain_requested.notified().await;
+        match drain_ws_manager.send(CloseAll).await {
+            Ok(closed) => info!("Closed {} WebSocket sessions", closed),
+            Err(e) => error!("Failed to close WebSocket sessions: {}", e),
+        }
+        handle.stop(true).await;
+    });
+
+    server.await
 }
@@ -0,0 +1,38 @@
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::{fs, io};
+
+// Renames top-level fields of the upstream payload before it is deserialized into `Config`,
+// for upstreams whose field names differ from ours (e.g. `color` instead of `ball_color`).
+#[derive(Debug, Clone, Default)]
+pub struct FieldMapping {
+    renames: HashMap<String, String>, // Upstream field name -> `Config` field name
+}
+
+impl FieldMapping {
+    // Load a mapping file holding a JSON object such as `{"color": "ball_color", "size": "ball_size"}`.
+    pub fn load(path: &str) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let renames = serde_json::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Self { renames })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.renames.is_empty()
+    }
+
+    // Rename mapped fields in place, leaving unmapped fields and non-object payloads untouched.
+    pub fn apply(&self, payload: Value) -> Value {
+        let Value::Object(fields) = payload else {
+            return payload;
+        };
+        let remapped: Map<String, Value> = fields
+            .into_iter()
+            .map(|(name, value)| match self.renames.get(&name) {
+                Some(target) => (target.clone(), value),
+                None => (name, value),
+            })
+            .collect();
+        Value::Object(remapped)
+    }
+}
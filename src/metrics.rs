@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::events::ConfigChanged;
+
+// Upper bounds, in seconds, of the WebSocket session duration buckets.
+pub const SESSION_DURATION_BUCKETS: &[f64] = &[1.0, 5.0, 30.0, 60.0, 300.0, 1800.0, 3600.0];
+// Upper bounds, in seconds, of the ping round-trip time buckets.
+pub const PONG_RTT_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+// Names of the metrics the service reports.
+pub const UPSTREAM_HEALTHY: &str = "upstream_healthy";
+pub const UPSTREAM_CONSECUTIVE_FAILURES: &str = "upstream_consecutive_failures";
+pub const UPSTREAM_CLOCK_SKEW_SECONDS: &str = "upstream_clock_skew_seconds";
+pub const WS_SESSION_DURATION_SECONDS: &str = "ws_session_duration_seconds";
+pub const WS_PONG_RTT_SECONDS: &str = "ws_pong_rtt_seconds";
+pub const WS_DELIVERY_FAILURES_TOTAL: &str = "ws_delivery_failures_total";
+pub const WS_UPGRADES_REJECTED_TOTAL: &str = "ws_upgrades_rejected_total";
+pub const CONFIG_CHANGES_TOTAL: &str = "config_changes_total";
+pub const CONFIG_LOCK_CONTENTION_TOTAL: &str = "config_lock_contention_total";
+
+// Help text for the metrics above, as shown by `/metrics`.
+const HELP: &[(&str, &str)] = &[
+    (UPSTREAM_HEALTHY, "Whether the config upstream is considered healthy."),
+    (UPSTREAM_CONSECUTIVE_FAILURES, "Config fetches, or health checks if configured, failed in a row."),
+    (UPSTREAM_CLOCK_SKEW_SECONDS, "Seconds the upstream's Date header was ahead of the local clock at its last answer."),
+    (WS_SESSION_DURATION_SECONDS, "How long WebSocket sessions stayed connected."),
+    (WS_PONG_RTT_SECONDS, "Time from a heartbeat ping to the WebSocket client's pong."),
+    (WS_DELIVERY_FAILURES_TOTAL, "Updates that didn't reach a WebSocket session, by reason."),
+    (WS_UPGRADES_REJECTED_TOTAL, "WebSocket upgrades turned away because too many were already in progress."),
+    (CONFIG_CHANGES_TOTAL, "Config changes committed since startup."),
+    (CONFIG_LOCK_CONTENTION_TOTAL, "Requests that gave up waiting for the config lock, by path."),
+];
+
+// Where instrumentation goes. The fetch loop, `WsManager`, and the sessions only ever talk to
+// this, so embedders can plug in their own exporter. Names follow the Prometheus conventions.
+pub trait Metrics: Send + Sync {
+    // Add one to a counter, e.g. `ws_delivery_failures_total` with `[("reason", "full")]`.
+    fn increment_counter(&self, name: &'static str, labels: &[(&'static str, &'static str)]);
+
+    fn set_gauge(&self, name: &'static str, value: f64);
+
+    // Record a duration in a histogram.
+    fn record_duration(&self, name: &'static str, duration: Duration);
+
+    // Everything recorded so far in the Prometheus text format, for `/metrics`. `None` for
+    // backends that export some other way.
+    fn render(&self) -> Option<String> {
+        None
+    }
+}
+
+// Drops everything, for deployments that don't collect metrics.
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {
+    fn increment_counter(&self, _: &'static str, _: &[(&'static str, &'static str)]) {}
+
+    fn set_gauge(&self, _: &'static str, _: f64) {}
+
+    fn record_duration(&self, _: &'static str, _: Duration) {}
+}
+
+// Keeps everything in memory and renders it in the Prometheus text format on `/metrics`.
+#[derive(Default)]
+pub struct PrometheusMetrics {
+    counters: Mutex<BTreeMap<&'static str, BTreeMap<String, u64>>>, // Values by name, then by rendered label set
+    gauges: Mutex<BTreeMap<&'static str, f64>>,
+    histograms: Mutex<BTreeMap<&'static str, Histogram>>, // Bucketed per `buckets`
+}
+
+impl Metrics for PrometheusMetrics {
+    fn increment_counter(&self, name: &'static str, labels: &[(&'static str, &'static str)]) {
+        let labels = labels.iter().map(|(label, value)| format!("{}=\"{}\"", label, value)).collect::<Vec<_>>().join(",");
+        *self.counters.lock().unwrap().entry(name).or_default().entry(labels).or_default() += 1;
+    }
+
+    fn set_gauge(&self, name: &'static str, value: f64) {
+        self.gauges.lock().unwrap().insert(name, value);
+    }
+
+    fn record_duration(&self, name: &'static str, duration: Duration) {
+        self.histograms.lock().unwrap().entry(name).or_insert_with(|| Histogram::new(buckets(name))).observe(duration);
+    }
+
+    fn render(&self) -> Option<String> {
+        let mut out = String::new();
+        for (name, value) in self.gauges.lock().unwrap().iter() {
+            write_header(name, "gauge", &mut out);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        for (name, histogram) in self.histograms.lock().unwrap().iter() {
+            histogram.render(name, help(name), &mut out);
+        }
+        for (name, series) in self.counters.lock().unwrap().iter() {
+            write_header(name, "counter", &mut out);
+            for (labels, value) in series {
+                if labels.is_empty() {
+                    let _ = writeln!(out, "{} {}", name, value);
+                } else {
+                    let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+                }
+            }
+        }
+        Some(out)
+    }
+}
+
+// Count the config changes announced on `events` until the sender goes away.
+pub async fn count_config_changes(metrics: Arc<dyn Metrics>, mut events: broadcast::Receiver<ConfigChanged>) {
+    loop {
+        match events.recv().await {
+            Ok(_) => metrics.increment_counter(CONFIG_CHANGES_TOTAL, &[]),
+            Err(RecvError::Lagged(skipped)) => {
+                for _ in 0..skipped {
+                    metrics.increment_counter(CONFIG_CHANGES_TOTAL, &[]);
+                }
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+// The bucket bounds for the histogram `name`.
+fn buckets(name: &str) -> &'static [f64] {
+    match name {
+        WS_PONG_RTT_SECONDS => PONG_RTT_BUCKETS,
+        _ => SESSION_DURATION_BUCKETS,
+    }
+}
+
+fn help(name: &str) -> &'static str {
+    HELP.iter().find(|(known, _)| *known == name).map_or("", |(_, help)| help)
+}
+
+fn write_header(name: &str, kind: &str, out: &mut String) {
+    let _ = writeln!(out, "# HELP {} {}", name, help(name));
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// The metrics backends that can be picked at startup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricsBackend {
+    Prometheus, // Served on `/metrics`
+    None,       // Nothing is collected; `/metrics` answers 404
+}
+
+impl MetricsBackend {
+    pub fn build(self) -> Arc<dyn Metrics> {
+        match self {
+            MetricsBackend::Prometheus => Arc::new(PrometheusMetrics::default()),
+            MetricsBackend::None => Arc::new(NoopMetrics),
+        }
+    }
+}
+
+impl FromStr for MetricsBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prometheus" => Ok(MetricsBackend::Prometheus),
+            "none" => Ok(MetricsBackend::None),
+            other => Err(format!("unknown metrics backend {:?}", other)),
+        }
+    }
+}
+
+// A fixed-bucket histogram of durations, rendered in the Prometheus text format.
+pub struct Histogram {
+    bounds: &'static [f64],  // Bucket upper bounds in seconds; `+Inf` is implied
+    buckets: Vec<AtomicU64>, // Observations per bucket, not cumulative; the last one is `+Inf`
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = self.bounds.iter().position(|&bound| seconds <= bound).unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    // Append the histogram as `name_bucket`, `name_sum`, and `name_count` series.
+    pub fn render(&self, name: &str, help: &str, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            match self.bounds.get(bucket) {
+                Some(bound) => {
+                    let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+                }
+                None => {
+                    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+                }
+            }
+        }
+        let _ = writeln!(out, "{}_sum {}", name, self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6);
+        let _ = writeln!(out, "{}_count {}", name, self.count());
+    }
+}
+
+// Why an update didn't reach a WebSocket session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryFailure {
+    Closed, // The session's actor had already stopped
+    Full,   // The session's mailbox was full
+    Lagged, // The session fell behind the broadcast channel and missed updates
+}
+
+impl DeliveryFailure {
+    pub fn label(self) -> &'static str {
+        match self {
+            DeliveryFailure::Closed => "closed",
+            DeliveryFailure::Full => "full",
+            DeliveryFailure::Lagged => "lagged",
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+use log::{debug, info, warn};
+use tokio::sync::broadcast;
+
+use crate::appstate::{read_lock, write_lock};
+use crate::mapping::FieldMapping;
+use crate::settings::Settings;
+use crate::source::{ConfigError, ConfigSource, HttpSource, UpstreamMode};
+use crate::websocket::{Broadcast, ConfigBroadcaster};
+use crate::Config;
+
+// A named config polled from an upstream of its own, followed by the sessions that connect with
+// `/ws/?profile=<name>`. It's kept apart from the shared config: there's no replay history,
+// patching, or throttling, and its changes aren't announced on `AppState::events`.
+pub struct Profile {
+    pub config: Arc<RwLock<Option<Config>>>, // What `AppState::config` is to other sessions
+    pub broadcaster: ConfigBroadcaster,      // Each new config, sent whole
+    source: HttpSource,
+}
+
+impl Profile {
+    // Make `config` the profile's, sending it to the profile's sessions if it changed.
+    fn commit(&self, config: Config) {
+        let previous = write_lock(&self.config).replace(config.clone());
+        if previous.as_ref() == Some(&config) {
+            return;
+        }
+        let Some(update) = Broadcast::full(config) else {
+            return;
+        };
+        if self.broadcaster.send(Arc::new(update)).is_err() {
+            debug!("No sessions follow this profile, broadcast dropped");
+        }
+    }
+}
+
+// The profiles being followed, by name. `replace` swaps the whole set at once, so a poll sees
+// either the old set or the new one.
+pub struct Profiles {
+    profiles: RwLock<BTreeMap<String, Arc<Profile>>>,
+    settings: Settings,          // Profile sources are built from these, with the profile's URL as the only upstream
+    field_mapping: FieldMapping, // Renames applied to profile payloads, as to the shared upstream's
+}
+
+impl Profiles {
+    // No profiles yet; `replace` adds them.
+    pub fn new(settings: &Settings, field_mapping: FieldMapping) -> Self {
+        Self {
+            profiles: RwLock::new(BTreeMap::new()),
+            settings: settings.clone(),
+            field_mapping,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Profile>> {
+        read_lock(&self.profiles).get(name).cloned()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        read_lock(&self.profiles).keys().cloned().collect()
+    }
+
+    // Follow `urls`, profile name to upstream URL, instead of the current set. Profiles that stay
+    // keep their config and sessions, switching upstream if theirs changed. Returns the ones
+    // removed, for the caller to close their sessions before dropping them: once the last handle
+    // goes, their sessions stop without a close frame.
+    pub fn replace(&self, urls: &BTreeMap<String, String>) -> Result<BTreeMap<String, Arc<Profile>>, ConfigError> {
+        let mut current = write_lock(&self.profiles);
+        let mut next = BTreeMap::new();
+        for (name, url) in urls {
+            let profile = match current.get(name) {
+                Some(profile) => profile.clone(),
+                None => Arc::new(self.build(url)?),
+            };
+            next.insert(name.clone(), profile);
+        }
+        // Only once every new profile is built, so a failure leaves the set as it was
+        for (name, url) in urls {
+            if let Some(profile) = current.get(name) {
+                profile.source.set_upstream(url.clone());
+            }
+        }
+        let mut removed = std::mem::replace(&mut *current, next);
+        removed.retain(|name, _| !current.contains_key(name));
+        info!("Following profiles {:?}", current.keys().collect::<Vec<_>>());
+        Ok(removed)
+    }
+
+    fn build(&self, url: &str) -> Result<Profile, ConfigError> {
+        // Polled alongside the shared upstream, however that one is followed
+        let settings = Settings { upstream_urls: vec![url.to_string()], upstream_mode: UpstreamMode::Poll, ..self.settings.clone() };
+        Ok(Profile {
+            config: Arc::new(RwLock::new(None)),
+            broadcaster: broadcast::channel(self.settings.ws_send_queue_capacity.max(1)).0,
+            source: HttpSource::new(&settings, self.field_mapping.clone())?,
+        })
+    }
+
+    // Fetch each profile's config once, committing the ones that changed. A failed fetch is
+    // logged and leaves that profile's config as it was.
+    pub async fn poll(&self) {
+        let profiles: Vec<(String, Arc<Profile>)> = read_lock(&self.profiles).iter().map(|(name, profile)| (name.clone(), profile.clone())).collect();
+        for (name, profile) in profiles {
+            let current = read_lock(&profile.config).clone();
+            let fetched = profile.source.fetch(current.as_ref()).await.and_then(|fetched| {
+                fetched.map(|config| config.validate().map(|()| config).map_err(ConfigError::Invalid)).transpose()
+            });
+            match fetched {
+                Ok(Some(config)) => profile.commit(config),
+                Ok(None) => (),
+                Err(e) => warn!("Failed to fetch config for profile {}: {}", name, e),
+            }
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::events::ChangeSource;
+use crate::websocket::ControlAction;
+use crate::Config;
+
+// Commands a client sends over the WebSocket, e.g. `{"type":"get_config"}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    GetConfig,                         // Resend the current config
+    Resync,                            // Drop any queued update and resend the current config
+    PeerCount,                         // Ask how many clients are connected
+    WatchPeerCount,                    // Get `peer_count` now and again whenever a client connects or disconnects
+    Subscribe { fields: Vec<String> }, // Only forward updates touching these fields; empty clears the filter
+    GetHistory {
+        #[serde(default)]
+        limit: Option<usize>,          // How many of the latest broadcasts to send; all that are kept when unset
+    },
+    Hello(HelloRequest),               // Agree on capabilities; only valid as the first message
+}
+
+// Payload of a client's `hello`, e.g. `{"type":"hello","version":1,"formats":["json"]}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HelloRequest {
+    pub version: u32,
+    #[serde(default)]
+    pub formats: Vec<String>, // Formats the client accepts, in order of preference; empty accepts the default
+}
+
+// Older spelling of `subscribe`, e.g. `{"subscribe":["ball_color"]}`.
+#[derive(Debug, Deserialize)]
+struct LegacySubscribe {
+    subscribe: Vec<String>,
+}
+
+impl ClientMessage {
+    // Parse a text frame. Besides the tagged form, the bare `get_config` and `peer_count` strings
+    // and `{"subscribe":[...]}` that clients sent before messages were typed are still understood.
+    pub fn parse(text: &str) -> Option<ClientMessage> {
+        match text {
+            "get_config" => return Some(ClientMessage::GetConfig),
+            "peer_count" => return Some(ClientMessage::PeerCount),
+            _ => (),
+        }
+        if let Ok(message) = serde_json::from_str(text) {
+            return Some(message);
+        }
+        serde_json::from_str::<LegacySubscribe>(text)
+            .ok()
+            .map(|legacy| ClientMessage::Subscribe { fields: legacy.subscribe })
+    }
+}
+
+// Messages the server sends over the WebSocket.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    // Only the fields that changed since the last broadcast, and what changed them if known
+    Patch {
+        changes: Map<String, Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        source: Option<ChangeSource>,
+    },
+    Replay { history: Vec<Config> },       // Recent broadcasts, oldest first, sent on connect
+    History { history: Vec<Config> },      // Recent broadcasts, newest first, in answer to `get_history`
+    Control { action: ControlAction },     // Freeze or restart the display without changing the config
+    PeerCount { count: usize },
+    Error { message: String },
+    Echo { received: String },             // A client's message sent back as received, with `WS_ECHO_COMMANDS`
+    #[serde(rename = "hello_ack")]
+    Welcome { version: u32, format: String, server: String }, // Answer to a compatible hello
+    // The full config, sent bare without a `type` as it always has been. Must stay last: untagged
+    // variants are only tried once no tag matches.
+    #[serde(untagged)]
+    Config(Config),
+}
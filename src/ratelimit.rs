@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::future::{ready, Future, Ready};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use log::debug;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::ApiError;
+
+// Past this many tracked clients, buckets that have refilled completely are forgotten.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+// Token buckets per client IP: each client may send `burst` requests at once, refilled at
+// `per_sec` requests per second. A rate of 0 lets everything through.
+pub struct RateLimiter {
+    per_sec: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_sec: u32, burst: u32) -> Self {
+        Self {
+            per_sec: f64::from(per_sec),
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Take a token for `ip`, or fail with how many seconds until the next one is available.
+    pub fn check(&self, ip: IpAddr) -> Result<(), ApiError> {
+        if self.per_sec == 0.0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            let (per_sec, burst) = (self.per_sec, self.burst);
+            buckets.retain(|_, bucket| bucket.tokens + now.duration_since(bucket.refilled_at).as_secs_f64() * per_sec < burst);
+        }
+        let bucket = buckets.entry(ip).or_insert(Bucket { tokens: self.burst, refilled_at: now });
+        let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * self.per_sec;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let retry_after = ((1.0 - bucket.tokens) / self.per_sec).ceil().max(1.0) as u64;
+        Err(ApiError::RateLimited(retry_after))
+    }
+}
+
+// Middleware applying a shared `RateLimiter` to the routes it wraps, keyed by the peer address.
+// Clients over the limit get `429 Too Many Requests` without reaching the handler.
+#[derive(Clone)]
+pub struct RateLimit(Arc<RateLimiter>);
+
+impl RateLimit {
+    pub fn new(limiter: Arc<RateLimiter>) -> Self {
+        Self(limiter)
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware { service: Rc::new(service), limiter: self.0.clone() }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // Requests without a peer address (e.g. over a Unix socket) aren't limited
+        if let Some(ip) = req.peer_addr().map(|addr| addr.ip()) {
+            if let Err(e) = self.limiter.check(ip) {
+                debug!("Rate limited {} on {}", ip, req.path());
+                // Answered here rather than returned as an error, so outer middleware still sees a response
+                return Box::pin(ready(Ok(req.error_response(e).map_into_right_body())));
+            }
+        }
+        let service = self.service.clone();
+        Box::pin(async move { service.call(req).await.map(ServiceResponse::map_into_left_body) })
+    }
+}
+
+// Bounds how many WebSocket upgrades are in progress at once, so a reconnect storm is let in a
+// slice at a time. Past `max_concurrent`, up to `queue_size` more wait up to `queue_timeout` for a
+// slot; the rest are turned away. A `max_concurrent` of 0 lets everything through.
+pub struct UpgradeLimiter {
+    slots: Option<Arc<Semaphore>>,
+    queue_size: usize,
+    queue_timeout: Duration, // Zero waits as long as it takes
+    queued: AtomicUsize,     // Upgrades waiting for a slot right now
+}
+
+impl UpgradeLimiter {
+    pub fn new(max_concurrent: usize, queue_size: usize, queue_timeout: Duration) -> Self {
+        Self {
+            slots: (max_concurrent > 0).then(|| Arc::new(Semaphore::new(max_concurrent))),
+            queue_size,
+            queue_timeout,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    // Take a slot for an upgrade, waiting in the queue if none is free. The upgrade counts as in
+    // progress until the permit is dropped; `None` means upgrades aren't limited.
+    pub async fn acquire(&self) -> Result<Option<OwnedSemaphorePermit>, ApiError> {
+        let Some(slots) = &self.slots else {
+            return Ok(None);
+        };
+        if let Ok(permit) = slots.clone().try_acquire_owned() {
+            return Ok(Some(permit));
+        }
+        if self.queued.fetch_add(1, Ordering::Relaxed) >= self.queue_size {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            return Err(ApiError::Overloaded("Too many WebSocket connections are starting; retry shortly.".to_string()));
+        }
+        let acquire = slots.clone().acquire_owned();
+        let permit = if self.queue_timeout.is_zero() {
+            Ok(acquire.await)
+        } else {
+            tokio::time::timeout(self.queue_timeout, acquire).await
+        };
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        match permit {
+            // The semaphore is never closed
+            Ok(permit) => Ok(permit.ok()),
+            Err(_) => Err(ApiError::Overloaded(format!(
+                "No WebSocket upgrade slot came free within {:?}; retry shortly.",
+                self.queue_timeout
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    use super::*;
+
+    const BURST: u32 = 3;
+
+    #[actix_web::test]
+    async fn request_past_the_burst_gets_429_with_retry_after() {
+        let limit = RateLimit::new(Arc::new(RateLimiter::new(1, BURST)));
+        let app = init_service(App::new().service(web::scope("/api").wrap(limit).route("/", web::get().to(HttpResponse::Ok)))).await;
+        let request = || TestRequest::get().uri("/api/").peer_addr("198.51.100.1:4000".parse().unwrap()).to_request();
+
+        for _ in 0..BURST {
+            assert_eq!(call_service(&app, request()).await.status(), StatusCode::OK);
+        }
+        let res = call_service(&app, request()).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "1");
+    }
+}
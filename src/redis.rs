@@ -0,0 +1,288 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+
+use actix_web::web;
+use log::{debug, info, warn};
+use reqwest::Url;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::{self, error::RecvError, error::TryRecvError};
+use tokio::time::{sleep, timeout, Duration};
+
+use crate::appstate::{read_lock, AppState};
+use crate::events::{ChangeSource, ConfigChanged};
+use crate::fetch::{commit_config, keep_locked_fields};
+use crate::Config;
+
+const DEFAULT_PORT: u16 = 6379;
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);      // Longest a connect or a command may take
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500); // First wait after Redis fails, doubled on every failure in a row
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);     // Upper bound for the retry delay
+
+// What an instance does with the shared config in Redis.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RedisRole {
+    #[default]
+    Publisher,  // Poll the upstream as usual, and write every committed config to Redis
+    Subscriber, // Don't poll; take every config from Redis instead
+}
+
+impl FromStr for RedisRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "publisher" => Ok(RedisRole::Publisher),
+            "subscriber" => Ok(RedisRole::Subscriber),
+            other => Err(format!("unknown Redis role {:?}; expected publisher or subscriber", other)),
+        }
+    }
+}
+
+// A RESP reply. Only what SET, GET, PUBLISH, and SUBSCRIBE answer with is needed, and the
+// contents of status and integer replies never are.
+#[derive(Debug)]
+enum Reply {
+    Status,
+    Error(String),
+    Integer,
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+// A single connection to Redis, speaking just enough of the protocol to share configs.
+struct Connection {
+    stream: BufReader<TcpStream>,
+}
+
+impl Connection {
+    // Connect to `redis://[[user]:password@]host[:port][/db]`, authenticating and selecting the
+    // database if the URL says to.
+    async fn open(url: &str) -> io::Result<Self> {
+        let url = Url::parse(url).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid Redis URL: {}", e)))?;
+        let host = url.host_str().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Redis URL has no host"))?;
+        let port = url.port().unwrap_or(DEFAULT_PORT);
+        let stream = timeout(COMMAND_TIMEOUT, TcpStream::connect((host, port)))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out connecting to Redis"))??;
+        let mut connection = Self { stream: BufReader::new(stream) };
+
+        if let Some(password) = url.password() {
+            match url.username() {
+                "" => connection.command(&[b"AUTH", password.as_bytes()]).await?,
+                user => connection.command(&[b"AUTH", user.as_bytes(), password.as_bytes()]).await?,
+            };
+        }
+        if let Some(db) = url.path().trim_start_matches('/').parse::<u32>().ok().filter(|db| *db != 0) {
+            connection.command(&[b"SELECT", db.to_string().as_bytes()]).await?;
+        }
+        Ok(connection)
+    }
+
+    // Send a command and wait for its reply, turning Redis errors into `io::Error`s.
+    async fn command(&mut self, args: &[&[u8]]) -> io::Result<Reply> {
+        let exchange = async {
+            self.send(args).await?;
+            self.read_reply().await
+        };
+        match timeout(COMMAND_TIMEOUT, exchange).await {
+            Ok(Ok(Reply::Error(message))) => Err(io::Error::other(format!("Redis error: {}", message))),
+            Ok(reply) => reply,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for Redis")),
+        }
+    }
+
+    async fn send(&mut self, args: &[&[u8]]) -> io::Result<()> {
+        let mut frame = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            frame.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            frame.extend_from_slice(arg);
+            frame.extend_from_slice(b"\r\n");
+        }
+        self.stream.get_mut().write_all(&frame).await
+    }
+
+    // Boxed, since arrays hold replies of their own.
+    fn read_reply(&mut self) -> Pin<Box<dyn Future<Output = io::Result<Reply>> + Send + '_>> {
+        Box::pin(async move {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Redis closed the connection"));
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            let malformed = || io::Error::new(io::ErrorKind::InvalidData, format!("malformed Redis reply {:?}", line));
+            let (kind, rest) = line.split_at_checked(1).ok_or_else(malformed)?;
+            match kind {
+                "+" => Ok(Reply::Status),
+                "-" => Ok(Reply::Error(rest.to_string())),
+                ":" => rest.parse::<i64>().map(|_| Reply::Integer).map_err(|_| malformed()),
+                "$" => match rest.parse::<i64>().map_err(|_| malformed())? {
+                    len if len < 0 => Ok(Reply::Bulk(None)),
+                    len => {
+                        // The payload, then its CRLF
+                        let mut data = vec![0; len as usize + 2];
+                        self.stream.read_exact(&mut data).await?;
+                        data.truncate(len as usize);
+                        Ok(Reply::Bulk(Some(data)))
+                    }
+                },
+                "*" => match rest.parse::<i64>().map_err(|_| malformed())? {
+                    len if len < 0 => Ok(Reply::Array(None)),
+                    len => {
+                        let mut items = Vec::with_capacity(len as usize);
+                        for _ in 0..len {
+                            items.push(self.read_reply().await?);
+                        }
+                        Ok(Reply::Array(Some(items)))
+                    }
+                },
+                _ => Err(malformed()),
+            }
+        })
+    }
+}
+
+// Write every config change announced on `events` to Redis, as the value of `key` and published on
+// the channel of the same name, so subscriber instances pick it up. Runs on a background task; a
+// write that fails is retried with backoff until it succeeds or a newer config replaces it. Must
+// be called from within a Tokio runtime.
+pub fn start_publisher(url: String, key: String, events: broadcast::Receiver<ConfigChanged>) {
+    tokio::spawn(publish_all(url, key, events));
+}
+
+async fn publish_all(url: String, key: String, mut events: broadcast::Receiver<ConfigChanged>) {
+    let mut connection: Option<Connection> = None;
+    let mut pending: Option<Config> = None;
+    let mut delay = RETRY_BASE_DELAY;
+    loop {
+        if pending.is_none() {
+            match events.recv().await {
+                Ok(ConfigChanged { config, .. }) => pending = Some(config),
+                // Only the latest config matters, and it's still to come
+                Err(RecvError::Lagged(skipped)) => debug!("Redis publisher skipped {} config changes", skipped),
+                Err(RecvError::Closed) => return,
+            }
+        }
+        // Catch up to the newest change, so a backlog isn't written one by one
+        loop {
+            match events.try_recv() {
+                Ok(ConfigChanged { config, .. }) => pending = Some(config),
+                Err(TryRecvError::Lagged(_)) => continue,
+                Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => break,
+            }
+        }
+        let Some(config) = &pending else {
+            continue;
+        };
+
+        match publish(&mut connection, &url, &key, config).await {
+            Ok(()) => {
+                debug!("Published the config to Redis key {}", key);
+                pending = None;
+                delay = RETRY_BASE_DELAY;
+            }
+            Err(e) => {
+                warn!("Failed to publish the config to Redis: {}; retrying in {:?}", e, delay);
+                connection = None;
+                sleep(delay).await;
+                delay = (delay * 2).min(RETRY_MAX_DELAY);
+            }
+        }
+    }
+}
+
+async fn publish(connection: &mut Option<Connection>, url: &str, key: &str, config: &Config) -> io::Result<()> {
+    let payload = serde_json::to_vec(config)?;
+    let connection = match connection {
+        Some(connection) => connection,
+        None => connection.insert(Connection::open(url).await?),
+    };
+    // Stored first, so a subscriber that connects in between still finds it
+    connection.command(&[b"SET", key.as_bytes(), &payload]).await?;
+    connection.command(&[b"PUBLISH", key.as_bytes(), &payload]).await?;
+    Ok(())
+}
+
+// Take the config from Redis instead of the upstream: the stored value of `REDIS_KEY` first, then
+// every config published on it, reconnecting with backoff whenever the connection drops.
+pub async fn follow(app_state: web::Data<AppState>) {
+    let url = app_state.settings.redis_url.clone().unwrap_or_default();
+    let key = app_state.settings.redis_key.clone();
+    let mut delay = RETRY_BASE_DELAY;
+    loop {
+        let mut received = 0;
+        let result = subscribe(&app_state, &url, &key, &mut received).await;
+        if received > 0 {
+            delay = RETRY_BASE_DELAY;
+        }
+        match result {
+            Ok(()) => warn!("Redis ended the subscription to {}; resubscribing in {:?}", key, delay),
+            Err(e) => warn!("Redis subscription to {} failed: {}; retrying in {:?}", key, e, delay),
+        }
+        sleep(delay).await;
+        delay = (delay * 2).min(RETRY_MAX_DELAY);
+    }
+}
+
+// Subscribe to `key`, then load its stored value, then commit each config published until the
+// connection drops. Subscribing first means no config published in between is missed.
+async fn subscribe(app_state: &AppState, url: &str, key: &str, received: &mut usize) -> io::Result<()> {
+    let mut subscription = Connection::open(url).await?;
+    subscription.command(&[b"SUBSCRIBE", key.as_bytes()]).await?;
+    info!("Subscribed to configs on Redis channel {}", key);
+
+    let mut connection = Connection::open(url).await?;
+    if let Reply::Bulk(Some(payload)) = connection.command(&[b"GET", key.as_bytes()]).await? {
+        apply(app_state, &payload);
+        *received += 1;
+    }
+    drop(connection);
+
+    loop {
+        // `["message", channel, payload]`; anything else a subscribed connection sees is ignored
+        if let Reply::Array(Some(items)) = subscription.read_reply().await? {
+            if let [Reply::Bulk(Some(kind)), _, Reply::Bulk(Some(payload))] = items.as_slice() {
+                if kind == b"message" {
+                    apply(app_state, payload);
+                    *received += 1;
+                }
+            }
+        }
+    }
+}
+
+// Commit a config another instance published, held to the same rules as one fetched here.
+fn apply(app_state: &AppState, payload: &[u8]) {
+    let config: Config = match serde_json::from_slice(payload) {
+        Ok(config) => config,
+        Err(e) => {
+            warn!("Ignoring an unreadable config from Redis: {}", e);
+            return;
+        }
+    };
+    if let Err(errors) = config.validate() {
+        warn!("Ignoring an invalid config from Redis: {}", errors.join("; "));
+        return;
+    }
+    let current = read_lock(&app_state.config).clone();
+    let config = keep_locked_fields(app_state, current.as_ref(), config);
+    commit_config(app_state, config, ChangeSource::Redis);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+    use crate::source::InMemorySource;
+
+    #[test]
+    fn published_config_is_committed_as_from_redis() {
+        let state = AppState::new(Settings::from_env(), Box::new(InMemorySource::default()));
+        let mut events = state.events.subscribe();
+
+        apply(&state, &serde_json::to_vec(&Config::builder().ball_color("red").build()).unwrap());
+        assert_eq!(events.try_recv().unwrap().source, ChangeSource::Redis);
+    }
+}
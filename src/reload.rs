@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use std::{fs, io};
+
+use log::{info, warn};
+
+use crate::settings::Settings;
+
+// The settings that can change while the service runs, on SIGHUP. The rest of `Settings` is
+// fixed at startup.
+#[derive(Debug)]
+pub struct LiveSettings {
+    poll_interval_secs: AtomicU64,      // Delay between upstream polls, read before every sleep
+    upstream_cache_ttl_secs: AtomicU64, // How long an upstream answer is reused, read on every poll
+}
+
+impl LiveSettings {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            poll_interval_secs: AtomicU64::new(settings.poll_interval_secs),
+            upstream_cache_ttl_secs: AtomicU64::new(settings.upstream_cache_ttl_secs),
+        }
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs.load(Ordering::Relaxed).max(1))
+    }
+
+    pub fn upstream_cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.upstream_cache_ttl_secs.load(Ordering::Relaxed))
+    }
+
+    // Take the live settings from `fresh`, and return the names of the other settings that differ
+    // from `startup` and so only take effect after a restart.
+    pub fn apply(&self, startup: &Settings, fresh: &Settings) -> Vec<String> {
+        let previous = self.poll_interval_secs.swap(fresh.poll_interval_secs, Ordering::Relaxed);
+        if previous != fresh.poll_interval_secs {
+            info!("Poll interval changed from {}s to {}s", previous, fresh.poll_interval_secs);
+        }
+        let previous = self.upstream_cache_ttl_secs.swap(fresh.upstream_cache_ttl_secs, Ordering::Relaxed);
+        if previous != fresh.upstream_cache_ttl_secs {
+            info!("Upstream cache TTL changed from {}s to {}s", previous, fresh.upstream_cache_ttl_secs);
+        }
+        changed_fields(startup, fresh)
+    }
+}
+
+// Names of the restart-only settings whose values differ. Only names are returned, since values
+// may be secrets.
+fn changed_fields(before: &Settings, after: &Settings) -> Vec<String> {
+    // The pattern names every field, so a new setting fails to compile until it's listed here as
+    // either live or restart-only
+    macro_rules! restart_only {
+        (live: $($live:ident),*; restart: $($field:ident),* $(,)?) => {{
+            let Settings { $($live: _,)* $($field: _,)* } = before;
+            [$((stringify!($field), before.$field != after.$field)),*]
+                .into_iter()
+                .filter(|(_, changed)| *changed)
+                .map(|(name, _)| name.to_string())
+                .collect()
+        }};
+    }
+    restart_only!(
+        live: poll_interval_secs, upstream_cache_ttl_secs;
+        restart:
+        bind_address,
+        http_keep_alive_secs,
+        tcp_nodelay,
+        serve_demo_page,
+        display_max_balls,
+        display_stream_min_balls,
+        display_reconnect_base_ms,
+        display_reconnect_cap_ms,
+        display_reconnect_jitter,
+        display_reconnect_max_attempts,
+        display_validate_html,
+        display_max_html_bytes,
+        block_until_ready,
+        ready_timeout_secs,
+        access_log_format,
+        upstream_urls,
+        config_profiles,
+        upstream_health_url,
+        upstream_auth_header,
+        upstream_auth,
+        rate_limit_per_sec,
+        rate_limit_burst,
+        upstream_signing_secret,
+        json_body_limit,
+        ws_send_queue_capacity,
+        ws_replay_size,
+        initial_stagger_max_ms,
+        ws_allowed_origins,
+        ws_max_unknown_commands,
+        ws_max_protocol_errors,
+        ws_unavailable_mode,
+        ws_unavailable_grace_ms,
+        ws_min_protocol_version,
+        ws_hello_window_ms,
+        ws_max_session_secs,
+        ws_log_requests,
+        fetch_overlap,
+        webhook_url,
+        webhook_max_attempts,
+        metrics_backend,
+        ws_min_broadcast_interval_ms,
+        log_max_config_chars,
+        config_lock_timeout_ms,
+        ws_history_mode,
+        ws_skip_duplicates,
+        ws_manager_timeout_ms,
+        ws_echo_commands,
+        settings_file,
+        upstream_clock_skew_warn_secs,
+        ws_max_concurrent_upgrades,
+        ws_upgrade_queue_size,
+        ws_upgrade_queue_ms,
+        server_render_balls,
+        upstream_mode,
+        upstream_idle_timeout_secs,
+        ws_heartbeat_interval_secs,
+        display_allowed_ips,
+        display_denied_ips,
+        display_ip_filter_ws,
+        trust_forwarded_for,
+        upstream_self_check,
+        display_not_ready_refresh_secs,
+        ws_throttled_fields,
+        ws_field_throttle_ms,
+        redis_url,
+        redis_role,
+        redis_key,
+        ws_max_message_size,
+        config_cache_max_age_secs,
+        audit_log_file,
+        ws_max_frame_size,
+        admin_token,
+        raw_payload_limit,
+        default_config,
+        default_config_file,
+        display_template_file,
+        field_mapping_file,
+        upstream_failure_threshold,
+        config_default_format,
+        config_format,
+        locked_fields,
+        config_update_mode,
+        upstream_timeout_secs,
+        upstream_max_body_bytes,
+        stats_interval_secs,
+    )
+}
+
+// Read the `KEY=VALUE` lines of `path`, for `Settings::from_vars` to apply over the process
+// environment. Blank lines and lines starting with `#` are skipped.
+pub fn read_env_file(path: &str) -> io::Result<HashMap<String, String>> {
+    let contents = fs::read_to_string(path)?;
+    let mut vars = HashMap::new();
+    for line in contents.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        match line.split_once('=') {
+            Some((key, value)) => {
+                vars.insert(key.trim().to_string(), value.trim().to_string());
+            }
+            None => warn!("Ignoring malformed line in {}: {:?}", path, line),
+        }
+    }
+    Ok(vars)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_file_reload_updates_the_poll_interval_and_names_the_rest() {
+        let path = std::env::temp_dir().join(format!("reload-{}.env", std::process::id()));
+        fs::write(&path, "# Comment\n\nPOLL_INTERVAL_SECS = 42\nBIND_ADDRESS=0.0.0.0:9999\nnot a setting\n").unwrap();
+        let vars = read_env_file(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        let vars = vars.unwrap();
+        assert_eq!(vars.len(), 2);
+
+        let startup = Settings::from_env();
+        let live = LiveSettings::new(&startup);
+        let pending = live.apply(&startup, &Settings::from_vars(&vars));
+        assert_eq!(live.poll_interval(), Duration::from_secs(42));
+        assert_eq!(pending, ["bind_address"]);
+        // Read, not set
+        assert_ne!(std::env::var("POLL_INTERVAL_SECS").ok().as_deref(), Some("42"));
+    }
+
+    #[test]
+    fn unchanged_settings_need_no_restart() {
+        let startup = Settings::from_env();
+        let live = LiveSettings::new(&startup);
+        assert!(live.apply(&startup, &startup.clone()).is_empty());
+    }
+}
@@ -0,0 +1,128 @@
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use rand::Rng;
+use tracing::Instrument;
+
+// Header carrying the request id, both ways.
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+tokio::task_local! {
+    // Id of the request being handled, for code (like the upstream fetch) that has no `HttpRequest`.
+    static CURRENT_REQUEST_ID: String;
+}
+
+// The id of the request being handled on this task, if any.
+pub fn current_request_id() -> Option<String> {
+    CURRENT_REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+// Middleware giving every request an id: the client's `X-Request-Id` if it sent a sane one,
+// otherwise a random one. The id is echoed in the response, recorded on the request's span,
+// available to handlers, and sent on to the upstream by fetches the request triggers. Handlers
+// read it with `current_request_id`. `env_logger` doesn't print span fields, so other log lines
+// only carry the id where they add it themselves; the access log line always has it.
+pub struct RequestIds;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestIds
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RequestIdMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdMiddleware { service: Rc::new(service) }))
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let id = req
+            .headers()
+            .get(&REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|id| is_valid(id))
+            .map(str::to_owned)
+            .unwrap_or_else(generate);
+
+        // Also a field of the request's span, for `tracing` subscribers
+        let span = tracing::info_span!("request", request_id = %id);
+        let service = self.service.clone();
+        let fut = span.in_scope(|| CURRENT_REQUEST_ID.sync_scope(id.clone(), || service.call(req)));
+        let res = CURRENT_REQUEST_ID.scope(id.clone(), async move {
+            let mut res = fut.await?;
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                res.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+            Ok(res)
+        });
+        Box::pin(res.instrument(span))
+    }
+}
+
+// Client-supplied ids end up in logs and headers, so only short, plain ones are kept.
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+}
+
+fn generate() -> String {
+    format!("{:016x}", rand::thread_rng().gen::<u64>())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App, HttpResponse};
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn response_carries_a_request_id() {
+        let app = test::init_service(App::new().wrap(RequestIds).route("/", web::get().to(HttpResponse::Ok))).await;
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        let id = res.headers().get(&REQUEST_ID_HEADER).and_then(|v| v.to_str().ok()).unwrap();
+        assert!(is_valid(id));
+
+        let req = test::TestRequest::get().uri("/").insert_header((REQUEST_ID_HEADER, "abc-123")).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(&REQUEST_ID_HEADER).unwrap(), "abc-123");
+    }
+
+    #[actix_web::test]
+    async fn handler_reads_the_request_id() {
+        let handler = || async { current_request_id().unwrap_or_default() };
+        let app = test::init_service(App::new().wrap(RequestIds).route("/", web::get().to(handler))).await;
+
+        let req = test::TestRequest::get().uri("/").insert_header((REQUEST_ID_HEADER, "abc-123")).to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "abc-123");
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        let id = res.headers().get(&REQUEST_ID_HEADER).unwrap().clone();
+        assert_eq!(test::read_body(res).await, id.as_bytes());
+        assert_eq!(current_request_id(), None);
+    }
+}
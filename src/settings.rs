@@ -0,0 +1,521 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::{env, fmt};
+
+use log::warn;
+
+use crate::fetch::FetchOverlap;
+use crate::handlers::ConfigFormat;
+use crate::history::HistoryMode;
+use crate::metrics::MetricsBackend;
+use crate::redis::RedisRole;
+use crate::source::{PayloadFormat, UpdateMode, UpstreamMode};
+use crate::websocket::{UnavailableMode, DEFAULT_BROADCAST_CAPACITY, DEFAULT_LOG_LIMIT};
+
+// Runtime settings for the service, read from the environment at startup. `LiveSettings` holds
+// the few that a SIGHUP can change afterwards.
+#[derive(Clone)]
+pub struct Settings {
+    pub bind_address: String,                    // Addresses the HTTP server listens on, comma-separated; IPv6 ones in brackets
+    pub http_keep_alive_secs: u64,               // Idle time before a keep-alive HTTP connection is closed; 0 disables keep-alive
+    pub tcp_nodelay: bool,                       // Send small frames immediately instead of batching them (Nagle's algorithm off)
+    pub poll_interval_secs: u64,                 // Delay between upstream polls
+    pub serve_demo_page: bool,                   // Whether the balls demo page is served at `/`
+    pub display_max_balls: u8,                   // Most balls the demo page renders, whatever the config asks for
+    pub display_stream_min_balls: u8,            // Pages with at least this many balls are streamed rather than rendered whole; 0 never streams
+    pub display_reconnect_base_ms: u64,          // First delay before the demo page reconnects its WebSocket, doubled on every failure
+    pub display_reconnect_cap_ms: u64,           // Upper bound for the demo page's reconnect delay
+    pub display_reconnect_jitter: f64,           // Fraction of each reconnect delay the demo page randomizes, from 0 to 1
+    pub display_reconnect_max_attempts: u32,     // Failed reconnects before the demo page only polls `/config`; 0 never gives up
+    pub display_validate_html: bool,             // Check each rendered demo page's structure, answering 500 instead of serving a broken one
+    pub display_max_html_bytes: usize,           // Largest demo page rendered; balls are dropped (with a notice) to stay under it
+    pub block_until_ready: bool,                 // Load the config once before accepting traffic
+    pub ready_timeout_secs: u64,                 // How long startup waits for that initial config
+    pub access_log_format: String,               // actix `Logger` format for the access log
+    pub upstream_urls: Vec<String>,              // Config upstreams, tried in order until one succeeds
+    pub config_profiles: BTreeMap<String, String>, // Named configs and the upstream each is polled from, for `/ws/?profile=<name>`
+    pub upstream_health_url: Option<String>,     // Endpoint probed each poll to judge upstream health; unset judges by the config fetches
+    pub upstream_auth_header: String,            // Header carrying `upstream_auth`, e.g. `Authorization` or `X-API-Key`
+    pub upstream_auth: Option<String>,           // Credential sent to the upstreams; never logged
+    pub rate_limit_per_sec: u32,                 // Requests per second each client IP may make to rate-limited routes; 0 disables limiting
+    pub rate_limit_burst: u32,                   // Requests a client IP may make at once before `rate_limit_per_sec` applies
+    pub upstream_signing_secret: Option<String>, // Shared secret upstream payloads must be signed with; never logged
+    pub json_body_limit: usize,                  // Maximum accepted JSON request body, in bytes
+    pub ws_send_queue_capacity: usize,           // Updates a slow WebSocket client may lag before the oldest are dropped
+    pub ws_replay_size: usize,                   // Recent broadcasts replayed to new WebSocket sessions; 0 disables replay
+    pub initial_stagger_max_ms: u64,             // Upper bound for the random delay before the first poll
+    pub ws_allowed_origins: Vec<String>,         // Origins allowed to open WebSockets; empty allows any
+    pub ws_max_unknown_commands: u32,            // Unrecognized messages tolerated per session before closing it
+    pub ws_max_protocol_errors: u32,             // Protocol errors in a row tolerated per session before closing it
+    pub ws_unavailable_mode: UnavailableMode,    // What WebSocket clients get on connect while no config is loaded
+    pub ws_unavailable_grace_ms: u64,            // How long a session waits for the first config before falling back to `ws_unavailable_mode`
+    pub ws_min_protocol_version: u32,            // Oldest WebSocket protocol version served; 0 also serves clients that never say hello
+    pub ws_hello_window_ms: u64,                 // How long a new WebSocket session waits for a `hello` before sending the config
+    pub ws_max_session_secs: u64,                // How long a WebSocket session may stay open before it's closed for the client to reconnect; 0 is unlimited
+    pub ws_log_requests: bool,                   // Log each WebSocket handshake request at debug, with credentials redacted
+    pub fetch_overlap: FetchOverlap,             // Whether a fetch waits for or skips one that's already running
+    pub webhook_url: Option<String>,             // URL every committed config change is POSTed to
+    pub webhook_max_attempts: u32,               // Tries per change before the webhook gives up on it
+    pub metrics_backend: MetricsBackend,         // Where metrics go: `prometheus` serves them on `/metrics`, `none` drops them
+    pub ws_min_broadcast_interval_ms: u64,       // Least time between two WebSocket broadcasts; 0 sends every change right away
+    pub log_max_config_chars: usize,             // Characters of a config written to the logs before it's cut off; 0 logs it whole
+    pub config_lock_timeout_ms: u64,             // Longest `/` and `/config` wait for a config update to release the lock; 0 waits indefinitely
+    pub ws_history_mode: HistoryMode,            // How the replay history stores broadcasts: whole, or as changes from the one before
+    pub ws_skip_duplicates: bool,                // Don't send a session a broadcast that leaves its config as it is
+    pub ws_manager_timeout_ms: u64,              // Longest an HTTP endpoint waits on the WebSocket manager before answering 503; 0 waits indefinitely
+    pub ws_echo_commands: bool,                  // Echo each client text message back as `{"type":"echo",...}`, for front-end debugging
+    pub settings_file: Option<String>,           // `KEY=VALUE` file read into the environment at startup and on every SIGHUP
+    pub upstream_clock_skew_warn_secs: u64,      // Warn when the upstream's `Date` is further than this from our clock; 0 never warns
+    pub ws_max_concurrent_upgrades: usize,       // WebSocket upgrades in progress at once, until each session's first config is sent; 0 doesn't limit
+    pub ws_upgrade_queue_size: usize,            // Upgrades that may wait for a slot once the limit is reached; the rest get a 503
+    pub ws_upgrade_queue_ms: u64,                // Longest a queued upgrade waits for a slot before getting a 503; 0 waits indefinitely
+    pub server_render_balls: bool,               // Render the balls into the page at `/`; when off, the page starts empty and its script adds them from the WebSocket
+    pub upstream_mode: UpstreamMode,             // Whether the upstream is polled, long-polled, or streamed from
+    pub upstream_idle_timeout_secs: u64,         // Longest a long poll is held, or a stream stays quiet, before reconnecting; 0 never gives up
+    pub ws_heartbeat_interval_secs: u64,         // How often each WebSocket client is pinged, for `ws_pong_rtt_seconds`; 0 never pings
+    pub display_allowed_ips: Vec<String>,        // CIDR ranges allowed to load the display pages; empty allows any
+    pub display_denied_ips: Vec<String>,         // CIDR ranges turned away from the display pages, even if allowed
+    pub display_ip_filter_ws: bool,              // Apply the display page address ranges to `/ws/` too
+    pub trust_forwarded_for: bool,               // Take the client address from `X-Forwarded-For`; only safe behind a proxy that sets it
+    pub upstream_self_check: bool,               // Try the upstream once at startup and log a diagnosis if it fails
+    pub display_not_ready_refresh_secs: u64,     // How often the page shown before the first config reloads itself
+    pub ws_throttled_fields: Vec<String>,        // Config fields whose changes are broadcast at most once per `ws_field_throttle_ms`
+    pub ws_field_throttle_ms: u64,               // Least time between two broadcasts changing the same throttled field
+    pub redis_url: Option<String>,               // Redis to share configs between instances through; may hold a password, never logged
+    pub redis_role: RedisRole,                   // Whether this instance writes configs to Redis or takes them from it
+    pub redis_key: String,                       // Key the shared config is stored under, and channel it's published on
+    pub ws_max_message_size: usize,              // Largest text message (command) accepted from a WebSocket client, in bytes
+    pub config_cache_max_age_secs: u32,          // How long proxies may cache `GET /config` responses; 0 sends `no-cache`
+    pub audit_log_file: Option<String>,          // File every committed config change is appended to as a line of JSON; log only when unset
+    pub ws_max_frame_size: usize,                // Largest inbound WebSocket frame accepted, in bytes
+    pub admin_token: Option<String>,             // Bearer token for admin endpoints; unset disables them
+    pub raw_payload_limit: usize,                // Bytes of the last upstream payload kept for `/debug/last-raw`
+    pub default_config: Option<String>,          // JSON config served until the first successful fetch
+    pub default_config_file: Option<String>,     // File holding that JSON, used when `default_config` is unset
+    pub display_template_file: Option<String>,   // TinyTemplate file rendered as the demo page instead of the built-in one
+    pub field_mapping_file: Option<String>,      // JSON file renaming upstream fields to `Config` fields
+    pub upstream_failure_threshold: u32,         // Consecutive failed fetches tolerated before the upstream is marked down
+    pub config_default_format: ConfigFormat,     // What `GET /config` answers in when the `Accept` header doesn't pick a format
+    pub config_format: PayloadFormat,            // How upstream payloads are encoded; `auto` goes by `Content-Type`
+    pub locked_fields: Vec<String>,              // Config fields the upstream can't change once a config is loaded
+    pub config_update_mode: UpdateMode,          // Whether upstream payloads replace the config or are merged onto it
+    pub upstream_cache_ttl_secs: u64,            // How long an upstream answer is reused before polls ask again; admin refreshes always ask
+    pub upstream_timeout_secs: u64,              // Per-request timeout for upstream fetches, body included
+    pub upstream_max_body_bytes: usize,          // Largest upstream response body accepted
+    pub stats_interval_secs: u64,                // How often `/ws/stats` pushes a stats frame
+}
+
+pub const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1:8081";
+
+const DEFAULT_UPSTREAM_URL: &str = "http://localhost:8080/config";
+
+// Client address, request line, status, and response time in milliseconds.
+const DEFAULT_ACCESS_LOG_FORMAT: &str = "%a \"%r\" %s %b %Dms %{x-request-id}o";
+
+impl Settings {
+    pub fn from_env() -> Self {
+        Self::from_vars(&HashMap::new())
+    }
+
+    // Read from `overrides`, e.g. the contents of `SETTINGS_FILE`, falling back to the process
+    // environment for the keys it doesn't set. The environment itself is never modified.
+    pub fn from_vars(overrides: &HashMap<String, String>) -> Self {
+        let vars = Vars(overrides);
+        Self {
+            bind_address: vars.or("BIND_ADDRESS", DEFAULT_BIND_ADDRESS.to_string()),
+            http_keep_alive_secs: vars.or("HTTP_KEEP_ALIVE_SECS", 5),
+            tcp_nodelay: vars.flag("TCP_NODELAY", true),
+            poll_interval_secs: vars.or("POLL_INTERVAL_SECS", 5),
+            serve_demo_page: vars.flag("SERVE_DEMO_PAGE", true),
+            display_max_balls: vars.or("DISPLAY_MAX_BALLS", 100),
+            display_stream_min_balls: vars.or("DISPLAY_STREAM_MIN_BALLS", 0),
+            display_reconnect_base_ms: vars.or("DISPLAY_RECONNECT_BASE_MS", 500),
+            display_reconnect_cap_ms: vars.or("DISPLAY_RECONNECT_CAP_MS", 30_000),
+            display_reconnect_jitter: vars.or("DISPLAY_RECONNECT_JITTER", 0.5),
+            display_reconnect_max_attempts: vars.or("DISPLAY_RECONNECT_MAX_ATTEMPTS", 0),
+            display_validate_html: vars.flag("DISPLAY_VALIDATE_HTML", false),
+            display_max_html_bytes: vars.or("DISPLAY_MAX_HTML_BYTES", 256 * 1024),
+            block_until_ready: vars.flag("BLOCK_UNTIL_READY", false),
+            ready_timeout_secs: vars.or("READY_TIMEOUT_SECS", 10),
+            access_log_format: vars.or("ACCESS_LOG_FORMAT", DEFAULT_ACCESS_LOG_FORMAT.to_string()),
+            upstream_urls: vars.list("CONFIG_UPSTREAM_URLS", &[DEFAULT_UPSTREAM_URL]),
+            config_profiles: vars.pairs("CONFIG_PROFILES"),
+            upstream_health_url: vars.text("CONFIG_UPSTREAM_HEALTH_URL"),
+            upstream_auth_header: vars.or("CONFIG_UPSTREAM_AUTH_HEADER", "Authorization".to_string()),
+            upstream_auth: vars.text("CONFIG_UPSTREAM_AUTH"),
+            rate_limit_per_sec: vars.or("RATE_LIMIT_PER_SEC", 10),
+            rate_limit_burst: vars.or("RATE_LIMIT_BURST", 20),
+            upstream_signing_secret: vars.text("CONFIG_SIGNING_SECRET"),
+            json_body_limit: vars.or("JSON_BODY_LIMIT_BYTES", 16 * 1024),
+            ws_send_queue_capacity: vars.or("WS_SEND_QUEUE_CAPACITY", DEFAULT_BROADCAST_CAPACITY),
+            ws_replay_size: vars.or("WS_REPLAY_SIZE", 0),
+            initial_stagger_max_ms: vars.or("INITIAL_FETCH_STAGGER_MS", 1000),
+            ws_allowed_origins: vars.list("WS_ALLOWED_ORIGINS", &[]),
+            ws_max_unknown_commands: vars.or("WS_MAX_UNKNOWN_COMMANDS", 10),
+            ws_max_protocol_errors: vars.or("WS_MAX_PROTOCOL_ERRORS", 3),
+            ws_unavailable_mode: vars.or("WS_UNAVAILABLE_MODE", UnavailableMode::Error),
+            ws_unavailable_grace_ms: vars.or("WS_UNAVAILABLE_GRACE_MS", 0),
+            ws_min_protocol_version: vars.or("WS_MIN_PROTOCOL_VERSION", 0),
+            ws_hello_window_ms: vars.or("WS_HELLO_WINDOW_MS", 0),
+            ws_max_session_secs: vars.or("WS_MAX_SESSION_SECS", 0),
+            ws_log_requests: vars.flag("WS_LOG_REQUESTS", false),
+            fetch_overlap: vars.or("CONFIG_FETCH_OVERLAP", FetchOverlap::Wait),
+            webhook_url: vars.text("CONFIG_WEBHOOK_URL"),
+            webhook_max_attempts: vars.or("CONFIG_WEBHOOK_MAX_ATTEMPTS", 5),
+            metrics_backend: vars.or("METRICS_BACKEND", MetricsBackend::Prometheus),
+            ws_min_broadcast_interval_ms: vars.or("WS_MIN_BROADCAST_INTERVAL_MS", 0),
+            log_max_config_chars: vars.or("LOG_MAX_CONFIG_CHARS", DEFAULT_LOG_LIMIT),
+            config_lock_timeout_ms: vars.or("CONFIG_LOCK_TIMEOUT_MS", 0),
+            ws_history_mode: vars.or("WS_HISTORY_MODE", HistoryMode::Full),
+            ws_skip_duplicates: vars.flag("WS_SKIP_DUPLICATES", true),
+            ws_manager_timeout_ms: vars.or("WS_MANAGER_TIMEOUT_MS", 5_000),
+            ws_echo_commands: vars.flag("WS_ECHO_COMMANDS", false),
+            settings_file: vars.text("SETTINGS_FILE"),
+            upstream_clock_skew_warn_secs: vars.or("UPSTREAM_CLOCK_SKEW_WARN_SECS", 30),
+            ws_max_concurrent_upgrades: vars.or("WS_MAX_CONCURRENT_UPGRADES", 64),
+            ws_upgrade_queue_size: vars.or("WS_UPGRADE_QUEUE_SIZE", 256),
+            ws_upgrade_queue_ms: vars.or("WS_UPGRADE_QUEUE_MS", 1_000),
+            server_render_balls: vars.flag("SERVER_RENDER_BALLS", true),
+            upstream_mode: vars.or("UPSTREAM_MODE", UpstreamMode::Poll),
+            upstream_idle_timeout_secs: vars.or("UPSTREAM_IDLE_TIMEOUT_SECS", 90),
+            ws_heartbeat_interval_secs: vars.or("WS_HEARTBEAT_INTERVAL_SECS", 30),
+            display_allowed_ips: vars.list("DISPLAY_ALLOWED_IPS", &[]),
+            display_denied_ips: vars.list("DISPLAY_DENIED_IPS", &[]),
+            display_ip_filter_ws: vars.flag("DISPLAY_IP_FILTER_WS", false),
+            trust_forwarded_for: vars.flag("TRUST_FORWARDED_FOR", false),
+            upstream_self_check: vars.flag("UPSTREAM_SELF_CHECK", false),
+            display_not_ready_refresh_secs: vars.or("DISPLAY_NOT_READY_REFRESH_SECS", 5).max(1),
+            ws_throttled_fields: vars.list("WS_THROTTLED_FIELDS", &[]),
+            ws_field_throttle_ms: vars.or("WS_FIELD_THROTTLE_MS", 1000),
+            redis_url: vars.text("REDIS_URL"),
+            redis_role: vars.or("REDIS_ROLE", RedisRole::Publisher),
+            redis_key: vars.or("REDIS_KEY", "subscriber_microservice:config".to_string()),
+            ws_max_message_size: vars.or("WS_MAX_MESSAGE_SIZE", 4096),
+            config_cache_max_age_secs: vars.or("CONFIG_CACHE_MAX_AGE_SECS", 0),
+            audit_log_file: vars.text("AUDIT_LOG_FILE"),
+            ws_max_frame_size: vars.or("WS_MAX_FRAME_SIZE", 64 * 1024),
+            admin_token: vars.text("ADMIN_TOKEN"),
+            raw_payload_limit: vars.or("DEBUG_RAW_PAYLOAD_MAX_BYTES", 64 * 1024),
+            default_config: vars.text("DEFAULT_CONFIG"),
+            default_config_file: vars.text("DEFAULT_CONFIG_FILE"),
+            display_template_file: vars.text("DISPLAY_TEMPLATE_FILE"),
+            field_mapping_file: vars.text("CONFIG_FIELD_MAPPING_FILE"),
+            upstream_failure_threshold: vars.or("UPSTREAM_FAILURE_THRESHOLD", 3),
+            config_default_format: vars.or("CONFIG_DEFAULT_FORMAT", ConfigFormat::Json),
+            config_format: vars.or("CONFIG_FORMAT", PayloadFormat::Auto),
+            locked_fields: vars.list("CONFIG_LOCKED_FIELDS", &[]),
+            config_update_mode: vars.or("CONFIG_UPDATE_MODE", UpdateMode::Replace),
+            upstream_cache_ttl_secs: vars.or("UPSTREAM_CACHE_TTL_SECS", 0),
+            upstream_timeout_secs: vars.or("UPSTREAM_TIMEOUT_SECS", 10),
+            upstream_max_body_bytes: vars.or("UPSTREAM_MAX_BODY_BYTES", 1024 * 1024),
+            stats_interval_secs: vars.or("STATS_INTERVAL_SECS", 5),
+        }
+    }
+
+    // Check the settings whose invalid values stop the service at startup rather than being
+    // ignored, since the default would quietly misread the upstream. Only `CONFIG_FORMAT` so far.
+    pub fn check_vars(overrides: &HashMap<String, String>) -> Result<(), String> {
+        if let Some(format) = Vars(overrides).text("CONFIG_FORMAT") {
+            format.trim().parse::<PayloadFormat>().map_err(|e| format!("Invalid CONFIG_FORMAT: {}", e))?;
+        }
+        Ok(())
+    }
+
+    // Every address in `bind_address`, e.g. `["0.0.0.0:8081", "[::]:8081"]` for dual-stack.
+    pub fn bind_addresses(&self) -> Vec<String> {
+        self.bind_address.split(',').map(str::trim).filter(|address| !address.is_empty()).map(str::to_string).collect()
+    }
+}
+
+// Written out rather than derived so the secrets don't end up in logs.
+impl fmt::Debug for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const REDACTED: &str = "<redacted>";
+        // Listing every field makes a new one fail to compile until it's added below
+        let Settings {
+            bind_address,
+            http_keep_alive_secs,
+            tcp_nodelay,
+            poll_interval_secs,
+            serve_demo_page,
+            display_max_balls,
+            display_stream_min_balls,
+            display_reconnect_base_ms,
+            display_reconnect_cap_ms,
+            display_reconnect_jitter,
+            display_reconnect_max_attempts,
+            display_validate_html,
+            display_max_html_bytes,
+            block_until_ready,
+            ready_timeout_secs,
+            access_log_format,
+            upstream_urls,
+            config_profiles,
+            upstream_health_url,
+            upstream_auth_header,
+            upstream_auth,
+            rate_limit_per_sec,
+            rate_limit_burst,
+            upstream_signing_secret,
+            json_body_limit,
+            ws_send_queue_capacity,
+            ws_replay_size,
+            initial_stagger_max_ms,
+            ws_allowed_origins,
+            ws_max_unknown_commands,
+            ws_max_protocol_errors,
+            ws_unavailable_mode,
+            ws_unavailable_grace_ms,
+            ws_min_protocol_version,
+            ws_hello_window_ms,
+            ws_max_session_secs,
+            ws_log_requests,
+            fetch_overlap,
+            webhook_url,
+            webhook_max_attempts,
+            metrics_backend,
+            ws_min_broadcast_interval_ms,
+            log_max_config_chars,
+            config_lock_timeout_ms,
+            ws_history_mode,
+            ws_skip_duplicates,
+            ws_manager_timeout_ms,
+            ws_echo_commands,
+            settings_file,
+            upstream_clock_skew_warn_secs,
+            ws_max_concurrent_upgrades,
+            ws_upgrade_queue_size,
+            ws_upgrade_queue_ms,
+            server_render_balls,
+            upstream_mode,
+            upstream_idle_timeout_secs,
+            ws_heartbeat_interval_secs,
+            display_allowed_ips,
+            display_denied_ips,
+            display_ip_filter_ws,
+            trust_forwarded_for,
+            upstream_self_check,
+            display_not_ready_refresh_secs,
+            ws_throttled_fields,
+            ws_field_throttle_ms,
+            redis_url,
+            redis_role,
+            redis_key,
+            ws_max_message_size,
+            config_cache_max_age_secs,
+            audit_log_file,
+            ws_max_frame_size,
+            admin_token,
+            raw_payload_limit,
+            default_config,
+            default_config_file,
+            display_template_file,
+            field_mapping_file,
+            upstream_failure_threshold,
+            config_default_format,
+            config_format,
+            locked_fields,
+            config_update_mode,
+            upstream_cache_ttl_secs,
+            upstream_timeout_secs,
+            upstream_max_body_bytes,
+            stats_interval_secs,
+        } = self;
+        f.debug_struct("Settings")
+            .field("bind_address", bind_address)
+            .field("http_keep_alive_secs", http_keep_alive_secs)
+            .field("tcp_nodelay", tcp_nodelay)
+            .field("poll_interval_secs", poll_interval_secs)
+            .field("serve_demo_page", serve_demo_page)
+            .field("display_max_balls", display_max_balls)
+            .field("display_stream_min_balls", display_stream_min_balls)
+            .field("display_reconnect_base_ms", display_reconnect_base_ms)
+            .field("display_reconnect_cap_ms", display_reconnect_cap_ms)
+            .field("display_reconnect_jitter", display_reconnect_jitter)
+            .field("display_reconnect_max_attempts", display_reconnect_max_attempts)
+            .field("display_validate_html", display_validate_html)
+            .field("display_max_html_bytes", display_max_html_bytes)
+            .field("block_until_ready", block_until_ready)
+            .field("ready_timeout_secs", ready_timeout_secs)
+            .field("access_log_format", access_log_format)
+            .field("upstream_urls", upstream_urls)
+            .field("config_profiles", config_profiles)
+            .field("upstream_health_url", upstream_health_url)
+            .field("upstream_auth_header", upstream_auth_header)
+            .field("upstream_auth", &upstream_auth.as_ref().map(|_| REDACTED))
+            .field("rate_limit_per_sec", rate_limit_per_sec)
+            .field("rate_limit_burst", rate_limit_burst)
+            .field("upstream_signing_secret", &upstream_signing_secret.as_ref().map(|_| REDACTED))
+            .field("json_body_limit", json_body_limit)
+            .field("ws_send_queue_capacity", ws_send_queue_capacity)
+            .field("ws_replay_size", ws_replay_size)
+            .field("initial_stagger_max_ms", initial_stagger_max_ms)
+            .field("ws_allowed_origins", ws_allowed_origins)
+            .field("ws_max_unknown_commands", ws_max_unknown_commands)
+            .field("ws_max_protocol_errors", ws_max_protocol_errors)
+            .field("ws_unavailable_mode", ws_unavailable_mode)
+            .field("ws_unavailable_grace_ms", ws_unavailable_grace_ms)
+            .field("ws_min_protocol_version", ws_min_protocol_version)
+            .field("ws_hello_window_ms", ws_hello_window_ms)
+            .field("ws_max_session_secs", ws_max_session_secs)
+            .field("ws_log_requests", ws_log_requests)
+            .field("fetch_overlap", fetch_overlap)
+            .field("webhook_url", webhook_url)
+            .field("webhook_max_attempts", webhook_max_attempts)
+            .field("metrics_backend", metrics_backend)
+            .field("ws_min_broadcast_interval_ms", ws_min_broadcast_interval_ms)
+            .field("log_max_config_chars", log_max_config_chars)
+            .field("config_lock_timeout_ms", config_lock_timeout_ms)
+            .field("ws_history_mode", ws_history_mode)
+            .field("ws_skip_duplicates", ws_skip_duplicates)
+            .field("ws_manager_timeout_ms", ws_manager_timeout_ms)
+            .field("ws_echo_commands", ws_echo_commands)
+            .field("settings_file", settings_file)
+            .field("upstream_clock_skew_warn_secs", upstream_clock_skew_warn_secs)
+            .field("ws_max_concurrent_upgrades", ws_max_concurrent_upgrades)
+            .field("ws_upgrade_queue_size", ws_upgrade_queue_size)
+            .field("ws_upgrade_queue_ms", ws_upgrade_queue_ms)
+            .field("server_render_balls", server_render_balls)
+            .field("upstream_mode", upstream_mode)
+            .field("upstream_idle_timeout_secs", upstream_idle_timeout_secs)
+            .field("ws_heartbeat_interval_secs", ws_heartbeat_interval_secs)
+            .field("display_allowed_ips", display_allowed_ips)
+            .field("display_denied_ips", display_denied_ips)
+            .field("display_ip_filter_ws", display_ip_filter_ws)
+            .field("trust_forwarded_for", trust_forwarded_for)
+            .field("upstream_self_check", upstream_self_check)
+            .field("display_not_ready_refresh_secs", display_not_ready_refresh_secs)
+            .field("ws_throttled_fields", ws_throttled_fields)
+            .field("ws_field_throttle_ms", ws_field_throttle_ms)
+            .field("redis_url", &redis_url.as_ref().map(|_| REDACTED))
+            .field("redis_role", redis_role)
+            .field("redis_key", redis_key)
+            .field("ws_max_message_size", ws_max_message_size)
+            .field("config_cache_max_age_secs", config_cache_max_age_secs)
+            .field("audit_log_file", audit_log_file)
+            .field("ws_max_frame_size", ws_max_frame_size)
+            .field("admin_token", &admin_token.as_ref().map(|_| REDACTED))
+            .field("raw_payload_limit", raw_payload_limit)
+            .field("default_config", default_config)
+            .field("default_config_file", default_config_file)
+            .field("display_template_file", display_template_file)
+            .field("field_mapping_file", field_mapping_file)
+            .field("upstream_failure_threshold", upstream_failure_threshold)
+            .field("config_default_format", config_default_format)
+            .field("config_format", config_format)
+            .field("locked_fields", locked_fields)
+            .field("config_update_mode", config_update_mode)
+            .field("upstream_cache_ttl_secs", upstream_cache_ttl_secs)
+            .field("upstream_timeout_secs", upstream_timeout_secs)
+            .field("upstream_max_body_bytes", upstream_max_body_bytes)
+            .field("stats_interval_secs", stats_interval_secs)
+            .finish()
+    }
+}
+
+// Where `from_vars` looks settings up: the overrides first, then the process environment.
+struct Vars<'a>(&'a HashMap<String, String>);
+
+impl Vars<'_> {
+    fn get(&self, key: &str) -> Option<String> {
+        self.0.get(key).cloned().or_else(|| env::var(key).ok())
+    }
+
+    // Read a string that counts as unset when empty.
+    fn text(&self, key: &str) -> Option<String> {
+        self.get(key).filter(|value| !value.is_empty())
+    }
+
+    // Read a boolean flag, accepting the usual spellings and falling back to `default` otherwise.
+    fn flag(&self, key: &str, default: bool) -> bool {
+        match self.get(key) {
+            Some(value) => match value.trim().to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => true,
+                "0" | "false" | "no" | "off" => false,
+                other => {
+                    warn!("Ignoring invalid value for {}: {:?}", key, other);
+                    default
+                }
+            },
+            None => default,
+        }
+    }
+
+    // Read and parse a value, falling back to `default` when unset or unparsable.
+    fn or<T: FromStr>(&self, key: &str, default: T) -> T {
+        match self.get(key) {
+            Some(value) => value.trim().parse().unwrap_or_else(|_| {
+                warn!("Ignoring invalid value for {}: {:?}", key, value);
+                default
+            }),
+            None => default,
+        }
+    }
+
+    // Read a comma-separated list, falling back to `default` when unset or empty.
+    fn list(&self, key: &str, default: &[&str]) -> Vec<String> {
+        let values: Vec<String> = self
+            .get(key)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+            .collect();
+        if values.is_empty() {
+            default.iter().map(|value| value.to_string()).collect()
+        } else {
+            values
+        }
+    }
+
+    // Read a comma-separated list of `name=value` pairs, warning about and skipping malformed ones.
+    fn pairs(&self, key: &str) -> BTreeMap<String, String> {
+        let mut pairs = BTreeMap::new();
+        for entry in self.list(key, &[]) {
+            match entry.split_once('=').map(|(name, value)| (name.trim(), value.trim())) {
+                Some((name, value)) if !name.is_empty() && !value.is_empty() => {
+                    pairs.insert(name.to_string(), value.to_string());
+                }
+                _ => warn!("Ignoring invalid entry in {}: {:?}", key, entry),
+            }
+        }
+        pairs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_output_redacts_the_secrets() {
+        let mut settings = Settings::from_env();
+        settings.admin_token = Some("hunter2-admin".to_string());
+        settings.upstream_auth = Some("Bearer hunter2-upstream".to_string());
+        settings.upstream_signing_secret = Some("hunter2-signing".to_string());
+        settings.redis_url = Some("redis://:hunter2-redis@localhost".to_string());
+        let debug = format!("{:?}", settings);
+        assert!(!debug.contains("hunter2"), "{}", debug);
+        assert!(debug.contains("admin_token: Some(\"<redacted>\")"));
+        assert!(debug.contains("poll_interval_secs"));
+    }
+
+    #[test]
+    fn overrides_win_over_the_environment() {
+        let vars = HashMap::from([("POLL_INTERVAL_SECS".to_string(), "42".to_string()), ("ADMIN_TOKEN".to_string(), String::new())]);
+        let settings = Settings::from_vars(&vars);
+        assert_eq!(settings.poll_interval_secs, 42);
+        assert_eq!(settings.admin_token, None);
+    }
+
+    #[test]
+    fn toml_config_format_is_refused() {
+        let vars = HashMap::from([("CONFIG_FORMAT".to_string(), "toml".to_string())]);
+        let error = Settings::check_vars(&vars).unwrap_err();
+        assert!(error.contains("TOML isn't supported"), "{}", error);
+
+        let vars = HashMap::from([("CONFIG_FORMAT".to_string(), "yaml".to_string())]);
+        assert_eq!(Settings::check_vars(&vars), Ok(()));
+    }
+}
@@ -0,0 +1,52 @@
+use sha1::{Digest, Sha1};
+
+// Header upstreams put the payload signature in, as `sha1=<hex HMAC-SHA1 of the body>`.
+pub const SIGNATURE_HEADER: &str = "X-Config-Signature";
+
+const BLOCK_SIZE: usize = 64; // SHA-1 block size, in bytes
+
+// HMAC-SHA1 of `body` under `secret` (RFC 2104), formatted as the header value.
+pub fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut key = [0u8; BLOCK_SIZE];
+    if secret.len() > BLOCK_SIZE {
+        key[..20].copy_from_slice(&Sha1::digest(secret));
+    } else {
+        key[..secret.len()].copy_from_slice(secret);
+    }
+    let inner = Sha1::new().chain_update(key.map(|b| b ^ 0x36)).chain_update(body).finalize();
+    let mac = Sha1::new().chain_update(key.map(|b| b ^ 0x5c)).chain_update(inner).finalize();
+    let hex: String = mac.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha1={}", hex)
+}
+
+// Whether `signature` is the header value `sign` produces for `body`. Compares in constant
+// time, so a forger can't learn the expected value byte by byte.
+pub fn verify(secret: &[u8], body: &[u8], signature: &str) -> bool {
+    let expected = sign(secret, body);
+    let signature = signature.trim().to_ascii_lowercase();
+    expected.len() == signature.len() && expected.bytes().zip(signature.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test cases 1, 2, and 6 of RFC 2202; the last has a key longer than a block.
+    #[test]
+    fn sign_matches_the_rfc_2202_vectors() {
+        assert_eq!(sign(&[0x0b; 20], b"Hi There"), "sha1=b617318655057264e28bc0b6fb378c8ef146be00");
+        assert_eq!(sign(b"Jefe", b"what do ya want for nothing?"), "sha1=effcdf6ae5eb2fa2d27416d5f184df9c259a7c79");
+        assert_eq!(
+            sign(&[0xaa; 80], b"Test Using Larger Than Block-Size Key - Hash Key First"),
+            "sha1=aa4ae5e15272d00e95705637ce8a3b55ed402112"
+        );
+    }
+
+    #[test]
+    fn verify_accepts_only_the_matching_signature() {
+        let signature = sign(b"secret", b"{}");
+        assert!(verify(b"secret", b"{}", &signature.to_ascii_uppercase()));
+        assert!(!verify(b"secret", b"{ }", &signature));
+        assert!(!verify(b"other", b"{}", &signature));
+    }
+}
@@ -0,0 +1,825 @@
+use actix_web::http::header::HttpDate;
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Error as ReqwestError, StatusCode};
+use serde_json::Value;
+use std::fmt;
+use std::ops::ControlFlow;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::Instrument;
+
+use crate::mapping::FieldMapping;
+use crate::request_id::{current_request_id, REQUEST_ID_HEADER};
+use crate::settings::Settings;
+use crate::signature::{self, SIGNATURE_HEADER};
+use crate::trace::{outgoing_traceparent, TraceParent, TRACEPARENT_HEADER};
+use crate::config::PartialConfig;
+use crate::Config;
+
+// Why fetching a config from the upstream failed.
+#[derive(Debug)]
+pub enum ConfigError {
+    Request(ReqwestError),        // Connecting, sending, or reading the response failed
+    Http(StatusCode),             // The upstream answered with a non-2xx status
+    Parse(serde_json::Error),     // The payload isn't a valid `Config`
+    ParseYaml(serde_yaml::Error), // The payload isn't valid YAML
+    UnsupportedFormat(String),    // The response's content type isn't one we can parse
+    BodyTooLarge(usize),          // The response body exceeded this many bytes
+    Invalid(Vec<String>),         // The payload is a config, but breaks the rules `Config::validate` checks
+    BadSignature(&'static str),   // The payload's signature is missing or doesn't match
+    InvalidAuthHeader(String),    // The configured upstream auth header can't be sent
+    StreamIdle(Duration),         // A streamed response went quiet for longer than this
+    StreamUnsupported,            // The source can only be polled
+    NoUpstream,                   // No upstream URL is configured to fetch from
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Request(e) => write!(f, "upstream request failed: {}", e),
+            ConfigError::Http(status) => write!(f, "upstream responded with {}", status),
+            ConfigError::Parse(e) => write!(f, "invalid config payload: {}", e),
+            ConfigError::ParseYaml(e) => write!(f, "invalid YAML config payload: {}", e),
+            ConfigError::UnsupportedFormat(content_type) => {
+                write!(f, "unsupported config content type {:?}; expected JSON or YAML (or set CONFIG_FORMAT)", content_type)
+            }
+            ConfigError::Invalid(errors) => write!(f, "invalid config: {}", errors.join("; ")),
+            ConfigError::BadSignature(reason) => write!(f, "rejected config payload: {}", reason),
+            ConfigError::BodyTooLarge(limit) => write!(f, "upstream response exceeds {} bytes", limit),
+            ConfigError::InvalidAuthHeader(reason) => write!(f, "invalid upstream auth header: {}", reason),
+            ConfigError::StreamIdle(idle) => write!(f, "upstream stream sent nothing for {:?}", idle),
+            ConfigError::StreamUnsupported => write!(f, "this config source can't stream"),
+            ConfigError::NoUpstream => write!(f, "no upstream URL configured; set CONFIG_UPSTREAM_URLS"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ConfigError {
+    // What most likely went wrong and what to check, for operators reading the startup self-check.
+    pub fn diagnosis(&self) -> String {
+        match self {
+            ConfigError::Request(e) if e.is_timeout() => {
+                format!("the upstream didn't answer in time; check it's up and that UPSTREAM_TIMEOUT_SECS is long enough ({})", self)
+            }
+            ConfigError::Request(e) if error_chain_mentions(e, "dns error") || error_chain_mentions(e, "failed to lookup address") => {
+                format!("the upstream host name doesn't resolve; check CONFIG_UPSTREAM_URLS for typos and that DNS works from here ({})", self)
+            }
+            ConfigError::Request(e) if connection_refused(e) => {
+                format!("nothing is listening at the upstream address; check the port in CONFIG_UPSTREAM_URLS and that the upstream is running ({})", self)
+            }
+            ConfigError::Request(e) if e.is_connect() => {
+                format!("couldn't connect to the upstream; check the network path and any TLS setup ({})", self)
+            }
+            ConfigError::Http(status) if *status == StatusCode::UNAUTHORIZED || *status == StatusCode::FORBIDDEN => {
+                format!("the upstream refused the request; check CONFIG_UPSTREAM_AUTH ({})", self)
+            }
+            ConfigError::Http(_) => format!("the upstream is reachable but answered with an error; check the URL path ({})", self),
+            ConfigError::Invalid(_) => format!("the upstream sent a config this service won't render; fix it at the upstream ({})", self),
+            ConfigError::Parse(_) | ConfigError::ParseYaml(_) | ConfigError::UnsupportedFormat(_) => {
+                format!("the upstream answered, but not with a usable config; check CONFIG_FORMAT and CONFIG_FIELD_MAPPING_FILE ({})", self)
+            }
+            _ => self.to_string(),
+        }
+    }
+}
+
+// Whether any error in `e`'s source chain says `needle`. reqwest doesn't expose DNS failures as a kind.
+fn error_chain_mentions(e: &ReqwestError, needle: &str) -> bool {
+    let mut source: Option<&dyn std::error::Error> = Some(e);
+    while let Some(error) = source {
+        if error.to_string().contains(needle) {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+fn connection_refused(e: &ReqwestError) -> bool {
+    let mut source: Option<&dyn std::error::Error> = Some(e);
+    while let Some(error) = source {
+        if error.downcast_ref::<std::io::Error>().is_some_and(|io| io.kind() == std::io::ErrorKind::ConnectionRefused) {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+impl From<ReqwestError> for ConfigError {
+    fn from(e: ReqwestError) -> Self {
+        ConfigError::Request(e)
+    }
+}
+
+impl From<serde_json::Error> for ConfigError {
+    fn from(e: serde_json::Error) -> Self {
+        ConfigError::Parse(e)
+    }
+}
+
+impl From<serde_yaml::Error> for ConfigError {
+    fn from(e: serde_yaml::Error) -> Self {
+        ConfigError::ParseYaml(e)
+    }
+}
+
+// How upstream payloads are encoded. TOML isn't supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadFormat {
+    Auto, // Go by each response's `Content-Type`
+    Json,
+    Yaml,
+}
+
+impl FromStr for PayloadFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(PayloadFormat::Auto),
+            "json" => Ok(PayloadFormat::Json),
+            "yaml" => Ok(PayloadFormat::Yaml),
+            "toml" => Err("TOML isn't supported; use json, yaml, or auto".to_string()),
+            other => Err(format!("unknown format {:?}", other)),
+        }
+    }
+}
+
+// How a fetched payload becomes the new config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateMode {
+    Replace, // The payload is a complete config
+    Merge,   // The payload may leave fields out; they keep their current values
+}
+
+impl FromStr for UpdateMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "replace" => Ok(UpdateMode::Replace),
+            "merge" => Ok(UpdateMode::Merge),
+            other => Err(format!("unknown mode {:?}", other)),
+        }
+    }
+}
+
+// How the upstream is followed for changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamMode {
+    Poll,     // Fetch every `POLL_INTERVAL_SECS`
+    LongPoll, // Fetch again as soon as a fetch is answered; the upstream holds each request until the config changes
+    Stream,   // Hold a `text/event-stream` response open and take each event's data as a config payload
+}
+
+impl FromStr for UpstreamMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "poll" => Ok(UpstreamMode::Poll),
+            "long_poll" => Ok(UpstreamMode::LongPoll),
+            "stream" => Ok(UpstreamMode::Stream),
+            other => Err(format!("unknown mode {:?}", other)),
+        }
+    }
+}
+
+impl PayloadFormat {
+    // The format a response's `Content-Type` names. Responses without one are taken to be JSON,
+    // as they always were.
+    fn from_content_type(content_type: Option<&str>) -> Result<Self, ConfigError> {
+        let Some(content_type) = content_type else {
+            return Ok(PayloadFormat::Json);
+        };
+        let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        match media_type.as_str() {
+            "application/json" => Ok(PayloadFormat::Json),
+            "application/yaml" | "application/x-yaml" | "text/yaml" | "text/x-yaml" => Ok(PayloadFormat::Yaml),
+            other if other.ends_with("+json") => Ok(PayloadFormat::Json),
+            other if other.ends_with("+yaml") => Ok(PayloadFormat::Yaml),
+            _ => Err(ConfigError::UnsupportedFormat(content_type.to_string())),
+        }
+    }
+
+    // Decode a payload into a JSON value, so field mappings apply the same way to every format.
+    fn decode(self, body: &[u8]) -> Result<Value, ConfigError> {
+        match self {
+            PayloadFormat::Auto | PayloadFormat::Json => Ok(serde_json::from_slice(body)?),
+            PayloadFormat::Yaml => Ok(serde_yaml::from_slice(body)?),
+        }
+    }
+}
+
+// Where configs come from. The poll loop only sees this trait, so the HTTP upstream can be
+// swapped for another source (or an in-memory one when exercising the loop without a network).
+#[async_trait]
+pub trait ConfigSource: Send + Sync {
+    // Fetch the current config. Returns `None` when the source reports it unchanged. `current` is
+    // the config in effect, for sources whose payloads only carry the fields that changed.
+    async fn fetch(&self, current: Option<&Config>) -> Result<Option<Config>, ConfigError>;
+
+    // Hold a connection to the upstream open and pass each config it pushes to `on_config`, until
+    // the upstream closes it (`Ok`), it fails, or `on_config` breaks. `current` is the config in
+    // effect when the stream opens. Sources that can only be polled fail with `StreamUnsupported`.
+    async fn stream(&self, _current: Option<Config>, _on_config: &mut (dyn FnMut(Config) -> ControlFlow<()> + Send)) -> Result<(), ConfigError> {
+        Err(ConfigError::StreamUnsupported)
+    }
+
+    // The last payload exactly as received, for debugging sources that send surprising configs.
+    fn last_raw(&self) -> Option<RawPayload> {
+        None
+    }
+
+    // How many seconds the upstream's clock was ahead of ours (negative when behind) when it last
+    // answered. `None` for sources that can't tell.
+    fn clock_skew(&self) -> Option<i64> {
+        None
+    }
+
+    // Point the source at a different upstream from the next fetch on. Returns `false` for
+    // sources that have no upstream URL.
+    fn set_upstream(&self, _url: String) -> bool {
+        false
+    }
+}
+
+// A payload as the upstream sent it, truncated to the configured debug limit.
+#[derive(Debug, Clone)]
+pub struct RawPayload {
+    pub content_type: Option<String>,
+    pub body: Bytes,
+}
+
+// Cache validators from the upstream's last successful response, sent back on the next poll
+// so an unchanged config comes back as a cheap 304.
+#[derive(Debug, Default)]
+pub struct UpstreamValidators {
+    pub url: Option<String>, // Upstream that issued the validators
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+// Polls one or more HTTP upstreams, starting with the one that last succeeded and failing over
+// to the rest in order.
+pub struct HttpSource {
+    client: reqwest::Client,
+    urls: RwLock<Vec<String>>,             // Read at the start of every fetch, so it can be swapped at runtime
+    preferred: AtomicUsize,                // Index of the upstream that last served the config
+    validators: Mutex<UpstreamValidators>,
+    field_mapping: FieldMapping,           // Renames applied to payloads before parsing
+    last_raw: Mutex<Option<RawPayload>>,
+    last_skew: Mutex<Option<i64>>,         // Seconds the last upstream `Date` header was ahead of our clock
+    raw_payload_limit: usize,              // Bytes of the last payload kept for debugging
+    max_body_bytes: usize,                 // Larger responses are rejected before parsing
+    format: PayloadFormat,                 // How payloads are encoded; `Auto` reads the content type
+    signing_secret: Option<String>,        // Payloads must be signed with this when set
+    update_mode: UpdateMode,               // Whether payloads replace the config or are merged onto it
+    stream_idle: Duration,                 // Longest a streamed response may go quiet; zero waits forever
+}
+
+impl HttpSource {
+    pub fn new(settings: &Settings, field_mapping: FieldMapping) -> Result<Self, ConfigError> {
+        if settings.upstream_urls.is_empty() {
+            return Err(ConfigError::NoUpstream);
+        }
+        let timeout = Duration::from_secs(settings.upstream_timeout_secs);
+        let idle = Duration::from_secs(settings.upstream_idle_timeout_secs);
+        let builder = reqwest::Client::builder().default_headers(auth_headers(settings)?);
+        // Held requests and streams outlive any ordinary timeout, so only connecting is bounded by it
+        let builder = match settings.upstream_mode {
+            UpstreamMode::Poll => builder.timeout(timeout),
+            UpstreamMode::LongPoll if !idle.is_zero() => builder.connect_timeout(timeout).timeout(idle),
+            UpstreamMode::LongPoll | UpstreamMode::Stream => builder.connect_timeout(timeout),
+        };
+        let client = builder.build()?;
+        Ok(Self {
+            client,
+            urls: RwLock::new(settings.upstream_urls.clone()),
+            preferred: AtomicUsize::new(0),
+            validators: Mutex::new(UpstreamValidators::default()),
+            field_mapping,
+            last_raw: Mutex::new(None),
+            last_skew: Mutex::new(None),
+            raw_payload_limit: settings.raw_payload_limit,
+            max_body_bytes: settings.upstream_max_body_bytes,
+            format: settings.config_format,
+            signing_secret: settings.upstream_signing_secret.clone(),
+            update_mode: settings.config_update_mode,
+            stream_idle: Duration::from_secs(settings.upstream_idle_timeout_secs),
+        })
+    }
+
+    // Fetch the config from a single upstream, sending `traceparent`. Returns `None` when the upstream
+    // reports it unchanged.
+    async fn fetch_upstream(&self, url: &str, current: Option<&Config>, traceparent: TraceParent) -> Result<Option<Config>, ConfigError> {
+        let mut request = self.client.get(url).header(TRACEPARENT_HEADER.as_str(), traceparent.to_string());
+        // Fetches triggered by an HTTP request (e.g. an admin refresh) carry its id upstream
+        if let Some(id) = current_request_id() {
+            request = request.header(REQUEST_ID_HEADER.as_str(), id);
+        }
+        {
+            // Validators are only meaningful to the upstream that issued them
+            let validators = self.validators.lock().unwrap();
+            if validators.url.as_deref() == Some(url) {
+                if let Some(etag) = &validators.etag {
+                    request = request.header(header::IF_NONE_MATCH, etag);
+                }
+                if let Some(last_modified) = &validators.last_modified {
+                    request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+                }
+            }
+        }
+        let resp = request.send().await?;
+        // Every answer carries a `Date`, `304`s and errors included
+        if let Some(skew) = date_skew(resp.headers()) {
+            *self.last_skew.lock().unwrap() = Some(skew);
+        }
+
+        // Nothing changed since the last poll: skip parsing and broadcasting
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            debug!("Upstream config not modified");
+            return Ok(None);
+        }
+
+        // Never parse error pages, even ones that happen to look like a config
+        if !resp.status().is_success() {
+            return Err(ConfigError::Http(resp.status()));
+        }
+        let etag = resp.headers().get(header::ETAG).and_then(|v| v.to_str().ok()).map(str::to_owned);
+        let last_modified = resp.headers().get(header::LAST_MODIFIED).and_then(|v| v.to_str().ok()).map(str::to_owned);
+        let content_type = resp.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).map(str::to_owned);
+        let signature = resp.headers().get(SIGNATURE_HEADER).and_then(|v| v.to_str().ok()).map(str::to_owned);
+        let body = self.read_body(resp).await?;
+        let config = self.parse_payload(body, content_type, signature.as_deref(), current)?;
+
+        *self.validators.lock().unwrap() = UpstreamValidators {
+            url: Some(url.to_string()),
+            etag,
+            last_modified,
+        };
+        Ok(Some(config))
+    }
+
+    // Turn a payload into a config: keep it for debugging, check its signature, decode it, apply
+    // the field mapping, and in merge mode merge it onto `current`.
+    fn parse_payload(&self, body: Bytes, content_type: Option<String>, signature: Option<&str>, current: Option<&Config>) -> Result<Config, ConfigError> {
+        let format = match self.format {
+            PayloadFormat::Auto => PayloadFormat::from_content_type(content_type.as_deref()),
+            format => Ok(format),
+        };
+        *self.last_raw.lock().unwrap() = Some(RawPayload {
+            content_type,
+            body: body.slice(..body.len().min(self.raw_payload_limit)),
+        });
+
+        // Check the signature over the exact bytes received, before anything is parsed
+        if let Some(secret) = &self.signing_secret {
+            let signature = signature.ok_or(ConfigError::BadSignature("missing signature"))?;
+            if !signature::verify(secret.as_bytes(), &body, signature) {
+                return Err(ConfigError::BadSignature("signature mismatch"));
+            }
+        }
+        let mut payload = format?.decode(&body)?;
+        if !self.field_mapping.is_empty() {
+            payload = self.field_mapping.apply(payload);
+        }
+        // Until there is a config to merge onto, even a merging upstream must send a complete one
+        Ok(match (self.update_mode, current) {
+            (UpdateMode::Merge, Some(current)) => serde_json::from_value::<PartialConfig>(payload)?.merge_onto(current),
+            _ => serde_json::from_value(payload)?,
+        })
+    }
+
+    // Open an event stream on a single upstream.
+    async fn open_stream(&self, url: &str) -> Result<reqwest::Response, ConfigError> {
+        let resp = self
+            .client
+            .get(url)
+            .header(header::ACCEPT, "text/event-stream")
+            .header(TRACEPARENT_HEADER.as_str(), outgoing_traceparent().to_string())
+            .send()
+            .await?;
+        if let Some(skew) = date_skew(resp.headers()) {
+            *self.last_skew.lock().unwrap() = Some(skew);
+        }
+        if !resp.status().is_success() {
+            return Err(ConfigError::Http(resp.status()));
+        }
+        Ok(resp)
+    }
+
+    // Read the response body, giving up as soon as it grows past `max_body_bytes` rather than
+    // buffering whatever a misbehaving upstream sends.
+    async fn read_body(&self, mut resp: reqwest::Response) -> Result<Bytes, ConfigError> {
+        if resp.content_length().is_some_and(|len| len > self.max_body_bytes as u64) {
+            return Err(ConfigError::BodyTooLarge(self.max_body_bytes));
+        }
+        let mut body = Vec::new();
+        while let Some(chunk) = resp.chunk().await? {
+            if body.len() + chunk.len() > self.max_body_bytes {
+                return Err(ConfigError::BodyTooLarge(self.max_body_bytes));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(Bytes::from(body))
+    }
+}
+
+// Splits a `text/event-stream` body into the data of its events. Only `data` fields are kept;
+// comments and the `event`, `id`, and `retry` fields are skipped.
+#[derive(Debug, Default)]
+struct EventStream {
+    line: Vec<u8>,        // The line being read, up to its newline
+    data: Option<String>, // `data` lines of the event being read, joined by newlines
+}
+
+impl EventStream {
+    // Read `chunk`, returning the data of every event it completes. Fails once an event grows
+    // past `max_bytes`.
+    fn feed(&mut self, chunk: &[u8], max_bytes: usize) -> Result<Vec<String>, ConfigError> {
+        let mut events = Vec::new();
+        for &byte in chunk {
+            if byte != b'\n' {
+                self.line.push(byte);
+                if self.line.len() + self.data.as_ref().map_or(0, String::len) > max_bytes {
+                    return Err(ConfigError::BodyTooLarge(max_bytes));
+                }
+                continue;
+            }
+            let line = String::from_utf8_lossy(&self.line).trim_end_matches('\r').to_string();
+            self.line.clear();
+            if line.is_empty() {
+                events.extend(self.data.take());
+                continue;
+            }
+            let (field, value) = line.split_once(':').unwrap_or((&line, ""));
+            if field == "data" {
+                let value = value.strip_prefix(' ').unwrap_or(value);
+                match &mut self.data {
+                    Some(data) => {
+                        data.push('\n');
+                        data.push_str(value);
+                    }
+                    None => self.data = Some(value.to_string()),
+                }
+            }
+        }
+        Ok(events)
+    }
+}
+
+// Seconds the response's `Date` header is ahead of our clock, negative when it's behind. `Date`
+// only has whole seconds, so up to a second of this is rounding.
+fn date_skew(headers: &HeaderMap) -> Option<i64> {
+    let date = headers.get(header::DATE)?.to_str().ok()?.parse::<HttpDate>().ok()?;
+    let skew = match SystemTime::from(date).duration_since(SystemTime::now()) {
+        Ok(ahead) => ahead.as_secs() as i64,
+        Err(behind) => -(behind.duration().as_secs() as i64),
+    };
+    Some(skew)
+}
+
+// The configured upstream auth header, if any. The value is marked sensitive so it's redacted
+// wherever reqwest formats the request.
+fn auth_headers(settings: &Settings) -> Result<HeaderMap, ConfigError> {
+    let mut headers = HeaderMap::new();
+    let Some(value) = &settings.upstream_auth else {
+        return Ok(headers);
+    };
+    let name = HeaderName::from_bytes(settings.upstream_auth_header.as_bytes())
+        .map_err(|_| ConfigError::InvalidAuthHeader(format!("bad header name {:?}", settings.upstream_auth_header)))?;
+    // Don't echo the value back: it's a secret
+    let mut value = HeaderValue::from_str(value).map_err(|_| ConfigError::InvalidAuthHeader(format!("bad value for {}", name)))?;
+    value.set_sensitive(true);
+    headers.insert(name, value);
+    Ok(headers)
+}
+
+#[async_trait]
+impl ConfigSource for HttpSource {
+    async fn fetch(&self, current: Option<&Config>) -> Result<Option<Config>, ConfigError> {
+        let urls = self.urls.read().unwrap().clone();
+        let preferred = self.preferred.load(Ordering::Relaxed);
+        let mut last_error = None;
+
+        for offset in 0..urls.len() {
+            let index = (preferred + offset) % urls.len();
+            let url = &urls[index];
+            // Continues the trace of the HTTP request that triggered the fetch, if any; polls start
+            // their own. Each attempt runs in a span carrying the `traceparent` it sends
+            let traceparent = outgoing_traceparent();
+            let span = tracing::info_span!("upstream_fetch", url = %url, traceparent = %traceparent);
+            match self.fetch_upstream(url, current, traceparent).instrument(span).await {
+                Ok(fetched) => {
+                    if index != preferred {
+                        info!("Switched to upstream {}", url);
+                        self.preferred.store(index, Ordering::Relaxed);
+                    }
+                    return Ok(fetched);
+                }
+                Err(e) => {
+                    warn!("Upstream {} failed: {}", url, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.expect("at least one upstream URL is configured"))
+    }
+
+    // Connects like `fetch` fails over, then stays on the upstream that answered. Each event's data
+    // is one payload; a payload that can't be parsed fails the stream, as it would fail a fetch.
+    async fn stream(&self, current: Option<Config>, on_config: &mut (dyn FnMut(Config) -> ControlFlow<()> + Send)) -> Result<(), ConfigError> {
+        let urls = self.urls.read().unwrap().clone();
+        let preferred = self.preferred.load(Ordering::Relaxed);
+        let mut connected = None;
+        for offset in 0..urls.len() {
+            let index = (preferred + offset) % urls.len();
+            match self.open_stream(&urls[index]).await {
+                Ok(resp) => {
+                    if index != preferred {
+                        info!("Switched to upstream {}", urls[index]);
+                        self.preferred.store(index, Ordering::Relaxed);
+                    }
+                    connected = Some(Ok(resp));
+                    break;
+                }
+                Err(e) => {
+                    warn!("Upstream {} failed: {}", urls[index], e);
+                    connected = Some(Err(e));
+                }
+            }
+        }
+        let mut resp = connected.expect("at least one upstream URL is configured")?;
+        debug!("Streaming configs from {}", resp.url());
+
+        let mut events = EventStream::default();
+        let mut current = current;
+        loop {
+            let chunk = if self.stream_idle.is_zero() {
+                resp.chunk().await?
+            } else {
+                tokio::time::timeout(self.stream_idle, resp.chunk()).await.map_err(|_| ConfigError::StreamIdle(self.stream_idle))??
+            };
+            let Some(chunk) = chunk else {
+                return Ok(());
+            };
+            for data in events.feed(&chunk, self.max_body_bytes)? {
+                // Events carry no headers, so they're decoded as JSON unless `CONFIG_FORMAT` says otherwise
+                let config = self.parse_payload(Bytes::from(data), None, None, current.as_ref())?;
+                current = Some(config.clone());
+                if on_config(config).is_break() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn last_raw(&self) -> Option<RawPayload> {
+        self.last_raw.lock().unwrap().clone()
+    }
+
+    fn clock_skew(&self) -> Option<i64> {
+        *self.last_skew.lock().unwrap()
+    }
+
+    // Replace the whole failover list with `url`. A fetch already in flight finishes against the
+    // old list; validators from the old upstream are never sent to the new one.
+    fn set_upstream(&self, url: String) -> bool {
+        *self.urls.write().unwrap() = vec![url];
+        self.preferred.store(0, Ordering::Relaxed);
+        true
+    }
+}
+
+// Serves whatever config was last set; handy for driving the poll loop without an upstream.
+#[derive(Debug, Default)]
+pub struct InMemorySource {
+    config: Mutex<Option<Config>>,
+}
+
+impl InMemorySource {
+    pub fn new(config: Config) -> Self {
+        Self { config: Mutex::new(Some(config)) }
+    }
+
+    pub fn set(&self, config: Config) {
+        *self.config.lock().unwrap() = Some(config);
+    }
+}
+
+#[async_trait]
+impl ConfigSource for InMemorySource {
+    async fn fetch(&self, _current: Option<&Config>) -> Result<Option<Config>, ConfigError> {
+        Ok(self.config.lock().unwrap().clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix::Actor;
+    use std::sync::Arc;
+
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+
+    use super::*;
+    use crate::admin::refresh_config;
+    use crate::appstate::AppState;
+    use crate::fetch::fetch_and_update_config;
+    use crate::trace::TraceContext;
+    use crate::websocket::WsManager;
+
+    // Serve `respond` on a free local port, returning the URL to fetch configs from.
+    fn mock_upstream<F>(respond: F) -> String
+    where
+        F: Fn(&HttpRequest) -> HttpResponse + Clone + Send + 'static,
+    {
+        let server = HttpServer::new(move || {
+            let respond = respond.clone();
+            App::new().default_service(web::to(move |req: HttpRequest| {
+                let res = respond(&req);
+                async move { res }
+            }))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}/config", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        url
+    }
+
+    // An `HttpSource` for `url`, with `configure` applied to the default settings.
+    fn http_source(url: String, configure: impl FnOnce(&mut Settings)) -> HttpSource {
+        let mut settings = Settings::from_env();
+        settings.upstream_urls = vec![url];
+        configure(&mut settings);
+        HttpSource::new(&settings, FieldMapping::default()).unwrap()
+    }
+
+    fn app_state(urls: Vec<String>) -> web::Data<AppState> {
+        let mut settings = Settings::from_env();
+        settings.upstream_urls = urls;
+        settings.admin_token = Some("secret".to_string());
+        let source = HttpSource::new(&settings, FieldMapping::default()).unwrap();
+        web::Data::new(AppState::new(settings, Box::new(source)))
+    }
+
+    #[test]
+    fn json_and_yaml_decode_to_the_same_config() {
+        let json = PayloadFormat::from_content_type(Some("application/json")).unwrap().decode(br#"{"ball_color":"red","ball_size":20}"#).unwrap();
+        let yaml = PayloadFormat::from_content_type(Some("text/yaml")).unwrap().decode(b"ball_color: red\nball_size: 20\n").unwrap();
+        assert_eq!(json, yaml);
+        assert!(matches!(PayloadFormat::from_content_type(Some("application/toml")), Err(ConfigError::UnsupportedFormat(_))));
+        assert!("toml".parse::<PayloadFormat>().is_err());
+    }
+
+    #[actix_web::test]
+    async fn correctly_signed_payload_is_accepted() {
+        let url = mock_upstream(|_| {
+            let body = serde_json::to_vec(&Config::default()).unwrap();
+            HttpResponse::Ok().insert_header((SIGNATURE_HEADER, signature::sign(b"secret", &body))).content_type("application/json").body(body)
+        });
+        let source = http_source(url, |settings| settings.upstream_signing_secret = Some("secret".to_string()));
+
+        assert_eq!(source.fetch(None).await.unwrap(), Some(Config::default()));
+    }
+
+    #[actix_web::test]
+    async fn tampered_payload_is_rejected() {
+        let url = mock_upstream(|_| {
+            let signed = serde_json::to_vec(&Config::default()).unwrap();
+            let sent = serde_json::to_vec(&Config::builder().ball_color("red").build()).unwrap();
+            HttpResponse::Ok().insert_header((SIGNATURE_HEADER, signature::sign(b"secret", &signed))).content_type("application/json").body(sent)
+        });
+        let source = http_source(url, |settings| settings.upstream_signing_secret = Some("secret".to_string()));
+
+        assert!(matches!(source.fetch(None).await, Err(ConfigError::BadSignature(_))));
+    }
+
+    #[actix_web::test]
+    async fn unsigned_payload_is_rejected_when_a_secret_is_set() {
+        let url = mock_upstream(|_| HttpResponse::Ok().json(Config::default()));
+        let source = http_source(url, |settings| settings.upstream_signing_secret = Some("secret".to_string()));
+
+        assert!(matches!(source.fetch(None).await, Err(ConfigError::BadSignature(_))));
+    }
+
+    #[actix_web::test]
+    async fn body_over_the_cap_is_rejected() {
+        let url = mock_upstream(|_| HttpResponse::Ok().json(Config::default()));
+        let source = http_source(url, |settings| settings.upstream_max_body_bytes = 16);
+
+        assert!(matches!(source.fetch(None).await, Err(ConfigError::BodyTooLarge(16))));
+    }
+
+    #[actix_web::test]
+    async fn error_statuses_fail_with_the_status() {
+        for status in [StatusCode::NOT_FOUND, StatusCode::INTERNAL_SERVER_ERROR] {
+            let url = mock_upstream(move |_| HttpResponse::build(status).body("<html>not a config</html>"));
+            let source = http_source(url, |_| ());
+
+            assert!(matches!(source.fetch(None).await, Err(ConfigError::Http(got)) if got == status));
+        }
+    }
+
+    #[actix_web::test]
+    async fn auth_header_reaches_the_upstream_marked_sensitive() {
+        let received = Arc::new(Mutex::new(None));
+        let url = mock_upstream({
+            let received = received.clone();
+            move |req| {
+                *received.lock().unwrap() = req.headers().get("x-api-key").and_then(|v| v.to_str().ok()).map(str::to_owned);
+                HttpResponse::Ok().json(Config::default())
+            }
+        });
+        let configure = |settings: &mut Settings| {
+            settings.upstream_auth_header = "X-API-Key".to_string();
+            settings.upstream_auth = Some("hunter2".to_string());
+        };
+        let mut settings = Settings::from_env();
+        configure(&mut settings);
+        assert!(auth_headers(&settings).unwrap()["x-api-key"].is_sensitive());
+
+        http_source(url, configure).fetch(None).await.unwrap();
+        assert_eq!(received.lock().unwrap().as_deref(), Some("hunter2"));
+    }
+
+    #[actix_web::test]
+    async fn not_modified_answer_is_not_broadcast() {
+        let url = mock_upstream(|req| match req.headers().get(header::IF_NONE_MATCH) {
+            Some(etag) if etag == "\"v1\"" => HttpResponse::NotModified().finish(),
+            _ => HttpResponse::Ok().insert_header((header::ETAG, "\"v1\"")).json(Config::default()),
+        });
+        let data = app_state(vec![url]);
+        let mut events = data.events.subscribe();
+
+        assert_eq!(fetch_and_update_config(data.clone()).await.unwrap(), Some(Config::default()));
+        assert!(events.try_recv().is_ok());
+        assert_eq!(fetch_and_update_config(data.clone()).await.unwrap(), None);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[actix_web::test]
+    async fn failing_upstream_fails_over_to_the_next() {
+        let failing = mock_upstream(|_| HttpResponse::InternalServerError().finish());
+        let working = mock_upstream(|_| HttpResponse::Ok().json(Config::builder().ball_color("red").build()));
+        let data = app_state(vec![failing, working]);
+
+        fetch_and_update_config(data.clone()).await.unwrap();
+        assert_eq!(data.config.read().unwrap().as_ref().map(|config| config.ball_color.as_str()), Some("red"));
+    }
+
+    #[actix_web::test]
+    async fn in_memory_source_drives_one_broadcast() {
+        let config = Config::builder().ball_color("red").build();
+        let data = web::Data::new(AppState::new(Settings::from_env(), Box::new(InMemorySource::new(config.clone()))));
+        let (tx, mut tap) = tokio::sync::mpsc::channel(4);
+        let _manager = WsManager::new().with_events(data.events.subscribe()).with_tap(tx).start();
+
+        fetch_and_update_config(data.clone()).await.unwrap();
+        assert_eq!(tap.recv().await.unwrap().config, config);
+    }
+
+    #[actix_web::test]
+    async fn refresh_continues_the_requests_trace_upstream() {
+        let received = Arc::new(Mutex::new(None));
+        let url = mock_upstream({
+            let received = received.clone();
+            move |req| {
+                *received.lock().unwrap() = req.headers().get(TRACEPARENT_HEADER.as_str()).and_then(|v| v.to_str().ok()).map(str::to_owned);
+                HttpResponse::Ok().json(Config::default())
+            }
+        });
+        let app = init_service(App::new().app_data(app_state(vec![url])).wrap(TraceContext).route("/refresh", web::post().to(refresh_config))).await;
+
+        let incoming = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let req = TestRequest::post()
+            .uri("/refresh")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .insert_header((TRACEPARENT_HEADER.as_str(), incoming))
+            .to_request();
+        assert!(call_service(&app, req).await.status().is_success());
+
+        let sent = received.lock().unwrap().clone().and_then(|value| TraceParent::parse(&value)).unwrap();
+        assert_eq!(sent.trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_ne!(sent, TraceParent::parse(incoming).unwrap());
+    }
+
+    #[test]
+    fn empty_upstream_list_is_an_error() {
+        let mut settings = Settings::from_env();
+        settings.upstream_urls.clear();
+        assert!(matches!(HttpSource::new(&settings, FieldMapping::default()), Err(ConfigError::NoUpstream)));
+    }
+}
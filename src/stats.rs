@@ -0,0 +1,90 @@
+use std::time::Duration;
+
+use actix::{Actor, ActorContext, ActorFutureExt, Addr, AsyncContext, StreamHandler, WrapFuture};
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web_actors::ws::{self, WebsocketContext};
+use log::{debug, error};
+use serde::Serialize;
+
+use crate::appstate::AppState;
+use crate::websocket::{SessionCount, WsError, WsManager};
+use crate::ApiError;
+
+// Periodic frame pushed to `/ws/stats` dashboards.
+#[derive(Debug, Serialize)]
+pub struct StatsFrame {
+    r#type: &'static str,
+    connected_clients: usize,     // Config sessions, not stats sessions
+    last_fetch: Option<u64>,      // Unix seconds of the last upstream fetch attempt
+    fetch_success_rate: Option<f64>,
+    upstream_healthy: bool,
+}
+
+// Pushes a `StatsFrame` every `interval`; inbound messages other than ping/close are ignored.
+pub struct StatsWs {
+    app_state: web::Data<AppState>,
+    ws_manager: Addr<WsManager>,
+    interval: Duration,
+}
+
+impl Actor for StatsWs {
+    type Context = WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.send_stats(ctx);
+        ctx.run_interval(self.interval, |act, ctx| act.send_stats(ctx));
+    }
+}
+
+impl StatsWs {
+    fn send_stats(&self, ctx: &mut WebsocketContext<Self>) {
+        let request = self.ws_manager.send(SessionCount).into_actor(self).map(|result, act, ctx| match result {
+            Ok(connected_clients) => {
+                let upstream = &act.app_state.upstream;
+                let frame = StatsFrame {
+                    r#type: "stats",
+                    connected_clients,
+                    last_fetch: upstream.last_fetch(),
+                    fetch_success_rate: upstream.success_rate(),
+                    upstream_healthy: upstream.is_healthy(),
+                };
+                match serde_json::to_string(&frame) {
+                    Ok(json) => ctx.text(json),
+                    Err(e) => error!("Failed to serialize stats: {}", e),
+                }
+            }
+            Err(e) => error!("Failed to query session count: {}", e),
+        });
+        ctx.spawn(request);
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for StatsWs {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(bytes)) => ctx.pong(&bytes),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Err(e) => {
+                error!("Stats WebSocket protocol error: {:?}", e);
+                ctx.stop();
+            }
+            _ => (),
+        }
+    }
+}
+
+pub async fn stats_ws(req: HttpRequest, stream: web::Payload, data: web::Data<AppState>, ws_manager: web::Data<Addr<WsManager>>) -> Result<HttpResponse, ApiError> {
+    debug!("Starting stats WebSocket session for request: {:?}", req);
+    let actor = StatsWs {
+        interval: Duration::from_secs(data.settings.stats_interval_secs.max(1)),
+        app_state: data.clone(),
+        ws_manager: ws_manager.get_ref().clone(),
+    };
+    ws::WsResponseBuilder::new(actor, &req, stream)
+        .frame_size(data.settings.ws_max_frame_size)
+        .start()
+        .map_err(|e| WsError::start_failed(e).into())
+}
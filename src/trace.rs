@@ -0,0 +1,133 @@
+use std::fmt;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::HeaderName;
+use rand::Rng;
+
+// W3C trace context header, e.g. `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+pub const TRACEPARENT_HEADER: HeaderName = HeaderName::from_static("traceparent");
+
+const SAMPLED: u8 = 0x01; // Flags on traces started here, so the upstream records them
+
+// A position in a distributed trace: the trace it belongs to and the span that made the call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceParent {
+    trace_id: u128,
+    parent_id: u64,
+    flags: u8,
+}
+
+impl TraceParent {
+    // Start a new trace, for work nothing upstream of us is tracing, like the poll loop.
+    pub fn root() -> Self {
+        Self { trace_id: rand::thread_rng().gen::<u128>().max(1), parent_id: new_span_id(), flags: SAMPLED }
+    }
+
+    // A new span in the same trace, for a call made on this one's behalf.
+    pub fn child(&self) -> Self {
+        Self { parent_id: new_span_id(), ..*self }
+    }
+
+    pub fn trace_id(&self) -> String {
+        format!("{:032x}", self.trace_id)
+    }
+
+    // Parse a `traceparent` value. Versions after `00` may add fields, which are ignored; an id
+    // of all zeros is invalid, as is version `ff`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut fields = value.trim().split('-');
+        let version = fields.next().filter(|field| is_hex(field, 2) && *field != "ff")?;
+        let trace_id = fields.next().filter(|field| is_hex(field, 32))?;
+        let parent_id = fields.next().filter(|field| is_hex(field, 16))?;
+        let flags = fields.next().filter(|field| is_hex(field, 2))?;
+        if version == "00" && fields.next().is_some() {
+            return None;
+        }
+        let parent = Self {
+            trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+            parent_id: u64::from_str_radix(parent_id, 16).ok()?,
+            flags: u8::from_str_radix(flags, 16).ok()?,
+        };
+        (parent.trace_id != 0 && parent.parent_id != 0).then_some(parent)
+    }
+}
+
+// Always written as version `00`, the only one defined.
+impl fmt::Display for TraceParent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "00-{:032x}-{:016x}-{:02x}", self.trace_id, self.parent_id, self.flags)
+    }
+}
+
+fn new_span_id() -> u64 {
+    rand::thread_rng().gen::<u64>().max(1)
+}
+
+// Lowercase hex only, as the spec requires.
+fn is_hex(field: &str, len: usize) -> bool {
+    field.len() == len && field.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+tokio::task_local! {
+    // Trace context of the request being handled, for code (like the upstream fetch) that has no `HttpRequest`.
+    static CURRENT_TRACE: Option<TraceParent>;
+}
+
+// The trace context of the request being handled on this task, if it carried one.
+pub fn current_trace() -> Option<TraceParent> {
+    CURRENT_TRACE.try_with(|trace| *trace).ok().flatten()
+}
+
+// The `traceparent` to send with an outgoing call: a child of the current request's trace, or a
+// new trace when there's none.
+pub fn outgoing_traceparent() -> TraceParent {
+    current_trace().map_or_else(TraceParent::root, |trace| trace.child())
+}
+
+// Middleware keeping a request's valid `traceparent`, so fetches it triggers (e.g. an admin
+// refresh) continue its trace. Handlers read it with `current_trace`.
+pub struct TraceContext;
+
+impl<S, B> Transform<S, ServiceRequest> for TraceContext
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = TraceContextMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TraceContextMiddleware { service: Rc::new(service) }))
+    }
+}
+
+pub struct TraceContextMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for TraceContextMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // A malformed header is dropped rather than passed on, as the spec asks
+        let trace = req.headers().get(&TRACEPARENT_HEADER).and_then(|v| v.to_str().ok()).and_then(TraceParent::parse);
+
+        let service = self.service.clone();
+        let fut = CURRENT_TRACE.sync_scope(trace, || service.call(req));
+        Box::pin(CURRENT_TRACE.scope(trace, fut))
+    }
+}
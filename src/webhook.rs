@@ -0,0 +1,53 @@
+use log::{debug, error, warn};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{sleep, Duration};
+
+use crate::events::{ChangeSource, ConfigChanged};
+use crate::Config;
+
+// Header telling the receiver what triggered the change, e.g. `poll`, as in WebSocket patches.
+pub const SOURCE_HEADER: &str = "x-config-source";
+
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500); // First retry delay, doubled on every failed attempt
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);     // Upper bound for the retry delay
+
+// POST every config change announced on `events` to `url`, for consumers that aren't WebSocket
+// clients, trying each up to `max_attempts` times. Deliveries run in order on a background task,
+// so a slow or failing receiver never holds up the poll loop. Must be called from within a Tokio
+// runtime.
+pub fn start(url: String, timeout: Duration, max_attempts: u32, events: broadcast::Receiver<ConfigChanged>) -> Result<(), reqwest::Error> {
+    let client = reqwest::Client::builder().timeout(timeout).build()?;
+    tokio::spawn(deliver_all(client, url, max_attempts.max(1), events));
+    Ok(())
+}
+
+async fn deliver_all(client: reqwest::Client, url: String, max_attempts: u32, mut events: broadcast::Receiver<ConfigChanged>) {
+    loop {
+        match events.recv().await {
+            Ok(ConfigChanged { config, source, .. }) => deliver(&client, &url, max_attempts, &config, source).await,
+            // Retries held the queue up for too long; the oldest changes are gone
+            Err(RecvError::Lagged(skipped)) => warn!("Webhook {} fell behind and dropped {} config changes", url, skipped),
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+// POST `config` to `url`, retrying with exponential backoff until it's accepted with a 2xx.
+async fn deliver(client: &reqwest::Client, url: &str, max_attempts: u32, config: &Config, source: ChangeSource) {
+    let mut delay = RETRY_BASE_DELAY;
+    for attempt in 1..=max_attempts {
+        match client.post(url).header(SOURCE_HEADER, source.label()).json(config).send().await {
+            Ok(resp) if resp.status().is_success() => {
+                debug!("Webhook {} accepted the config change", url);
+                return;
+            }
+            Ok(resp) => warn!("Webhook {} returned {} (attempt {}/{})", url, resp.status(), attempt, max_attempts),
+            Err(e) => warn!("Webhook {} failed: {} (attempt {}/{})", url, e, attempt, max_attempts),
+        }
+        if attempt < max_attempts {
+            sleep(delay).await;
+            delay = (delay * 2).min(RETRY_MAX_DELAY);
+        }
+    }
+    error!("Giving up on delivering a config change to webhook {} after {} attempts", url, max_attempts);
+}
@@ -1,37 +1,538 @@
-
-use actix::{Actor, Addr, AsyncContext, Context, Handler, Message, Running, StreamHandler};
+use actix::dev::{Request, SendError};
+use actix::{Actor, ActorContext, ActorState, ActorFutureExt, Addr, AsyncContext, Context, Handler, MailboxError, Message, Running, SpawnHandle, StreamHandler, WrapFuture};
+use actix_http::ws::Item;
 use actix_web_actors::ws::{self, WebsocketContext};
-use log::{debug, info, error};
+use log::{debug, info, error, warn};
 use serde::{Serialize, Deserialize};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, RwLock};
+use actix_web::http::header;
 use actix_web::{web, HttpRequest, HttpResponse};
-use std::collections::HashSet;
+use serde_json::{Map, Value};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, mpsc, OwnedSemaphorePermit};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+
+use crate::events::{ChangeSource, ConfigChanged};
+use crate::history::{ConfigHistory, HistoryMode};
+use crate::metrics::{DeliveryFailure, Metrics, NoopMetrics, WS_DELIVERY_FAILURES_TOTAL, WS_PONG_RTT_SECONDS, WS_SESSION_DURATION_SECONDS, WS_UPGRADES_REJECTED_TOTAL};
+use crate::protocol::{ClientMessage, HelloRequest, ServerMessage};
+use crate::config::truncate_for_log;
+use crate::{
+    appstate::{read_lock, AppState},
+    ApiError, Config,
+};
+
+// Why a WebSocket operation failed.
+#[derive(Debug)]
+pub enum WsError {
+    Serialize(serde_json::Error),     // A message couldn't be turned into a text frame
+    SessionNotFound(SessionId),       // No session with this id is registered with the manager
+    Full(SessionId),                  // The session's mailbox is at capacity
+    Closed(SessionId),                // The session's actor had already stopped
+    OriginNotAllowed(String),         // The handshake's `Origin` isn't in `WS_ALLOWED_ORIGINS`
+    UnsupportedProtocol(Vec<String>), // The client only asked for subprotocols we don't speak
+    Handshake(ws::HandshakeError),    // The request isn't a valid WebSocket upgrade
+    Start(String),                    // Starting the session failed for another reason
+    NotReady,                         // No config has been loaded yet and `WS_UNAVAILABLE_MODE` is `refuse`
+    UnknownProfile(String),           // `/ws/?profile=` names a profile that isn't being followed
+}
+
+impl fmt::Display for WsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WsError::Serialize(e) => write!(f, "failed to serialize message: {}", e),
+            WsError::SessionNotFound(id) => write!(f, "session {} not found", id),
+            WsError::Full(id) => write!(f, "session {} mailbox is full", id),
+            WsError::Closed(id) => write!(f, "session {} is closed", id),
+            WsError::OriginNotAllowed(origin) => write!(f, "origin {:?} not allowed", origin),
+            WsError::UnsupportedProtocol(requested) => {
+                write!(f, "unsupported WebSocket subprotocol {:?}; supported: {:?}", requested, SUPPORTED_PROTOCOLS)
+            }
+            WsError::Handshake(e) => write!(f, "WebSocket handshake failed: {}", e),
+            WsError::Start(reason) => write!(f, "failed to start WebSocket session: {}", reason),
+            WsError::NotReady => write!(f, "no config loaded yet"),
+            WsError::UnknownProfile(name) => write!(f, "unknown profile {:?}", name),
+        }
+    }
+}
+
+impl std::error::Error for WsError {}
+
+impl From<serde_json::Error> for WsError {
+    fn from(e: serde_json::Error) -> Self {
+        WsError::Serialize(e)
+    }
+}
+
+impl WsError {
+    // Why `WsResponseBuilder::start` failed, logged at a level to match whose fault it is.
+    pub fn start_failed(e: actix_web::Error) -> Self {
+        let e = match e.as_error::<ws::HandshakeError>() {
+            Some(&handshake) => WsError::Handshake(handshake),
+            None => WsError::Start(e.to_string()),
+        };
+        match e {
+            WsError::Handshake(_) => warn!("Rejected WebSocket upgrade: {}", e),
+            _ => error!("Error starting WebSocket session: {}", e),
+        }
+        e
+    }
 
-use crate::{appstate::AppState, Config};
+    // How the failure is counted in `ws_delivery_failures_total`, if it's a delivery failure at all.
+    fn delivery_failure(&self) -> Option<DeliveryFailure> {
+        match self {
+            WsError::Full(_) => Some(DeliveryFailure::Full),
+            WsError::Closed(_) | WsError::SessionNotFound(_) => Some(DeliveryFailure::Closed),
+            _ => None,
+        }
+    }
+}
+
+// Handshake failures are the client's fault; anything else that reaches a handler is ours.
+impl From<WsError> for ApiError {
+    fn from(e: WsError) -> Self {
+        match e {
+            WsError::OriginNotAllowed(origin) => ApiError::OriginNotAllowed(origin),
+            WsError::UnsupportedProtocol(requested) => ApiError::BadRequest(format!(
+                "Unsupported WebSocket subprotocol {:?}; supported: {:?}",
+                requested, SUPPORTED_PROTOCOLS
+            )),
+            WsError::NotReady => ApiError::ConfigUnavailable,
+            WsError::UnknownProfile(name) => ApiError::NotFound(format!("No profile named {:?}.", name)),
+            WsError::Handshake(ws::HandshakeError::NoWebsocketUpgrade) => {
+                ApiError::UpgradeRequired("This is a WebSocket endpoint; send a WebSocket upgrade request.".to_string())
+            }
+            WsError::Handshake(e) => ApiError::BadRequest(format!("Malformed WebSocket upgrade request: {}.", e)),
+            _ => ApiError::Internal("Failed to start WebSocket session.".to_string()),
+        }
+    }
+}
+
+pub type WsResult<T> = Result<T, WsError>;
 
 trait WsMessage {
-    fn as_text(&self) -> String;
+    // The message as a text frame.
+    fn to_text(&self) -> WsResult<String>;
+
+    // The message as a text frame, or `None` (after logging why) if it can't be serialized.
+    fn as_text(&self) -> Option<String> {
+        self.to_text().map_err(|e| error!("{}, skipping it", e)).ok()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Message)]
 #[rtype(result = "()")]
 pub struct GenericWsMessage {
     pub config: Config,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<ChangeSource>, // What changed the config, passed on to clients with the patch
 }
 
 impl WsMessage for GenericWsMessage {
-    fn as_text(&self) -> String {
-        serde_json::to_string(self).unwrap()
+    fn to_text(&self) -> WsResult<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+impl WsMessage for ServerMessage {
+    fn to_text(&self) -> WsResult<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+// Ask the manager `msg` from a request handler, giving up after `timeout` so a stuck or overloaded
+// manager answers 503 rather than hanging the request. Zero waits indefinitely. `what` names the
+// request in errors, e.g. "list sessions".
+pub async fn ask_manager<M>(manager: &Addr<WsManager>, msg: M, timeout: Duration, what: &str) -> Result<M::Result, ApiError>
+where
+    M: Message + Send + 'static,
+    M::Result: Send,
+    WsManager: Handler<M>,
+{
+    let request = manager.send(msg);
+    let result = if timeout.is_zero() { request.await } else { request.timeout(timeout).await };
+    result.map_err(|e| match e {
+        MailboxError::Timeout => {
+            warn!("WebSocket manager didn't answer within {:?} to {}", timeout, what);
+            ApiError::Timeout(format!("Timed out trying to {}; retry shortly.", what))
+        }
+        MailboxError::Closed => ApiError::Internal(format!("Failed to {}: {}", what, e)),
+    })
+}
+
+// Config updates fanned out to every session.
+pub type ConfigBroadcaster = broadcast::Sender<Arc<Broadcast>>;
+
+// A config update as sessions receive it: the frame to forward as-is, and the fields it carries,
+// worked out once by the manager so sessions can filter and dedupe it without parsing the frame.
+#[derive(Debug)]
+pub struct Broadcast {
+    pub frame: Arc<str>,
+    pub fields: Map<String, Value>, // The whole config, or for a patch only the changed fields (`null` when cleared)
+    pub patch: bool,
+}
+
+impl Broadcast {
+    // `config` whole rather than as a patch, or `None` (after logging why) if it can't be serialized.
+    pub fn full(config: Config) -> Option<Self> {
+        let fields = match serde_json::to_value(&config) {
+            Ok(Value::Object(fields)) => fields,
+            _ => Map::new(),
+        };
+        let frame = ServerMessage::Config(config).as_text()?;
+        Some(Broadcast { frame: frame.into(), fields, patch: false })
     }
 }
 
+// Default for how many updates a slow session may fall behind before the oldest are dropped.
+pub const DEFAULT_BROADCAST_CAPACITY: usize = 16;
+
+// Default for how many characters of a config are written to the logs.
+pub const DEFAULT_LOG_LIMIT: usize = 512;
+
+// Subprotocols clients may request via `Sec-WebSocket-Protocol`, in order of preference.
+pub const SUPPORTED_PROTOCOLS: &[&str] = &["config.v1"];
+
+// Protocol version and message formats negotiated by the optional `hello` exchange.
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const LEGACY_PROTOCOL_VERSION: u32 = 0; // What clients that never say hello are taken to speak
+// How long sessions wait for a hello when a minimum version is set but `WS_HELLO_WINDOW_MS` isn't,
+// so clients get a chance to say it before they're taken for legacy ones
+const MIN_VERSION_HELLO_WINDOW: Duration = Duration::from_secs(1);
+pub const SUPPORTED_FORMATS: &[&str] = &["json"];
+
+// Identifies a WebSocket session in logs and in the manager's session map.
+pub type SessionId = u64;
+
+// How often the manager checks its session map for actors that stopped without disconnecting.
+const REAP_INTERVAL: Duration = Duration::from_secs(30);
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+// A connected client as the manager tracks it.
+struct Session {
+    addr: Addr<ConfigWs>,
+    info: SessionInfo,
+}
+
+// What's known about a session from its handshake, as listed by `GET /admin/sessions`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: SessionId,
+    pub connected_at: u64,         // Unix seconds of the upgrade
+    pub remote_ip: Option<IpAddr>, // Peer address of the connection; a proxy's if there is one
+    pub tag: Option<String>,       // Group from `/ws/?tag=...`, for targeted broadcasts
+    pub protocol: Option<String>,  // Subprotocol agreed in the handshake, if the client asked for one
+    pub profile: Option<String>,   // Profile from `/ws/?profile=...` whose config the session follows instead of the shared one
+}
+
 pub struct WsManager {
-    sessions: HashSet<Addr<ConfigWs>>,
+    sessions: HashMap<SessionId, Session>,
+    broadcaster: ConfigBroadcaster,
+    last_broadcast: Option<Config>, // Baseline the next broadcast is diffed against
+    paused: bool,                   // Set by the admin API to hold broadcasts during a rollout
+    held: Option<GenericWsMessage>, // Latest config received while paused
+    tap: Option<mpsc::Sender<GenericWsMessage>>, // Observer sent a copy of each broadcast, for tests
+    history: ConfigHistory,         // Most recent broadcasts, oldest first, replayed to new sessions
+    metrics: Arc<dyn Metrics>,      // Where direct sends that didn't reach their session are counted
+    events: Option<broadcast::Receiver<ConfigChanged>>, // Committed config changes to broadcast, until the actor starts
+    min_interval: Duration,         // Least time between two broadcasts; zero sends every change right away
+    last_sent: Option<Instant>,     // When the last broadcast went out
+    throttled: Option<GenericWsMessage>, // Latest config that arrived within `min_interval` of the last broadcast
+    flush_scheduled: bool,          // Whether a timer will broadcast `throttled`
+    log_limit: usize,               // Characters of a config written to the logs; 0 writes it whole
+    peer_watchers: HashSet<SessionId>, // Sessions sent `peer_count` whenever the number of sessions changes
+    throttled_fields: HashSet<String>, // Fields whose changes are broadcast at most once per `field_interval`
+    field_interval: Duration,       // Least time between two broadcasts changing the same throttled field
+    field_sent_at: HashMap<String, Instant>, // When each throttled field last went out
+    field_pending: Option<GenericWsMessage>, // Latest config with throttled changes still held back
+    field_flush_scheduled: bool,    // Whether a timer will broadcast `field_pending`
 }
 
 impl WsManager {
     pub fn new() -> Self {
-        Self { sessions: HashSet::new() }
+        Self::with_capacity(DEFAULT_BROADCAST_CAPACITY)
+    }
+
+    // `capacity` bounds each session's backlog of unsent updates; past it the oldest are dropped.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let (broadcaster, _) = broadcast::channel(capacity.max(1));
+        Self {
+            sessions: HashMap::new(),
+            broadcaster,
+            last_broadcast: None,
+            paused: false,
+            held: None,
+            tap: None,
+            history: ConfigHistory::new(HistoryMode::Full, 0),
+            metrics: Arc::new(NoopMetrics),
+            events: None,
+            min_interval: Duration::ZERO,
+            last_sent: None,
+            throttled: None,
+            flush_scheduled: false,
+            log_limit: DEFAULT_LOG_LIMIT,
+            peer_watchers: HashSet::new(),
+            throttled_fields: HashSet::new(),
+            field_interval: Duration::ZERO,
+            field_sent_at: HashMap::new(),
+            field_pending: None,
+            field_flush_scheduled: false,
+        }
+    }
+
+    // Broadcast every config change announced on `events`, e.g. `AppState::events.subscribe()`.
+    pub fn with_events(mut self, events: broadcast::Receiver<ConfigChanged>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    // Report failed deliveries to `metrics`; by default they're only logged.
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    // Replay up to `size` of the latest broadcasts to each new session, so it can animate
+    // the recent changes. 0 disables replay.
+    pub fn with_replay(mut self, size: usize) -> Self {
+        self.history = ConfigHistory::new(HistoryMode::Full, size);
+        self
+    }
+
+    // Keep the replay history as `mode`, e.g. `HistoryMode::Diff` to store only what changed
+    // between broadcasts. Full by default.
+    pub fn with_history_mode(mut self, mode: HistoryMode) -> Self {
+        self.history = ConfigHistory::new(mode, self.history.capacity());
+        self
+    }
+
+    // Space broadcasts at least `interval` apart. Changes that arrive sooner are held, and only
+    // the latest is sent once the interval has passed. Unlike a debounce, a steady stream of
+    // changes still goes out at this rate.
+    pub fn with_min_interval(mut self, interval: Duration) -> Self {
+        self.min_interval = interval;
+        self
+    }
+
+    // Broadcast changes to `fields` at most once per `interval`, for fields that are expensive for
+    // clients to apply, like `number_of_balls`. Changes to other fields still go out right away,
+    // and a held change joins the first broadcast after its interval has passed.
+    pub fn with_field_throttle(mut self, fields: Vec<String>, interval: Duration) -> Self {
+        self.throttled_fields = fields.into_iter().collect();
+        self.field_interval = interval;
+        self
+    }
+
+    // Cut configs written to the debug log after `limit` characters; 0 logs them whole.
+    pub fn with_log_limit(mut self, limit: usize) -> Self {
+        self.log_limit = limit;
+        self
+    }
+
+    // Also send every config that is actually broadcast to `tap`, so tests can observe the fan-out
+    // (after dedup, diffing, and pauses) without a WebSocket client.
+    pub fn with_tap(mut self, tap: mpsc::Sender<GenericWsMessage>) -> Self {
+        self.tap = Some(tap);
+        self
+    }
+
+    // Handle sessions subscribe through; grab it before starting the actor.
+    pub fn broadcaster(&self) -> ConfigBroadcaster {
+        self.broadcaster.clone()
+    }
+
+    // Remove sessions whose actor has stopped, e.g. after a panic skipped its `Disconnect`.
+    fn reap_closed_sessions(&mut self) -> usize {
+        let before = self.sessions.len();
+        self.sessions.retain(|id, session| {
+            let connected = session.addr.connected();
+            if !connected {
+                warn!("Pruning closed session {}", id);
+            }
+            connected
+        });
+        let reaped = before - self.sessions.len();
+        if reaped > 0 {
+            self.peer_count_changed();
+        }
+        reaped
+    }
+
+    // Broadcast `msg` unless the last broadcast was less than `min_interval` ago, in which case
+    // it replaces any config already waiting and goes out when the interval has passed.
+    fn broadcast_throttled(&mut self, msg: GenericWsMessage, ctx: &mut Context<Self>) {
+        let wait = match self.last_sent {
+            Some(sent) => self.min_interval.saturating_sub(sent.elapsed()),
+            None => Duration::ZERO,
+        };
+        if wait.is_zero() {
+            let msg = self.hold_throttled_fields(msg, ctx);
+            if self.broadcast(msg) {
+                self.last_sent = Some(Instant::now());
+            }
+            return;
+        }
+        debug!("Holding config for {:?} to respect the minimum broadcast interval", wait);
+        self.throttled = Some(msg);
+        if !self.flush_scheduled {
+            self.flush_scheduled = true;
+            ctx.run_later(wait, |act, ctx| {
+                act.flush_scheduled = false;
+                let Some(msg) = act.throttled.take() else {
+                    return;
+                };
+                // Pausing in the meantime takes precedence; the config waits for the resume instead
+                if act.paused {
+                    act.held = Some(msg);
+                } else {
+                    act.broadcast_throttled(msg, ctx);
+                }
+            });
+        }
+    }
+
+    // `msg` without the changes to throttled fields that went out less than `field_interval` ago:
+    // those keep the value last broadcast, and the whole config is broadcast again once the
+    // earliest of them may go out.
+    fn hold_throttled_fields(&mut self, msg: GenericWsMessage, ctx: &mut Context<Self>) -> GenericWsMessage {
+        if self.throttled_fields.is_empty() || self.field_interval.is_zero() {
+            return msg;
+        }
+        // A newer config supersedes whatever was held
+        self.field_pending = None;
+        let now = Instant::now();
+        let Some(previous) = self.last_broadcast.clone() else {
+            for field in &self.throttled_fields {
+                self.field_sent_at.insert(field.clone(), now);
+            }
+            return msg;
+        };
+
+        let mut held: Vec<String> = Vec::new();
+        let mut wait = self.field_interval;
+        for (field, _) in previous.diff(&msg.config).into_iter().filter(|(field, _)| self.throttled_fields.contains(field)) {
+            let remaining = self.field_sent_at.get(&field).map_or(Duration::ZERO, |sent| self.field_interval.saturating_sub(now.duration_since(*sent)));
+            if remaining.is_zero() {
+                self.field_sent_at.insert(field, now);
+            } else {
+                wait = wait.min(remaining);
+                held.push(field);
+            }
+        }
+        if held.is_empty() {
+            return msg;
+        }
+
+        debug!("Holding changes to {:?} for {:?} to respect the field throttle", held, wait);
+        let config = msg.config.with_fields_from(&previous, &held);
+        self.field_pending = Some(msg.clone());
+        if !self.field_flush_scheduled {
+            self.field_flush_scheduled = true;
+            ctx.run_later(wait, |act, ctx| {
+                act.field_flush_scheduled = false;
+                let Some(msg) = act.field_pending.take() else {
+                    return;
+                };
+                // Pausing in the meantime takes precedence, unless a newer config is already held
+                if act.paused {
+                    act.held.get_or_insert(msg);
+                } else {
+                    act.broadcast_throttled(msg, ctx);
+                }
+            });
+        }
+        GenericWsMessage { config, source: msg.source }
+    }
+
+    // Send the config to every session, as a patch against the previous broadcast when there is one.
+    // Returns whether anything was sent.
+    fn broadcast(&mut self, msg: GenericWsMessage) -> bool {
+        let GenericWsMessage { config, source } = msg;
+        // Drop sessions whose actor already stopped without its `Disconnect` arriving yet
+        self.reap_closed_sessions();
+        // Serialize and diff once here; every session forwards the same shared frame, filtering
+        // on its fields. After the first broadcast only the changed fields are sent, as
+        // `{"type":"patch","changes":{...}}`
+        let (message, patch) = match &self.last_broadcast {
+            Some(previous) => {
+                let changes = previous.diff(&config);
+                if changes.is_empty() {
+                    debug!("Config unchanged, skipping broadcast");
+                    return false;
+                }
+                (ServerMessage::Patch { changes, source }, true)
+            }
+            None => (ServerMessage::Config(config.clone()), false),
+        };
+        let Some(config_json) = message.as_text() else {
+            return false;
+        };
+        let fields = match message {
+            ServerMessage::Patch { changes, .. } => changes,
+            _ => match serde_json::to_value(&config) {
+                Ok(Value::Object(fields)) => fields,
+                _ => Map::new(),
+            },
+        };
+        let update = Arc::new(Broadcast { frame: config_json.into(), fields, patch });
+        if let Some(tap) = &self.tap {
+            if let Err(e) = tap.try_send(GenericWsMessage { config: config.clone(), source }) {
+                warn!("Broadcast tap dropped a message: {}", e);
+            }
+        }
+        // A restored history may already end with this config
+        if self.history.newest() != Some(&config) {
+            self.history.push(config.clone());
+        }
+        self.last_broadcast = Some(config);
+        if self.broadcaster.send(update).is_err() {
+            debug!("No sessions subscribed, broadcast dropped");
+        }
+        true
+    }
+}
+
+impl WsManager {
+    // Write a frame straight to a registered session's mailbox, counting and logging it against
+    // the session if it can't be delivered.
+    fn send_to(&self, id: SessionId, frame: Arc<str>) -> WsResult<()> {
+        let result = match self.sessions.get(&id) {
+            Some(session) => session.addr.try_send(SendConfig(frame)).map_err(|e| match e {
+                SendError::Full(_) => WsError::Full(id),
+                SendError::Closed(_) => WsError::Closed(id),
+            }),
+            None => Err(WsError::SessionNotFound(id)),
+        };
+        if let Err(e) = &result {
+            warn!("Failed to deliver: {}", e);
+            if let Some(reason) = e.delivery_failure() {
+                self.metrics.increment_counter(WS_DELIVERY_FAILURES_TOTAL, &[("reason", reason.label())]);
+            }
+        }
+        result
+    }
+
+    // Tell every session watching the peer count how many sessions there are now, forgetting
+    // watchers that are gone.
+    fn peer_count_changed(&mut self) {
+        let sessions = &self.sessions;
+        self.peer_watchers.retain(|id| sessions.contains_key(id));
+        let Some(frame) = (ServerMessage::PeerCount { count: self.sessions.len() }).as_text() else {
+            return;
+        };
+        let frame: Arc<str> = frame.into();
+        for &id in &self.peer_watchers {
+            let _ = self.send_to(id, frame.clone());
+        }
     }
 }
 
@@ -45,24 +546,136 @@ impl Default for WsManager {
 #[rtype(result = "()")]
 pub struct Connect {
     pub addr: Addr<ConfigWs>,
+    pub info: SessionInfo,
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Disconnect {
-    pub addr: Addr<ConfigWs>,
+    pub id: SessionId,
+}
+
+// Hold broadcasts until `ResumeBroadcasts`, keeping only the latest config.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PauseBroadcasts;
+
+// Resume broadcasting; replies whether a held config was flushed.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct ResumeBroadcasts;
+
+// Close every session with a "going away" frame, e.g. before shutting down. Replies how many were closed.
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct CloseAll;
+
+// Send `config` in full to the sessions tagged `tag`, or to all of them for `None`.
+// Replies how many sessions it went to. Unlike a regular broadcast, the shared config is untouched.
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct TargetedBroadcast {
+    pub tag: Option<String>,
+    pub config: Config,
+}
+
+// Freeze or restart ball movement on every connected display, leaving the config alone. Sent to
+// clients as `{"type":"control","action":"pause"}`. Replies how many sessions it went to.
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct DisplayControl(pub ControlAction);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlAction {
+    Pause,
+    Resume,
+}
+
+// Write a pre-serialized frame (a config, replay, or control message) to a single session.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SendConfig(pub Arc<str>);
+
+// Tell a single session to close, with this reason in the close frame.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CloseSession(pub ws::CloseReason);
+
+// Close the sessions following any of these profiles, e.g. once they're removed. Replies how many were closed.
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct CloseProfiles(pub Vec<String>);
+
+// Ask the manager for every connected session, oldest first.
+#[derive(Message)]
+#[rtype(result = "Vec<SessionInfo>")]
+pub struct ListSessions;
+
+// Ask the manager how many sessions are currently connected.
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct SessionCount;
+
+// Send a session `{"type":"peer_count","count":N}` now and whenever a session connects or disconnects.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct WatchPeerCount {
+    pub id: SessionId,
 }
 
+// Ask the manager for the broadcasts it keeps for replay, oldest first.
+#[derive(Message)]
+#[rtype(result = "Vec<Config>")]
+pub struct GetHistory;
+
+// Replace the replay history, oldest first, e.g. from a state snapshot. Only the latest
+// `WS_REPLAY_SIZE` entries are kept; replies how many that is.
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct RestoreHistory(pub Vec<Config>);
+
 impl Actor for WsManager {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(events) = self.events.take() {
+            ctx.add_stream(BroadcastStream::new(events));
+        }
+
+        // Bound how long stale sessions can linger if their `Disconnect` never arrives
+        ctx.run_interval(REAP_INTERVAL, |act, _| {
+            let reaped = act.reap_closed_sessions();
+            if reaped > 0 {
+                warn!("Reaped {} stale sessions that disconnected without notice", reaped);
+            }
+        });
+    }
 }
 
 impl Handler<Connect> for WsManager {
     type Result = ();
 
     fn handle(&mut self, msg: Connect, _: &mut Self::Context) {
-        info!("New client connected: {:?}", msg.addr);
-        self.sessions.insert(msg.addr);
+        // Keep the first registration; a repeated `Connect` must not replace or double-count it
+        let id = msg.info.id;
+        match self.sessions.entry(id) {
+            Entry::Occupied(_) => warn!("Ignoring duplicate connect for session {}", id),
+            Entry::Vacant(entry) => {
+                info!("New client connected: {} (tag {:?}, profile {:?})", id, msg.info.tag, msg.info.profile);
+                let shared = msg.info.profile.is_none();
+                entry.insert(Session { addr: msg.addr, info: msg.info });
+                self.peer_count_changed();
+                // Recent broadcasts as full configs, oldest first, in one frame of their own. They're
+                // of the shared config, which sessions following a profile never see
+                if !self.history.is_empty() && shared {
+                    let replay = ServerMessage::Replay { history: self.history.to_vec() };
+                    if let Some(json) = replay.as_text() {
+                        let _ = self.send_to(id, json.into());
+                    }
+                }
+            }
+        }
     }
 }
 
@@ -70,25 +683,256 @@ impl Handler<Disconnect> for WsManager {
     type Result = ();
 
     fn handle(&mut self, msg: Disconnect, _: &mut Self::Context) {
-        info!("Client disconnected: {:?}", msg.addr);
-        self.sessions.remove(&msg.addr);
+        match self.sessions.remove(&msg.id) {
+            Some(_) => {
+                info!("Client disconnected: {}", msg.id);
+                self.peer_count_changed();
+            }
+            None => debug!("Ignoring disconnect: {}", WsError::SessionNotFound(msg.id)),
+        }
+    }
+}
+
+impl Handler<WatchPeerCount> for WsManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: WatchPeerCount, _: &mut Self::Context) {
+        if !self.sessions.contains_key(&msg.id) {
+            debug!("Ignoring peer count watch: {}", WsError::SessionNotFound(msg.id));
+            return;
+        }
+        // Watching twice still sends the current count, but only one update per change
+        self.peer_watchers.insert(msg.id);
+        if let Some(frame) = (ServerMessage::PeerCount { count: self.sessions.len() }).as_text() {
+            let _ = self.send_to(msg.id, frame.into());
+        }
+    }
+}
+
+impl Handler<CloseAll> for WsManager {
+    type Result = usize;
+
+    fn handle(&mut self, _: CloseAll, _: &mut Self::Context) -> usize {
+        for session in self.sessions.values() {
+            session.addr.do_send(CloseSession(ws::CloseReason {
+                code: ws::CloseCode::Away,
+                description: Some("Server shutting down".to_string()),
+            }));
+        }
+        self.sessions.len()
+    }
+}
+
+impl Handler<CloseProfiles> for WsManager {
+    type Result = usize;
+
+    fn handle(&mut self, msg: CloseProfiles, _: &mut Self::Context) -> usize {
+        let mut closed = 0;
+        for session in self.sessions.values() {
+            let Some(profile) = session.info.profile.as_ref().filter(|profile| msg.0.contains(profile)) else {
+                continue;
+            };
+            session.addr.do_send(CloseSession(ws::CloseReason {
+                code: ws::CloseCode::Away,
+                description: Some(format!("Profile {} was removed, reconnect without it or with another one", profile)),
+            }));
+            closed += 1;
+        }
+        info!("Closed {} sessions of removed profiles {:?}", closed, msg.0);
+        closed
+    }
+}
+
+impl Handler<TargetedBroadcast> for WsManager {
+    type Result = usize;
+
+    fn handle(&mut self, msg: TargetedBroadcast, _: &mut Self::Context) -> usize {
+        self.reap_closed_sessions();
+        // Targeted sessions diverge from the shared baseline, so they get the whole config, not a patch
+        let Some(config_json) = ServerMessage::Config(msg.config).as_text() else {
+            return 0;
+        };
+        let config_json: Arc<str> = config_json.into();
+        let mut delivered = 0;
+        for (&id, session) in &self.sessions {
+            if (msg.tag.is_none() || session.info.tag == msg.tag) && self.send_to(id, config_json.clone()).is_ok() {
+                delivered += 1;
+            }
+        }
+        info!("Targeted broadcast to tag {:?} reached {} sessions", msg.tag, delivered);
+        delivered
+    }
+}
+
+impl Handler<DisplayControl> for WsManager {
+    type Result = usize;
+
+    fn handle(&mut self, msg: DisplayControl, _: &mut Self::Context) -> usize {
+        self.reap_closed_sessions();
+        // Sent to each session directly rather than through the broadcast channel, which only carries configs
+        let Some(frame) = (ServerMessage::Control { action: msg.0 }).as_text() else {
+            return 0;
+        };
+        let frame: Arc<str> = frame.into();
+        let delivered = self.sessions.keys().filter(|&&id| self.send_to(id, frame.clone()).is_ok()).count();
+        info!("Display control {:?} sent to {} sessions", msg.0, delivered);
+        delivered
+    }
+}
+
+impl Handler<ListSessions> for WsManager {
+    type Result = Vec<SessionInfo>;
+
+    fn handle(&mut self, _: ListSessions, _: &mut Self::Context) -> Vec<SessionInfo> {
+        self.reap_closed_sessions();
+        let mut sessions: Vec<SessionInfo> = self.sessions.values().map(|session| session.info.clone()).collect();
+        sessions.sort_by_key(|info| info.id);
+        sessions
+    }
+}
+
+impl Handler<SessionCount> for WsManager {
+    type Result = usize;
+
+    fn handle(&mut self, _: SessionCount, _: &mut Self::Context) -> usize {
+        self.sessions.len()
+    }
+}
+
+impl Handler<GetHistory> for WsManager {
+    type Result = Vec<Config>;
+
+    fn handle(&mut self, _: GetHistory, _: &mut Self::Context) -> Vec<Config> {
+        self.history.to_vec()
+    }
+}
+
+impl Handler<RestoreHistory> for WsManager {
+    type Result = usize;
+
+    fn handle(&mut self, msg: RestoreHistory, _: &mut Self::Context) -> usize {
+        self.history.replace(msg.0)
     }
 }
 
 impl Handler<GenericWsMessage> for WsManager {
     type Result = ();
 
-    fn handle(&mut self, msg: GenericWsMessage, _: &mut Self::Context) {
-        debug!("Broadcasting message: {:?}", msg);
-        for addr in self.sessions.iter() {
-            addr.do_send(msg.clone());
+    fn handle(&mut self, msg: GenericWsMessage, ctx: &mut Self::Context) {
+        if self.paused {
+            debug!("Broadcasts paused, holding config: {}", truncate_for_log(&msg.config, self.log_limit));
+            self.held = Some(msg);
+            return;
+        }
+        debug!("Broadcasting message: {}", truncate_for_log(&msg, self.log_limit));
+        self.broadcast_throttled(msg, ctx);
+    }
+}
+
+impl StreamHandler<Result<ConfigChanged, BroadcastStreamRecvError>> for WsManager {
+    fn handle(&mut self, msg: Result<ConfigChanged, BroadcastStreamRecvError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ConfigChanged { config, source, .. }) => Handler::handle(self, GenericWsMessage { config, source: Some(source) }, ctx),
+            // Only the latest config matters, and it's still to come or was already received
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => warn!("Broadcasts skipped {} config changes", skipped),
+        }
+    }
+
+    // The manager outlives the event stream; keep it running when the sender goes away
+    fn finished(&mut self, _: &mut Self::Context) {}
+}
+
+impl Handler<PauseBroadcasts> for WsManager {
+    type Result = ();
+
+    fn handle(&mut self, _: PauseBroadcasts, _: &mut Self::Context) {
+        info!("Broadcasts paused");
+        self.paused = true;
+    }
+}
+
+impl Handler<ResumeBroadcasts> for WsManager {
+    type Result = bool;
+
+    fn handle(&mut self, _: ResumeBroadcasts, ctx: &mut Self::Context) -> bool {
+        info!("Broadcasts resumed");
+        self.paused = false;
+        match self.held.take() {
+            Some(msg) => {
+                self.broadcast_throttled(msg, ctx);
+                true
+            }
+            None => false,
         }
     }
 }
 
 pub struct ConfigWs {
-    config: Arc<Mutex<Option<Config>>>,
+    id: SessionId,
+    info: SessionInfo,                     // Handshake details registered with the manager
+    connected_at: Instant,
+    metrics: Arc<dyn Metrics>,             // Where the session's duration and missed updates are recorded
+    config: Arc<RwLock<Option<Config>>>,
     ws_manager: Addr<WsManager>,
+    updates: Option<broadcast::Receiver<Arc<Broadcast>>>,
+    subscription: Option<HashSet<String>>, // Fields whose changes this client wants; None means all
+    last_sent: Option<Value>,              // Last config sent, to detect changes in subscribed fields
+    pending: Option<PendingUpdate>,        // Update not yet written to the client
+    awaiting_config: bool,                 // The client hasn't been sent the real config yet
+    unavailable_mode: UnavailableMode,     // What to send on connect while no config is loaded
+    unavailable_grace: Duration,           // How long to wait for a first config before falling back to `unavailable_mode`
+    grace_timer: Option<SpawnHandle>,      // Pending fallback while waiting out the grace period
+    unknown_commands: u32,                 // Unrecognized messages received so far
+    seen_message: bool,                    // Whether the client has sent anything yet; hello must come first
+    hello_window: Duration,                // How long the initial config waits for a hello
+    hello_timer: Option<SpawnHandle>,      // Pending initial send while waiting for a hello
+    initial_sent: bool,                    // Whether the initial config went out; broadcasts wait for it
+    max_unknown_commands: u32,             // Close the session past this many; 0 never closes
+    protocol_errors: u32,                  // Protocol errors in a row, reset by any valid message
+    max_protocol_errors: u32,              // Close the session once this many happen in a row; 0 never closes
+    fragments: Option<Vec<u8>>,            // Text message being reassembled from continuation frames
+    max_message_size: usize,               // Largest text message accepted, in one frame or reassembled, in bytes
+    max_lifetime: Duration,                // Close the session once it has been open this long; zero never closes
+    history_size: usize,                   // Most broadcasts the manager keeps, and so the largest `get_history` limit
+    min_protocol_version: u32,             // Clients speaking an older protocol are closed
+    hello_version: Option<u32>,            // Protocol version agreed in the hello; None until one is accepted
+    echo: bool,                            // Send every text message back to the client as received, for debugging
+    manager_timeout: Duration,             // Longest a client's request waits on the manager; zero waits indefinitely
+    skip_duplicates: bool,                 // Drop broadcasts that wouldn't change the config the client holds
+    delivered: Option<Value>,              // The config the client holds, as far as this session has sent it
+    upgrade_permit: Option<OwnedSemaphorePermit>, // Slot in `AppState::upgrades`, held until the initial config is sent
+    heartbeat_interval: Duration,          // How often the client is pinged; zero never pings
+    pings_unanswered: u32,                 // Pings sent since the last pong
+    last_pong: Option<Instant>,            // When the client last answered a ping
+}
+
+// How a session answers while no config has been loaded yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnavailableMode {
+    Error,   // Send the `config_unavailable` error
+    Default, // Send `Config::default()`
+    Defer,   // Send nothing until the first config arrives
+    Refuse,  // Refuse the upgrade with a 503, so clients and load balancers retry elsewhere
+}
+
+impl FromStr for UnavailableMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(UnavailableMode::Error),
+            "default" => Ok(UnavailableMode::Default),
+            "defer" => Ok(UnavailableMode::Defer),
+            "refuse" => Ok(UnavailableMode::Refuse),
+            other => Err(format!("unknown mode {:?}", other)),
+        }
+    }
+}
+
+// What a session still has to write to its client.
+enum PendingUpdate {
+    Frame(Arc<Broadcast>), // A single broadcast, forwarded as-is
+    Resync,                // Updates were collapsed or missed; send the full current config instead
 }
 
 impl Actor for ConfigWs {
@@ -96,92 +940,1301 @@ impl Actor for ConfigWs {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         let addr = ctx.address();
-        self.ws_manager.do_send(Connect { addr });
+        self.ws_manager.do_send(Connect { addr, info: self.info.clone() });
+
+        // Forward config broadcasts to this client
+        if let Some(updates) = self.updates.take() {
+            ctx.add_stream(BroadcastStream::new(updates));
+        }
 
-        // Send the current configuration to the client.
-        self.send_current_config(ctx); // Assuming send_current_config is implemented.
+        // Send the current configuration, giving the client a chance to say hello first if configured
+        if self.hello_window.is_zero() {
+            self.send_initial_config(ctx);
+        } else {
+            self.hello_timer = Some(ctx.run_later(self.hello_window, |act, ctx| act.send_initial_config(ctx)));
+        }
+
+        if !self.heartbeat_interval.is_zero() {
+            ctx.run_interval(self.heartbeat_interval, |act, ctx| act.heartbeat(ctx));
+        }
+
+        // Make long-lived clients reconnect now and then, so a load balancer gets to spread them again
+        if !self.max_lifetime.is_zero() {
+            ctx.run_later(self.max_lifetime, |act, ctx| {
+                info!("Session {} reached its maximum lifetime of {:?}, closing", act.id, act.max_lifetime);
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Normal,
+                    description: Some("Session lifetime reached, please reconnect".to_string()),
+                }));
+                ctx.stop();
+            });
+        }
     }
 
-    fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
-        let addr = ctx.address();
-        self.ws_manager.do_send(Disconnect { addr });
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        let duration = self.connected_at.elapsed();
+        info!("Session {} lasted {:.1}s", self.id, duration.as_secs_f64());
+        self.metrics.record_duration(WS_SESSION_DURATION_SECONDS, duration);
+        self.ws_manager.do_send(Disconnect { id: self.id });
         Running::Stop
     }
 }
 
-
 // Utility methods for ConfigWs actor.
 impl ConfigWs {
+    // Run a handler body, turning a panic into a logged, orderly close. Left alone, a panic tears
+    // the connection down without the close frame, and the session lingers in the `WsManager`
+    // until it's reaped.
+    fn guarded<F>(&mut self, what: &str, ctx: &mut WebsocketContext<Self>, handler: F)
+    where
+        F: FnOnce(&mut Self, &mut WebsocketContext<Self>),
+    {
+        let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| handler(self, ctx))) else {
+            return;
+        };
+        let reason = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown cause");
+        error!("Session {} panicked while handling {}: {}", self.id, what, reason);
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Error,
+            description: Some("Internal server error".to_string()),
+        }));
+        // `stopping` tells the manager the session is gone
+        ctx.stop();
+    }
+
+    // Ping the client, with the time since connecting as the payload for its pong to carry back.
+    fn heartbeat(&mut self, ctx: &mut WebsocketContext<Self>) {
+        if self.pings_unanswered > 0 {
+            let heard = self.last_pong.unwrap_or(self.connected_at).elapsed();
+            debug!("Session {} left {} pings unanswered, last heard from {:.1}s ago", self.id, self.pings_unanswered, heard.as_secs_f64());
+        }
+        self.pings_unanswered += 1;
+        let sent = self.connected_at.elapsed().as_micros() as u64;
+        ctx.ping(&sent.to_be_bytes());
+    }
+
+    // Record the round trip of the ping `payload` came back from. Pongs the client sent unasked
+    // carry something else, and only count as a sign of life.
+    fn pong(&mut self, payload: &[u8]) {
+        self.last_pong = Some(Instant::now());
+        self.pings_unanswered = 0;
+        let Ok(sent) = <[u8; 8]>::try_from(payload).map(u64::from_be_bytes) else {
+            return;
+        };
+        if let Some(rtt) = self.connected_at.elapsed().checked_sub(Duration::from_micros(sent)) {
+            self.metrics.record_duration(WS_PONG_RTT_SECONDS, rtt);
+        }
+    }
+
     // This method now sends the current ball configuration to the client.
-    fn send_current_config(&self, ctx: &mut WebsocketContext<Self>) {
-        let config_lock = self.config.lock().unwrap(); // Lock and access shared config state.
-        if let Some(config) = &*config_lock {
-            // Serialize the current config to a JSON string
-            let config_json = serde_json::to_string(config).expect("Failed to serialize config");
-            // Send the serialized config to the client
-            ctx.text(config_json);
+    fn send_current_config(&mut self, ctx: &mut WebsocketContext<Self>) {
+        let current = read_lock(&self.config).clone(); // Clone out so the read guard is released right away
+        let config = match current {
+            Some(config) => {
+                self.awaiting_config = false;
+                if let Some(timer) = self.grace_timer.take() {
+                    ctx.cancel_future(timer);
+                }
+                config
+            }
+            None => {
+                // Either way the client doesn't hold the real config yet, so the next broadcast,
+                // likely a patch, is replaced with the full config
+                self.awaiting_config = true;
+                match self.unavailable_mode {
+                    // A refusing server only upgrades once a config is loaded, so this is rare
+                    UnavailableMode::Error | UnavailableMode::Refuse => {
+                        ctx.text(ApiError::ConfigUnavailable.to_json().to_string());
+                        return;
+                    }
+                    UnavailableMode::Default => Config::default(),
+                    UnavailableMode::Defer => {
+                        debug!("Session {} waiting for the first config", self.id);
+                        return;
+                    }
+                }
+            }
+        };
+        match serde_json::to_value(ServerMessage::Config(config)) {
+            Ok(config_json) => {
+                ctx.text(config_json.to_string());
+                self.delivered = Some(config_json.clone());
+                self.last_sent = Some(config_json);
+            }
+            // Tell the client rather than dropping the session; the next broadcast resyncs it in full
+            Err(e) => {
+                error!("Failed to serialize config for session {}: {}", self.id, e);
+                self.awaiting_config = true;
+                ctx.text(ApiError::Internal("Failed to serialize the config.".to_string()).to_json().to_string());
+            }
+        }
+    }
+
+    // Send the config a new session starts from, once, cancelling any wait for a hello.
+    fn send_initial_config(&mut self, ctx: &mut WebsocketContext<Self>) {
+        if self.initial_sent {
+            return;
+        }
+        // The upgrade is done once this returns; let the next one in
+        let _upgrade_permit = self.upgrade_permit.take();
+        if let Some(timer) = self.hello_timer.take() {
+            ctx.cancel_future(timer);
+        }
+        self.initial_sent = true;
+        if self.hello_version.is_none() && LEGACY_PROTOCOL_VERSION < self.min_protocol_version {
+            self.refuse_version(LEGACY_PROTOCOL_VERSION, ctx);
+            return;
+        }
+
+        // Right after startup the first fetch may be moments away: hold off on the fallback, and let
+        // the first broadcast deliver the config (via the `awaiting_config` resync) if it comes in time
+        if !self.unavailable_grace.is_zero() && read_lock(&self.config).is_none() {
+            debug!("Session {} waiting up to {:?} for the first config", self.id, self.unavailable_grace);
+            self.awaiting_config = true;
+            self.grace_timer = Some(ctx.run_later(self.unavailable_grace, |act, ctx| {
+                act.grace_timer = None;
+                act.send_current_config(ctx);
+            }));
+            return;
+        }
+        self.send_current_config(ctx);
+    }
+
+    // Close a client speaking a protocol older than `min_protocol_version`. Browsers running a
+    // cached copy of the demo page reconnect with the current script once reloaded.
+    fn refuse_version(&mut self, version: u32, ctx: &mut WebsocketContext<Self>) {
+        warn!("Session {} speaks protocol version {}, below the minimum {}, closing", self.id, version, self.min_protocol_version);
+        let message = format!(
+            "protocol version {} is no longer supported, the minimum is {}; reload to get an up-to-date client",
+            version, self.min_protocol_version
+        );
+        self.send(ServerMessage::Error { message }, ctx);
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some("Protocol version too old, please reload".to_string()),
+        }));
+        ctx.stop();
+    }
+
+    // Answer a hello with the agreed capabilities, then send the initial config.
+    fn hello(&mut self, request: HelloRequest, first: bool, ctx: &mut WebsocketContext<Self>) {
+        if !first {
+            self.send(ServerMessage::Error { message: "hello must be the first message".to_string() }, ctx);
+            return;
+        }
+        if request.version < self.min_protocol_version {
+            self.refuse_version(request.version, ctx);
+            return;
+        }
+        let format = if request.formats.is_empty() {
+            SUPPORTED_FORMATS.first().copied()
         } else {
-            // Optionally, handle the case where config is not set
-            ctx.text("{\"error\": \"Configuration not available.\"}");
+            request.formats.iter().map(String::as_str).find(|format| SUPPORTED_FORMATS.contains(format))
+        };
+        let (Some(format), PROTOCOL_VERSION) = (format, request.version) else {
+            warn!("Session {} sent an incompatible hello: {:?}", self.id, request);
+            let message = format!(
+                "unsupported hello: server speaks version {} with formats {:?}",
+                PROTOCOL_VERSION, SUPPORTED_FORMATS
+            );
+            self.send(ServerMessage::Error { message }, ctx);
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Protocol,
+                description: Some("Incompatible hello".to_string()),
+            }));
+            ctx.stop();
+            return;
+        };
+        debug!("Session {} said hello: {:?}", self.id, request);
+        self.hello_version = Some(request.version);
+        let welcome = ServerMessage::Welcome {
+            version: PROTOCOL_VERSION,
+            format: format.to_string(),
+            server: concat!("subscriber_microservice/", env!("CARGO_PKG_VERSION")).to_string(),
+        };
+        self.send(welcome, ctx);
+        self.send_initial_config(ctx);
+    }
+
+    // Replace the field filter; the client is assumed to hold the current shared config.
+    fn subscribe(&mut self, fields: Vec<String>) {
+        if fields.is_empty() {
+            self.subscription = None;
+            return;
+        }
+        self.last_sent = read_lock(&self.config).as_ref().and_then(|config| serde_json::to_value(config).ok());
+        self.subscription = Some(fields.into_iter().collect());
+    }
+
+    // Coalesce updates for a busy client: a burst of broadcasts arriving before the flush runs
+    // collapses into one full-config resync, since a patch can't simply be dropped.
+    fn queue_update(&mut self, update: PendingUpdate, ctx: &mut WebsocketContext<Self>) {
+        if self.pending.is_some() {
+            debug!("Session {} collapsed pending updates into a resync", self.id);
+            self.pending = Some(PendingUpdate::Resync);
+            return;
+        }
+        self.pending = Some(update);
+        // Deferred instead of written here on purpose: the timer only fires once the actor has
+        // handled the broadcasts already buffered in its stream, so a burst folds into the slot
+        // above and is written once. This isn't the initial config, which `started` sends directly
+        ctx.run_later(Duration::ZERO, |act, ctx| match act.pending.take() {
+            Some(PendingUpdate::Frame(update)) => act.send_frame(&update, ctx),
+            Some(PendingUpdate::Resync) => act.send_current_config(ctx),
+            None => (),
+        });
+    }
+
+    // Write a broadcast, unless the client already holds the config it leads to, e.g. a patch
+    // racing the config sent on connect.
+    fn send_frame(&mut self, update: &Broadcast, ctx: &mut WebsocketContext<Self>) {
+        if self.skip_duplicates {
+            let after = if update.patch {
+                self.delivered.as_ref().and_then(|held| patched(held, &update.fields))
+            } else {
+                Some(Value::Object(update.fields.clone()))
+            };
+            match after {
+                Some(config) if self.delivered.as_ref() == Some(&config) => {
+                    debug!("Session {} already holds this config, skipping the broadcast", self.id);
+                    return;
+                }
+                Some(config) => self.delivered = Some(config),
+                None => (),
+            }
+        }
+        ctx.text(&*update.frame);
+    }
+
+    // Tell the client its message wasn't understood, closing the session once it has sent too many.
+    fn reject_unknown_command(&mut self, command: &str, ctx: &mut WebsocketContext<Self>) {
+        self.send(ServerMessage::Error { message: format!("unknown command: {}", command) }, ctx);
+        self.unknown_commands += 1;
+        if self.max_unknown_commands > 0 && self.unknown_commands > self.max_unknown_commands {
+            warn!("Session {} sent too many unknown commands, closing", self.id);
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some("Too many unknown commands".to_string()),
+            }));
+            ctx.stop();
+        }
+    }
+
+    // Ask the manager `msg`, giving up after `manager_timeout` unless it's zero.
+    fn ask_manager<M>(&self, msg: M) -> Request<WsManager, M>
+    where
+        M: Message + Send + 'static,
+        M::Result: Send,
+        WsManager: Handler<M>,
+    {
+        let request = self.ws_manager.send(msg);
+        if self.manager_timeout.is_zero() { request } else { request.timeout(self.manager_timeout) }
+    }
+
+    // Tell the client a request to the manager failed; a timeout is worth retrying.
+    fn manager_failed(&self, what: &str, e: MailboxError, ctx: &mut WebsocketContext<Self>) {
+        error!("Session {} failed to {}: {}", self.id, what, e);
+        if let MailboxError::Timeout = e {
+            self.send(ServerMessage::Error { message: format!("Timed out trying to {}; retry shortly.", what) }, ctx);
+        }
+    }
+
+    // Reply with the number of connected clients once the manager answers.
+    fn send_peer_count(&self, ctx: &mut WebsocketContext<Self>) {
+        let request = self.ask_manager(SessionCount).into_actor(self).map(|result, act, ctx| match result {
+            Ok(count) => act.send(ServerMessage::PeerCount { count }, ctx),
+            Err(e) => act.manager_failed("query session count", e, ctx),
+        });
+        ctx.spawn(request);
+    }
+
+    // Send the latest `limit` broadcasts, newest first, or an error if the limit is out of range.
+    fn send_history(&self, limit: Option<usize>, ctx: &mut WebsocketContext<Self>) {
+        let limit = limit.unwrap_or(self.history_size);
+        if self.history_size == 0 {
+            self.send(ServerMessage::Error { message: "History is disabled.".to_string() }, ctx);
+            return;
+        }
+        if limit == 0 || limit > self.history_size {
+            let message = format!("limit must be between 1 and {}", self.history_size);
+            self.send(ServerMessage::Error { message }, ctx);
+            return;
+        }
+        let request = self.ask_manager(GetHistory).into_actor(self).map(move |result, act, ctx| match result {
+            Ok(history) => {
+                let history = history.into_iter().rev().take(limit).collect();
+                act.send(ServerMessage::History { history }, ctx);
+            }
+            Err(e) => act.manager_failed("query history", e, ctx),
+        });
+        ctx.spawn(request);
+    }
+
+    // Write a single message to this client.
+    fn send(&self, message: ServerMessage, ctx: &mut WebsocketContext<Self>) {
+        if let Some(text) = message.as_text() {
+            ctx.text(text);
+        }
+    }
+
+    // Dispatch a complete text message from the client.
+    fn handle_text(&mut self, message_string: String, ctx: &mut WebsocketContext<Self>) {
+        let first = !self.seen_message;
+        self.seen_message = true;
+        // Before anything else, so front-end developers see their message arrived even if it's rejected
+        if self.echo {
+            self.send(ServerMessage::Echo { received: message_string.clone() }, ctx);
+        }
+
+        let message = ClientMessage::parse(&message_string);
+        if let Some(ClientMessage::Hello(request)) = message {
+            self.hello(request, first, ctx);
+            return;
+        }
+        // Clients that don't say hello get the defaults, unless they're too old to serve
+        self.send_initial_config(ctx);
+        if ctx.state() != ActorState::Running {
+            return;
+        }
+
+        match message {
+            Some(ClientMessage::GetConfig) => self.send_current_config(ctx),
+            Some(ClientMessage::Resync) => {
+                // Whatever was queued is superseded by the full config
+                self.pending = None;
+                self.send_current_config(ctx);
+            }
+            Some(ClientMessage::PeerCount) => self.send_peer_count(ctx),
+            Some(ClientMessage::WatchPeerCount) => self.ws_manager.do_send(WatchPeerCount { id: self.id }),
+            Some(ClientMessage::GetHistory { limit }) => self.send_history(limit, ctx),
+            Some(ClientMessage::Subscribe { fields }) => {
+                debug!("Session {} subscribed to fields: {:?}", self.id, fields);
+                self.subscribe(fields);
+            }
+            Some(ClientMessage::Hello(_)) | None => {
+                warn!("Session {} sent unexpected text message: {}", self.id, message_string);
+                self.reject_unknown_command(&message_string, ctx);
+            }
+        }
+    }
+
+    // Close the session over a text message larger than `WS_MAX_MESSAGE_SIZE`, without handling it.
+    fn close_oversized(&mut self, ctx: &mut WebsocketContext<Self>) {
+        warn!("Session {} sent a message over {} bytes, closing", self.id, self.max_message_size);
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Size,
+            description: Some("Message exceeds the maximum size".to_string()),
+        }));
+        ctx.stop();
+    }
+
+    // Reassemble a text message sent in fragments, dispatching it once the last one arrives.
+    fn continuation(&mut self, item: Item, ctx: &mut WebsocketContext<Self>) {
+        let (chunk, last) = match item {
+            Item::FirstText(chunk) => {
+                self.fragments = Some(Vec::new());
+                (chunk, false)
+            }
+            // Binary messages are ignored whole, fragmented or not
+            Item::FirstBinary(_) => {
+                self.fragments = None;
+                return;
+            }
+            Item::Continue(chunk) => (chunk, false),
+            Item::Last(chunk) => (chunk, true),
+        };
+        let Some(fragments) = self.fragments.as_mut() else {
+            return;
+        };
+        // Each fragment fits in a frame, but together they're held to the message limit
+        if fragments.len() + chunk.len() > self.max_message_size {
+            self.close_oversized(ctx);
+            return;
+        }
+        fragments.extend_from_slice(&chunk);
+        if !last {
+            return;
+        }
+        match String::from_utf8(self.fragments.take().unwrap_or_default()) {
+            Ok(text) => self.handle_text(text, ctx),
+            Err(_) => {
+                warn!("Session {} sent a fragmented text message that isn't UTF-8, closing", self.id);
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Invalid,
+                    description: Some("Text message isn't valid UTF-8".to_string()),
+                }));
+                ctx.stop();
+            }
         }
     }
+
+    // Decide whether a broadcast passes the subscription filter, remembering it if it does.
+    fn wants_update(&mut self, update: &Broadcast) -> bool {
+        let Some(fields) = &self.subscription else {
+            return true;
+        };
+        // A patch lists exactly the fields that changed
+        if update.patch {
+            return fields.iter().any(|field| update.fields.contains_key(field));
+        }
+        let changed = match &self.last_sent {
+            Some(previous) => fields.iter().any(|field| previous.get(field) != update.fields.get(field)),
+            None => true,
+        };
+        if changed {
+            self.last_sent = Some(Value::Object(update.fields.clone()));
+        }
+        changed
+    }
 }
 
+// The config a client holds once `frame` is applied on top of `held`, or `None` if that can't be
+// told: the frame carries no config, or it's a patch and the base isn't known. A `null` in a patch
+// clears the field, which leaves it out of the config's JSON like an unset one.
+fn config_after(held: Option<&Value>, frame: &Value) -> Option<Value> {
+    match frame.get("type").and_then(Value::as_str) {
+        None => Some(frame.clone()),
+        Some("patch") => patched(held?, frame.get("changes")?.as_object()?),
+        Some(_) => None,
+    }
+}
+
+// `held` with a patch's `changes` applied, or `None` if `held` isn't a config object.
+fn patched(held: &Value, changes: &Map<String, Value>) -> Option<Value> {
+    let mut config = held.clone();
+    let fields = config.as_object_mut()?;
+    for (field, value) in changes {
+        match value {
+            Value::Null => fields.remove(field),
+            value => fields.insert(field.clone(), value.clone()),
+        };
+    }
+    Some(config)
+}
 
 // Handle incoming WebSocket messages, responding or acting based on the message type.
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ConfigWs {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-        match msg {
-            Ok(ws::Message::Text(text)) => {
-                let message_string = text.to_string(); // Convert ByteString to String
+        self.guarded("a client message", ctx, |act, ctx| act.client_message(msg, ctx));
+    }
+}
 
-                // Compare the string directly instead of using String::from in the match arm
-                if message_string == "get_config" {
-                    // Send the current configuration to the client
-                    self.send_current_config(ctx);
-                } else {
-                    // Log unexpected text messages or handle them as needed
-                    log::warn!("Received unexpected text message: {}", message_string);
-                }
-            },
+impl ConfigWs {
+    fn client_message(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut WebsocketContext<Self>) {
+        if msg.is_ok() {
+            self.protocol_errors = 0;
+        }
+        match msg {
+            Ok(ws::Message::Text(text)) if text.len() > self.max_message_size => self.close_oversized(ctx),
+            Ok(ws::Message::Text(text)) => self.handle_text(text.to_string(), ctx),
+            Ok(ws::Message::Continuation(item)) => self.continuation(item, ctx),
+            Ok(ws::Message::Ping(payload)) => ctx.pong(&payload),
+            Ok(ws::Message::Pong(payload)) => self.pong(&payload),
 
+            Err(ws::ProtocolError::Overflow) => {
+                // The frame exceeded the configured maximum size; the codec can't resync after that
+                warn!("Session {} sent an oversized frame, closing", self.id);
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Size,
+                    description: Some("Frame exceeds the maximum size".to_string()),
+                }));
+                ctx.stop();
+            }
             Err(e) => {
-                // Log or handle protocol errors
-                log::error!("WebSocket protocol error: {:?}", e);
-            },
-            // You don't need an exhaustive match here since you've covered all variants of ws::Message
-            _ => (), // Ignore other message types (Close, Ping, Pong) or handle as needed
+                error!("WebSocket protocol error in session {}: {:?}", self.id, e);
+                // A client that keeps sending malformed frames isn't going to recover
+                self.protocol_errors += 1;
+                if self.max_protocol_errors > 0 && self.protocol_errors >= self.max_protocol_errors {
+                    warn!("Session {} sent {} malformed frames in a row, closing", self.id, self.protocol_errors);
+                    ctx.close(Some(ws::CloseReason {
+                        code: ws::CloseCode::Protocol,
+                        description: Some("Too many protocol errors".to_string()),
+                    }));
+                    ctx.stop();
+                }
+            }
+            // Binary messages aren't part of the protocol, and after a close the stream ends, stopping the session
+            _ => (),
         }
     }
 }
 
+// Forward pre-serialized config broadcasts from the WsManager to the client.
+impl Handler<SendConfig> for ConfigWs {
+    type Result = ();
 
+    fn handle(&mut self, msg: SendConfig, ctx: &mut Self::Context) {
+        self.guarded("a direct send", ctx, |act, ctx| {
+            // A targeted config replaces what the client holds, so later broadcasts are compared against it
+            if act.skip_duplicates {
+                if let Some(config) = serde_json::from_str(&msg.0).ok().and_then(|frame| config_after(act.delivered.as_ref(), &frame)) {
+                    act.delivered = Some(config);
+                }
+            }
+            ctx.text(&*msg.0)
+        });
+    }
+}
 
-// Handle incoming configuration update messages, updating the shared config state and notifying clients.
-impl Handler<GenericWsMessage> for ConfigWs {
+impl Handler<CloseSession> for ConfigWs {
     type Result = ();
 
-    fn handle(&mut self, msg: GenericWsMessage, ctx: &mut Self::Context) {
-        // Update the local configuration based on the message
-        let mut config_lock = self.config.lock().unwrap();
-        *config_lock = Some(msg.config.clone());
+    fn handle(&mut self, msg: CloseSession, ctx: &mut Self::Context) {
+        ctx.close(Some(msg.0));
+        ctx.stop();
+    }
+}
+
+impl StreamHandler<Result<Arc<Broadcast>, BroadcastStreamRecvError>> for ConfigWs {
+    fn handle(&mut self, msg: Result<Arc<Broadcast>, BroadcastStreamRecvError>, ctx: &mut Self::Context) {
+        self.guarded("a broadcast", ctx, |act, ctx| act.broadcast_update(msg, ctx));
+    }
+}
+
+impl ConfigWs {
+    fn broadcast_update(&mut self, msg: Result<Arc<Broadcast>, BroadcastStreamRecvError>, ctx: &mut WebsocketContext<Self>) {
+        match msg {
+            // The initial config, still to be sent, will already be the latest
+            Ok(_) if !self.initial_sent => (),
+            Ok(_) if self.awaiting_config => self.queue_update(PendingUpdate::Resync, ctx),
+            Ok(update) => {
+                if self.wants_update(&update) {
+                    self.queue_update(PendingUpdate::Frame(update), ctx);
+                }
+            }
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                // Skipped patches can't be replayed, so catch the client up with the full config
+                warn!("Session {} lagged behind, skipped {} config updates", self.id, skipped);
+                self.metrics.increment_counter(WS_DELIVERY_FAILURES_TOTAL, &[("reason", DeliveryFailure::Lagged.label())]);
+                self.queue_update(PendingUpdate::Resync, ctx);
+            }
+        }
+    }
+}
+
+// Headers whose values never make it into the logs.
+const REDACTED_HEADERS: &[header::HeaderName] = &[header::AUTHORIZATION, header::PROXY_AUTHORIZATION, header::COOKIE];
+
+// The handshake request for the debug log: method, URI, and headers, with credentials redacted.
+fn redacted_request(req: &HttpRequest) -> String {
+    let headers: Vec<String> = req
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            let value = if REDACTED_HEADERS.contains(name) { "[redacted]" } else { value.to_str().unwrap_or("[non-ascii]") };
+            format!("{}: {}", name, value)
+        })
+        .collect();
+    format!("{} {} {:?} [{}]", req.method(), req.uri(), req.version(), headers.join(", "))
+}
+
+// The `/ws/` URL for a server listening on `addr`. Wildcard addresses are swapped for the loopback
+// of the same family, and IPv6 hosts are bracketed, e.g. `ws://[::1]:8081/ws/`.
+pub fn ws_url(addr: SocketAddr) -> String {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    format!("ws://{}/ws/", SocketAddr::new(ip, addr.port()))
+}
+
+// Query parameters accepted by `/ws/`.
+#[derive(Debug, Default, Deserialize)]
+struct WsParams {
+    tag: Option<String>,     // Group the session joins, e.g. `/ws/?tag=east`
+    profile: Option<String>, // Profile to follow instead of the shared config, e.g. `/ws/?profile=canary`
+}
 
-        // Optionally, respond back to the client to confirm the update
-        let confirmation = serde_json::to_string(&msg.config).expect("Failed to serialize config");
-        ctx.text(confirmation);
+// Check the handshake's origin and subprotocols, returning the subprotocol the session speaks, if
+// the client asked for one.
+fn check_handshake(req: &HttpRequest, allowed_origins: &[String]) -> WsResult<Option<String>> {
+    // Browsers always send `Origin` on a WebSocket handshake, so checking it stops other sites from
+    // connecting through a visitor's browser. Clients that send none aren't browsers and pass
+    if let Some(origin) = req.headers().get(header::ORIGIN) {
+        let origin = origin.to_str().unwrap_or("");
+        if !allowed_origins.is_empty() && !allowed_origins.iter().any(|allowed| allowed == origin) {
+            warn!("Rejected WebSocket handshake from origin {:?}", origin);
+            return Err(WsError::OriginNotAllowed(origin.to_string()));
+        }
     }
+
+    // Clients that don't ask for a subprotocol get the default one; clients that only ask for
+    // ones we don't speak are refused rather than silently downgraded
+    let requested: Vec<&str> = req
+        .headers()
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .collect();
+    // The handshake settles on the client's first choice that we speak
+    let protocol = requested.iter().find(|protocol| SUPPORTED_PROTOCOLS.contains(protocol)).map(|protocol| protocol.to_string());
+    if !requested.is_empty() && protocol.is_none() {
+        return Err(WsError::UnsupportedProtocol(requested.into_iter().map(String::from).collect()));
+    }
+    Ok(protocol)
 }
 
+pub async fn config_ws(req: HttpRequest, stream: web::Payload, data: web::Data<AppState>, ws_manager: web::Data<Addr<WsManager>>, broadcaster: web::Data<ConfigBroadcaster>) -> Result<HttpResponse, ApiError> {
+    if data.settings.ws_log_requests {
+        debug!("Starting WebSocket session for request: {}", redacted_request(&req));
+    }
+
+    let protocol = check_handshake(&req, &data.settings.ws_allowed_origins)?;
+    let params = web::Query::<WsParams>::from_query(req.query_string()).map(web::Query::into_inner).unwrap_or_default();
+    // Sessions following a profile read its config and broadcasts in place of the shared ones
+    let (config, updates) = match &params.profile {
+        Some(name) => {
+            let Some(profile) = data.profiles.get(name) else {
+                let e = WsError::UnknownProfile(name.clone());
+                debug!("Refused WebSocket handshake: {}", e);
+                return Err(e.into());
+            };
+            (profile.config.clone(), profile.broadcaster.subscribe())
+        }
+        None => (data.config.clone(), broadcaster.subscribe()),
+    };
+    // Same condition as `/ready`'s `config_loaded`, checked before upgrading so the client never connects
+    if data.settings.ws_unavailable_mode == UnavailableMode::Refuse && read_lock(&config).is_none() {
+        debug!("Refused WebSocket handshake: {}", WsError::NotReady);
+        return Err(WsError::NotReady.into());
+    }
+    let upgrade_permit = data.upgrades.acquire().await.map_err(|e| {
+        debug!("Refused WebSocket handshake: {}", e);
+        data.metrics.increment_counter(WS_UPGRADES_REJECTED_TOTAL, &[]);
+        e
+    })?;
 
-pub async fn config_ws(req: HttpRequest, stream: web::Payload, data: web::Data<AppState>, ws_manager: web::Data<Addr<WsManager>>) -> HttpResponse {
-    debug!("Starting WebSocket session for request: {:?}", req);
+    let id = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
+    let info = SessionInfo {
+        id,
+        connected_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        remote_ip: req.peer_addr().map(|addr| addr.ip()),
+        tag: params.tag,
+        protocol,
+        profile: params.profile,
+    };
     let actor = ConfigWs {
-        config: data.config.clone(),
+        id,
+        info,
+        connected_at: Instant::now(),
+        metrics: data.metrics.clone(),
+        config,
         ws_manager: ws_manager.get_ref().clone(),
+        updates: Some(updates),
+        subscription: None,
+        last_sent: None,
+        pending: None,
+        awaiting_config: false,
+        unavailable_mode: data.settings.ws_unavailable_mode,
+        unavailable_grace: Duration::from_millis(data.settings.ws_unavailable_grace_ms),
+        grace_timer: None,
+        unknown_commands: 0,
+        seen_message: false,
+        hello_window: match Duration::from_millis(data.settings.ws_hello_window_ms) {
+            window if window.is_zero() && data.settings.ws_min_protocol_version > LEGACY_PROTOCOL_VERSION => MIN_VERSION_HELLO_WINDOW,
+            window => window,
+        },
+        hello_timer: None,
+        initial_sent: false,
+        max_unknown_commands: data.settings.ws_max_unknown_commands,
+        protocol_errors: 0,
+        max_protocol_errors: data.settings.ws_max_protocol_errors,
+        fragments: None,
+        max_message_size: data.settings.ws_max_message_size,
+        max_lifetime: Duration::from_secs(data.settings.ws_max_session_secs),
+        history_size: data.settings.ws_replay_size,
+        min_protocol_version: data.settings.ws_min_protocol_version,
+        hello_version: None,
+        echo: data.settings.ws_echo_commands,
+        manager_timeout: Duration::from_millis(data.settings.ws_manager_timeout_ms),
+        skip_duplicates: data.settings.ws_skip_duplicates,
+        delivered: None,
+        upgrade_permit,
+        heartbeat_interval: Duration::from_secs(data.settings.ws_heartbeat_interval_secs),
+        pings_unanswered: 0,
+        last_pong: None,
     };
-    ws::start(actor, &req, stream)
-        .unwrap_or_else(|e| {
-            error!("Error starting WebSocket session: {:?}", e);
-            HttpResponse::InternalServerError().finish()
-        })
-}
\ No newline at end of file
+    ws::WsResponseBuilder::new(actor, &req, stream)
+        .protocols(SUPPORTED_PROTOCOLS)
+        .frame_size(data.settings.ws_max_frame_size)
+        .start()
+        .map_err(|e| WsError::start_failed(e).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use actix::dev::channel;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{App, HttpServer};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::fetch::commit_config;
+    use crate::settings::Settings;
+    use crate::source::InMemorySource;
+
+    const WAIT: Duration = Duration::from_secs(2); // Longest a client waits for a frame it expects
+
+    // What `config_ws` runs against: the state, with the default config loaded, and the manager
+    // built from its settings the way `main` builds it.
+    struct Harness {
+        state: web::Data<AppState>,
+        manager: Addr<WsManager>,
+        broadcaster: ConfigBroadcaster,
+    }
+
+    impl Harness {
+        fn new(configure: impl FnOnce(&mut Settings)) -> Self {
+            let mut settings = Settings::from_env();
+            configure(&mut settings);
+            let state = AppState::new(settings, Box::new(InMemorySource::default()));
+            *state.config.write().unwrap() = Some(Config::default());
+            let manager = WsManager::with_capacity(state.settings.ws_send_queue_capacity)
+                .with_replay(state.settings.ws_replay_size)
+                .with_min_interval(Duration::from_millis(state.settings.ws_min_broadcast_interval_ms))
+                .with_field_throttle(state.settings.ws_throttled_fields.clone(), Duration::from_millis(state.settings.ws_field_throttle_ms))
+                .with_events(state.events.subscribe());
+            let broadcaster = manager.broadcaster();
+            Self { state: web::Data::new(state), manager: manager.start(), broadcaster }
+        }
+
+        // Register `/ws/` and the app data it needs.
+        fn configure(&self, cfg: &mut web::ServiceConfig) {
+            cfg.app_data(self.state.clone())
+                .app_data(web::Data::new(self.manager.clone()))
+                .app_data(web::Data::new(self.broadcaster.clone()))
+                .route("/ws/", web::get().to(config_ws));
+        }
+
+        // Serve `/ws/` on a free local port from a single worker. Returns the address.
+        fn serve(&self) -> String {
+            self.serve_with(|_| ())
+        }
+
+        // Serve `/ws/` like `serve`, along with the routes `extra` adds.
+        fn serve_with(&self, extra: impl Fn(&mut web::ServiceConfig) + Clone + Send + 'static) -> String {
+            let (state, manager, broadcaster) = (self.state.clone(), self.manager.clone(), self.broadcaster.clone());
+            let server = HttpServer::new(move || {
+                App::new()
+                    .app_data(state.clone())
+                    .app_data(web::Data::new(manager.clone()))
+                    .app_data(web::Data::new(broadcaster.clone()))
+                    .route("/ws/", web::get().to(config_ws))
+                    .configure(extra.clone())
+            })
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+            let addr = server.addrs()[0].to_string();
+            actix_web::rt::spawn(server.run());
+            addr
+        }
+    }
+
+    // A WebSocket client: masks what it sends, as clients must, and reads the server's frames,
+    // which are never masked or fragmented.
+    struct Client {
+        stream: TcpStream,
+        buf: Vec<u8>,
+    }
+
+    impl Client {
+        // Connect and read the initial config.
+        async fn connect(addr: &str) -> (Client, Value) {
+            Client::connect_to(addr, "/ws/").await
+        }
+
+        // Connect to `path`, e.g. `/ws/?profile=east`, and read the initial config.
+        async fn connect_to(addr: &str, path: &str) -> (Client, Value) {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let request = format!(
+                "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+                path, addr
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut client = Client { stream, buf: Vec::new() };
+            let end = loop {
+                if let Some(end) = client.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break end + 4;
+                }
+                assert!(client.fill().await, "server closed the connection during the handshake");
+            };
+            let head = String::from_utf8_lossy(&client.buf[..end]).into_owned();
+            assert!(head.starts_with("HTTP/1.1 101"), "upgrade refused: {}", head);
+            client.buf.drain(..end);
+            let config = client.text().await;
+            (client, config)
+        }
+
+        // Read what the server sent since; false once it has closed the connection.
+        async fn fill(&mut self) -> bool {
+            let mut chunk = [0; 4096];
+            let n = self.stream.read(&mut chunk).await.unwrap();
+            self.buf.extend_from_slice(&chunk[..n]);
+            n > 0
+        }
+
+        // Send a single frame, e.g. opcode 1 for text or 0 for a continuation.
+        async fn send_frame(&mut self, opcode: u8, fin: bool, payload: &[u8]) {
+            let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+            match payload.len() {
+                len @ 0..=125 => frame.push(0x80 | len as u8),
+                len @ 126..=0xffff => {
+                    frame.push(0x80 | 126);
+                    frame.extend_from_slice(&(len as u16).to_be_bytes());
+                }
+                len => {
+                    frame.push(0x80 | 127);
+                    frame.extend_from_slice(&(len as u64).to_be_bytes());
+                }
+            }
+            let mask = [0x12, 0x34, 0x56, 0x78];
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+            self.stream.write_all(&frame).await.unwrap();
+        }
+
+        async fn send_text(&mut self, text: &str) {
+            self.send_frame(1, true, text.as_bytes()).await;
+        }
+
+        // The next frame's opcode and payload, or `None` once the connection is closed.
+        async fn next_frame(&mut self) -> Option<(u8, Vec<u8>)> {
+            loop {
+                if self.buf.len() >= 2 {
+                    let (len, header) = match self.buf[1] & 0x7f {
+                        126 if self.buf.len() >= 4 => (u16::from_be_bytes([self.buf[2], self.buf[3]]) as usize, 4),
+                        127 if self.buf.len() >= 10 => (u64::from_be_bytes(self.buf[2..10].try_into().unwrap()) as usize, 10),
+                        126 | 127 => (usize::MAX, 0),
+                        len => (len as usize, 2),
+                    };
+                    if len != usize::MAX && self.buf.len() >= header + len {
+                        let opcode = self.buf[0] & 0x0f;
+                        return Some((opcode, self.buf.drain(..header + len).skip(header).collect()));
+                    }
+                }
+                if !self.fill().await {
+                    return None;
+                }
+            }
+        }
+
+        // The next text message, skipping control frames, failing if the session closes first or
+        // nothing arrives within `WAIT`.
+        async fn text(&mut self) -> Value {
+            let message = timeout(WAIT, async {
+                loop {
+                    match self.next_frame().await {
+                        Some((1, payload)) => return serde_json::from_slice(&payload).unwrap(),
+                        Some((8, payload)) => panic!("session closed: {:?}", String::from_utf8_lossy(&payload)),
+                        Some(_) => continue,
+                        None => panic!("connection closed"),
+                    }
+                }
+            })
+            .await;
+            message.expect("no message in time")
+        }
+
+        // The code of the close frame the server sends next, failing on a text message first.
+        async fn close_code(&mut self) -> u16 {
+            let code = timeout(WAIT, async {
+                loop {
+                    match self.next_frame().await {
+                        Some((8, payload)) => return u16::from_be_bytes([payload[0], payload[1]]),
+                        Some((1, payload)) => panic!("expected a close, got {}", String::from_utf8_lossy(&payload)),
+                        Some(_) => continue,
+                        None => panic!("connection closed without a close frame"),
+                    }
+                }
+            })
+            .await;
+            code.expect("no close frame in time")
+        }
+
+        // Fail if a text message arrives within `within`.
+        async fn expect_silence(&mut self, within: Duration) {
+            if let Ok(message) = timeout(within, self.text()).await {
+                panic!("unexpected message: {}", message);
+            }
+        }
+    }
+
+    // A well-formed upgrade request for `/ws/`.
+    fn upgrade_request() -> TestRequest {
+        TestRequest::get()
+            .uri("/ws/")
+            .insert_header((header::UPGRADE, "websocket"))
+            .insert_header((header::CONNECTION, "Upgrade"))
+            .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="))
+            .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+    }
+
+    // A session address for the manager to hold; it stays connected while the receiver lives.
+    fn session_addr() -> (Addr<ConfigWs>, channel::AddressReceiver<ConfigWs>) {
+        let (tx, rx) = channel::channel(16);
+        (Addr::new(tx), rx)
+    }
+
+    fn session_info(id: SessionId) -> SessionInfo {
+        SessionInfo { id, connected_at: 0, remote_ip: None, tag: None, protocol: None, profile: None }
+    }
+
+    #[actix_web::test]
+    async fn duplicate_connect_keeps_a_single_session() {
+        let manager = WsManager::new().start();
+        let (addr, _rx) = session_addr();
+
+        manager.send(Connect { addr: addr.clone(), info: session_info(7) }).await.unwrap();
+        manager.send(Connect { addr, info: session_info(7) }).await.unwrap();
+        assert_eq!(manager.send(SessionCount).await.unwrap(), 1);
+    }
+
+    #[actix_web::test]
+    async fn tap_sees_each_broadcast() {
+        let (tx, mut tap) = mpsc::channel(4);
+        let manager = WsManager::new().with_tap(tx).start();
+        let config = Config::builder().ball_color("red").build();
+
+        manager.send(GenericWsMessage { config: config.clone(), source: Some(ChangeSource::Admin) }).await.unwrap();
+        let seen = tap.recv().await.unwrap();
+        assert_eq!(seen.config, config);
+        assert_eq!(seen.source, Some(ChangeSource::Admin));
+    }
+
+    #[actix_web::test]
+    async fn broadcast_carries_only_the_changed_fields() {
+        let manager = WsManager::new();
+        let mut updates = manager.broadcaster().subscribe();
+        let manager = manager.start();
+
+        manager.send(GenericWsMessage { config: Config::default(), source: None }).await.unwrap();
+        manager.send(GenericWsMessage { config: Config::builder().ball_size(80).build(), source: None }).await.unwrap();
+        assert!(!updates.recv().await.unwrap().patch);
+        let update = updates.recv().await.unwrap();
+        assert!(update.patch);
+        assert_eq!(update.fields.keys().collect::<Vec<_>>(), ["ball_size"]);
+    }
+
+    #[actix_web::test]
+    async fn handshake_from_a_disallowed_origin_gets_403() {
+        let harness = Harness::new(|settings| settings.ws_allowed_origins = vec!["https://allowed.example".to_string()]);
+        let app = init_service(App::new().configure(|cfg| harness.configure(cfg))).await;
+
+        let req = upgrade_request().insert_header((header::ORIGIN, "https://evil.example")).to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn handshake_from_an_allowed_origin_upgrades() {
+        let harness = Harness::new(|settings| settings.ws_allowed_origins = vec!["https://allowed.example".to_string()]);
+        let app = init_service(App::new().configure(|cfg| harness.configure(cfg))).await;
+
+        let req = upgrade_request().insert_header((header::ORIGIN, "https://allowed.example")).to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    #[actix_web::test]
+    async fn subscriber_only_hears_about_its_fields() {
+        let harness = Harness::new(|_| ());
+        let (mut client, _) = Client::connect(&harness.serve()).await;
+        client.send_text(r#"{"type":"subscribe","fields":["ball_color"]}"#).await;
+        // Answered after the subscription is in place
+        client.send_text("peer_count").await;
+        assert_eq!(client.text().await["type"], "peer_count");
+
+        commit_config(&harness.state, Config::builder().number_of_balls(9).build(), ChangeSource::Admin);
+        client.expect_silence(Duration::from_millis(300)).await;
+
+        commit_config(&harness.state, Config::builder().number_of_balls(9).ball_color("red").build(), ChangeSource::Admin);
+        let patch = client.text().await;
+        assert_eq!(patch["type"], "patch");
+        assert_eq!(patch["changes"]["ball_color"], "red");
+    }
+
+    #[actix_web::test]
+    async fn peer_count_counts_every_client() {
+        let harness = Harness::new(|_| ());
+        let addr = harness.serve();
+        let (mut first, _) = Client::connect(&addr).await;
+        let (_second, _) = Client::connect(&addr).await;
+
+        first.send_text("peer_count").await;
+        assert_eq!(first.text().await, serde_json::json!({ "type": "peer_count", "count": 2 }));
+    }
+
+    #[actix_web::test]
+    async fn broadcast_prunes_closed_sessions() {
+        let manager = WsManager::new().start();
+        let (open, _open_rx) = session_addr();
+        let (closed, closed_rx) = session_addr();
+        manager.send(Connect { addr: open, info: session_info(1) }).await.unwrap();
+        manager.send(Connect { addr: closed, info: session_info(2) }).await.unwrap();
+        drop(closed_rx);
+        assert_eq!(manager.send(SessionCount).await.unwrap(), 2);
+
+        manager.send(GenericWsMessage { config: Config::default(), source: None }).await.unwrap();
+        assert_eq!(manager.send(SessionCount).await.unwrap(), 1);
+    }
+
+    #[actix_web::test]
+    async fn burst_of_broadcasts_reaches_a_busy_client_as_the_latest_config() {
+        let harness = Harness::new(|_| ());
+        // `/block` holds up the server's only worker, and so the session, until released
+        let (blocked_tx, mut blocked) = mpsc::unbounded_channel();
+        let (release, release_rx) = std::sync::mpsc::channel::<()>();
+        let release_rx = Arc::new(std::sync::Mutex::new(release_rx));
+        let addr = harness.serve_with(move |cfg| {
+            let (blocked_tx, release_rx) = (blocked_tx.clone(), release_rx.clone());
+            cfg.route(
+                "/block",
+                web::get().to(move || {
+                    let _ = blocked_tx.send(());
+                    let _ = release_rx.lock().unwrap().recv();
+                    async { HttpResponse::Ok().finish() }
+                }),
+            );
+        });
+        let (mut client, _) = Client::connect(&addr).await;
+        let mut updates = harness.broadcaster.subscribe();
+
+        let mut blocker = TcpStream::connect(&addr).await.unwrap();
+        blocker.write_all(b"GET /block HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        blocked.recv().await.unwrap();
+        for balls in [6, 7, 8] {
+            commit_config(&harness.state, Config::builder().number_of_balls(balls).build(), ChangeSource::Admin);
+            updates.recv().await.unwrap();
+        }
+        release.send(()).unwrap();
+
+        let config = client.text().await;
+        assert_eq!(config.get("type"), None, "expected the full config, got {}", config);
+        assert_eq!(config["number_of_balls"], 8);
+        client.expect_silence(Duration::from_millis(300)).await;
+    }
+
+    #[actix_web::test]
+    async fn frame_over_the_size_limit_closes_the_session() {
+        let harness = Harness::new(|settings| settings.ws_max_frame_size = 64);
+        let (mut client, _) = Client::connect(&harness.serve()).await;
+
+        client.send_text(&"x".repeat(100)).await;
+        assert_eq!(client.close_code().await, u16::from(ws::CloseCode::Size));
+    }
+
+    #[actix_web::test]
+    async fn reaper_removes_sessions_that_stopped_without_disconnecting() {
+        tokio::time::pause();
+        let manager = WsManager::new().start();
+        let (addr, rx) = session_addr();
+        manager.send(Connect { addr, info: session_info(3) }).await.unwrap();
+        drop(rx);
+        assert_eq!(manager.send(SessionCount).await.unwrap(), 1);
+
+        tokio::time::sleep(REAP_INTERVAL + Duration::from_secs(1)).await;
+        assert_eq!(manager.send(SessionCount).await.unwrap(), 0);
+    }
+
+    #[actix_web::test]
+    async fn configs_held_during_a_pause_go_out_as_one_broadcast() {
+        let (tx, mut tap) = mpsc::channel(8);
+        let manager = WsManager::new().with_tap(tx).start();
+
+        manager.send(PauseBroadcasts).await.unwrap();
+        for balls in [6, 7, 8] {
+            manager.send(GenericWsMessage { config: Config::builder().number_of_balls(balls).build(), source: None }).await.unwrap();
+        }
+        assert!(tap.try_recv().is_err());
+
+        assert!(manager.send(ResumeBroadcasts).await.unwrap());
+        assert_eq!(tap.try_recv().unwrap().config.number_of_balls, 8);
+        assert!(tap.try_recv().is_err());
+    }
+
+    #[actix_web::test]
+    async fn unknown_command_gets_an_error_reply() {
+        let harness = Harness::new(|_| ());
+        let (mut client, _) = Client::connect(&harness.serve()).await;
+
+        client.send_text("bogus").await;
+        assert_eq!(client.text().await, serde_json::json!({ "type": "error", "message": "unknown command: bogus" }));
+    }
+
+    #[actix_web::test]
+    async fn new_client_is_replayed_the_latest_broadcasts() {
+        let harness = Harness::new(|settings| settings.ws_replay_size = 2);
+        let addr = harness.serve();
+        let mut updates = harness.broadcaster.subscribe();
+        for balls in [6, 7, 8] {
+            commit_config(&harness.state, Config::builder().number_of_balls(balls).build(), ChangeSource::Admin);
+            updates.recv().await.unwrap();
+        }
+
+        let (mut client, config) = Client::connect(&addr).await;
+        assert_eq!(config["number_of_balls"], 8);
+        let replay = client.text().await;
+        assert_eq!(replay["type"], "replay");
+        let balls: Vec<&Value> = replay["history"].as_array().unwrap().iter().map(|config| &config["number_of_balls"]).collect();
+        assert_eq!(balls, [7, 8]);
+    }
+
+    #[actix_web::test]
+    async fn repeated_protocol_errors_close_the_session() {
+        let harness = Harness::new(|settings| settings.ws_max_protocol_errors = 3);
+        let (mut client, _) = Client::connect(&harness.serve()).await;
+
+        // A continuation frame with no message to continue is malformed; below the threshold, and
+        // with a valid message in between, the session stays open
+        for _ in 0..2 {
+            client.send_frame(0, true, b"stray").await;
+        }
+        client.send_text("get_config").await;
+        assert_eq!(client.text().await["number_of_balls"], Config::default().number_of_balls);
+
+        for _ in 0..3 {
+            client.send_frame(0, true, b"stray").await;
+        }
+        assert_eq!(client.close_code().await, u16::from(ws::CloseCode::Protocol));
+    }
+
+    #[actix_web::test]
+    async fn fragmented_get_config_is_answered() {
+        let harness = Harness::new(|_| ());
+        let (mut client, _) = Client::connect(&harness.serve()).await;
+        commit_config(&harness.state, Config::builder().ball_color("red").build(), ChangeSource::Admin);
+        assert_eq!(client.text().await["ball_color"], "red");
+
+        client.send_frame(1, false, b"get_").await;
+        client.send_frame(0, true, b"config").await;
+        let config = client.text().await;
+        assert_eq!(config.get("type"), None, "expected the full config, got {}", config);
+        assert_eq!(config["ball_color"], "red");
+    }
+
+    #[actix_web::test]
+    async fn broadcasts_are_spaced_by_the_minimum_interval() {
+        let interval = Duration::from_millis(200);
+        let (tx, mut tap) = mpsc::channel(8);
+        let manager = WsManager::new().with_min_interval(interval).with_tap(tx).start();
+
+        let start = Instant::now();
+        for balls in [6, 7, 8] {
+            manager.send(GenericWsMessage { config: Config::builder().number_of_balls(balls).build(), source: None }).await.unwrap();
+        }
+        // The first goes out right away, and only the latest of the rest once the interval has passed
+        assert_eq!(tap.recv().await.unwrap().config.number_of_balls, 6);
+        assert_eq!(tap.recv().await.unwrap().config.number_of_balls, 8);
+        assert!(start.elapsed() >= interval, "second broadcast after {:?}", start.elapsed());
+        assert!(timeout(interval * 2, tap.recv()).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn upgrade_past_the_concurrency_limit_gets_503() {
+        let harness = Harness::new(|settings| {
+            settings.ws_max_concurrent_upgrades = 1;
+            settings.ws_upgrade_queue_size = 0;
+        });
+        let app = init_service(App::new().configure(|cfg| harness.configure(cfg))).await;
+
+        let in_progress = harness.state.upgrades.acquire().await.unwrap();
+        assert_eq!(call_service(&app, upgrade_request().to_request()).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+        drop(in_progress);
+        assert_eq!(call_service(&app, upgrade_request().to_request()).await.status(), StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    #[actix_web::test]
+    async fn throttled_field_waits_while_others_go_out_right_away() {
+        let interval = Duration::from_millis(200);
+        let (tx, mut tap) = mpsc::channel(8);
+        let manager = WsManager::new().with_field_throttle(vec!["number_of_balls".to_string()], interval).with_tap(tx).start();
+        let start = Instant::now();
+        manager.send(GenericWsMessage { config: Config::default(), source: None }).await.unwrap();
+        tap.recv().await.unwrap();
+
+        let changed = Config::builder().number_of_balls(9).ball_color("red").build();
+        manager.send(GenericWsMessage { config: changed.clone(), source: None }).await.unwrap();
+        let prompt = tap.recv().await.unwrap().config;
+        assert_eq!(prompt.ball_color, "red");
+        assert_eq!(prompt.number_of_balls, Config::default().number_of_balls);
+
+        assert_eq!(tap.recv().await.unwrap().config, changed);
+        assert!(start.elapsed() >= interval, "number_of_balls went out after {:?}", start.elapsed());
+    }
+
+    #[actix_web::test]
+    async fn plain_get_gets_426_and_a_malformed_upgrade_400() {
+        let harness = Harness::new(|_| ());
+        let app = init_service(App::new().configure(|cfg| harness.configure(cfg))).await;
+
+        let plain = TestRequest::get().uri("/ws/").to_request();
+        assert_eq!(call_service(&app, plain).await.status(), StatusCode::UPGRADE_REQUIRED);
+
+        let without_key = TestRequest::get()
+            .uri("/ws/")
+            .insert_header((header::UPGRADE, "websocket"))
+            .insert_header((header::CONNECTION, "Upgrade"))
+            .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+            .to_request();
+        assert_eq!(call_service(&app, without_key).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn text_over_the_message_limit_closes_without_an_answer() {
+        let harness = Harness::new(|settings| settings.ws_max_message_size = 8);
+        let (mut client, _) = Client::connect(&harness.serve()).await;
+
+        // Ten bytes, so never handled as the command it would be
+        client.send_text("get_config").await;
+        assert_eq!(client.close_code().await, u16::from(ws::CloseCode::Size));
+    }
+
+    #[actix_web::test]
+    async fn removing_a_profile_closes_its_subscribers_and_leaves_the_rest() {
+        let harness = Harness::new(|settings| settings.admin_token = Some("token".to_string()));
+        // Never polled here, so the URLs don't need to answer
+        let urls = ["east", "west"].map(|name| (name.to_string(), format!("http://127.0.0.1:9/{}", name)));
+        harness.state.profiles.replace(&BTreeMap::from(urls)).unwrap();
+        for (name, color) in [("east", "red"), ("west", "green")] {
+            *harness.state.profiles.get(name).unwrap().config.write().unwrap() = Some(Config::builder().ball_color(color).build());
+        }
+        let addr = harness.serve_with(|cfg| {
+            cfg.route("/admin/profiles", web::put().to(crate::admin::set_profiles));
+        });
+
+        let (mut east, config) = Client::connect_to(&addr, "/ws/?profile=east").await;
+        assert_eq!(config["ball_color"], "red");
+        let (mut west, config) = Client::connect_to(&addr, "/ws/?profile=west").await;
+        assert_eq!(config["ball_color"], "green");
+        let (mut shared, _) = Client::connect(&addr).await;
+
+        let response: Value = reqwest::Client::new()
+            .put(format!("http://{}/admin/profiles", addr))
+            .bearer_auth("token")
+            .json(&serde_json::json!({ "west": "http://127.0.0.1:9/west" }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(response, serde_json::json!({ "profiles": ["west"], "removed": ["east"], "closed": 1 }));
+
+        assert_eq!(east.close_code().await, u16::from(ws::CloseCode::Away));
+        west.send_text("get_config").await;
+        assert_eq!(west.text().await["ball_color"], "green");
+        shared.send_text("get_config").await;
+        assert_eq!(shared.text().await["ball_color"], "blue");
+        assert!(harness.state.profiles.get("east").is_none());
+    }
+}
@@ -0,0 +1,175 @@
+// End to end: the server binary polls a mock upstream, and a WebSocket client sees each config
+// the upstream serves. Only built with the `bin` feature (on by default), since it runs the binary.
+//
+// Run with `cargo test --test config_ws`.
+#![cfg(feature = "bin")]
+
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::{web, App, HttpResponse, HttpServer};
+use serde_json::Value;
+use subscriber_microservice::Config;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The server process, killed when the test ends, however it ends.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// A WebSocket client reading the text frames the server sends. Server frames are never masked
+// or fragmented, which keeps this small.
+struct Client {
+    stream: TcpStream,
+    buf: Vec<u8>,
+}
+
+impl Client {
+    async fn connect(addr: &str) -> Client {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "GET /ws/ HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            addr
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut client = Client { stream, buf: Vec::new() };
+        let end = loop {
+            if let Some(end) = client.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end + 4;
+            }
+            client.fill().await;
+        };
+        let head = String::from_utf8_lossy(&client.buf[..end]).into_owned();
+        assert!(head.starts_with("HTTP/1.1 101"), "upgrade refused: {}", head);
+        client.buf.drain(..end);
+        client
+    }
+
+    async fn fill(&mut self) {
+        let mut chunk = [0; 4096];
+        let n = self.stream.read(&mut chunk).await.unwrap();
+        assert!(n > 0, "server closed the connection");
+        self.buf.extend_from_slice(&chunk[..n]);
+    }
+
+    // The next text message, skipping pings and other control frames.
+    async fn next_text(&mut self) -> Value {
+        loop {
+            while self.buf.len() < 2 {
+                self.fill().await;
+            }
+            let (len, header) = match self.buf[1] & 0x7f {
+                126 => {
+                    while self.buf.len() < 4 {
+                        self.fill().await;
+                    }
+                    (u16::from_be_bytes([self.buf[2], self.buf[3]]) as usize, 4)
+                }
+                127 => {
+                    while self.buf.len() < 10 {
+                        self.fill().await;
+                    }
+                    (u64::from_be_bytes(self.buf[2..10].try_into().unwrap()) as usize, 10)
+                }
+                len => (len as usize, 2),
+            };
+            while self.buf.len() < header + len {
+                self.fill().await;
+            }
+            let opcode = self.buf[0] & 0x0f;
+            let payload: Vec<u8> = self.buf.drain(..header + len).skip(header).collect();
+            if opcode == 1 {
+                return serde_json::from_slice(&payload).unwrap();
+            }
+        }
+    }
+
+    // Read messages until one leaves the client with `ball_color` set to `color`, failing after `within`.
+    async fn expect_color(&mut self, color: &str, within: Duration) {
+        let seen = timeout(within, async {
+            loop {
+                let message = self.next_text().await;
+                let fields = match message.get("type").and_then(Value::as_str) {
+                    Some("patch") => &message["changes"],
+                    Some(_) => continue,
+                    None => &message,
+                };
+                if fields.get("ball_color").and_then(Value::as_str) == Some(color) {
+                    return;
+                }
+            }
+        })
+        .await;
+        assert!(seen.is_ok(), "no config with ball_color {:?} within {:?}", color, within);
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+// Serve whatever config is in `current`, on a free local port. Returns its URL.
+fn mock_upstream(current: Arc<Mutex<Config>>) -> String {
+    let server = HttpServer::new(move || {
+        let current = current.clone();
+        App::new().default_service(web::to(move || {
+            let config = current.lock().unwrap().clone();
+            async move { HttpResponse::Ok().json(config) }
+        }))
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let url = format!("http://{}/config", server.addrs()[0]);
+    actix_web::rt::spawn(server.run());
+    url
+}
+
+fn start_server(addr: &str, upstream: &str) -> Server {
+    let child = Command::new(env!("CARGO_BIN_EXE_subscriber_microservice"))
+        .env("BIND_ADDRESS", addr)
+        .env("CONFIG_UPSTREAM_URLS", upstream)
+        .env("POLL_INTERVAL_SECS", POLL_INTERVAL.as_secs().to_string())
+        .env("BLOCK_UNTIL_READY", "true")
+        .env("RUST_LOG", "warn")
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    Server(child)
+}
+
+async fn wait_until_listening(addr: &str) {
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while TcpStream::connect(addr).await.is_err() {
+        assert!(Instant::now() < deadline, "server didn't start listening on {}", addr);
+        sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[actix_web::test]
+async fn client_sees_the_upstreams_change_within_one_poll() {
+    let current = Arc::new(Mutex::new(Config::builder().ball_color("red").build()));
+    let upstream = mock_upstream(current.clone());
+    let addr = format!("127.0.0.1:{}", free_port());
+    let _server = start_server(&addr, &upstream);
+    wait_until_listening(&addr).await;
+
+    let mut client = Client::connect(&addr).await;
+    client.expect_color("red", STARTUP_TIMEOUT).await;
+
+    *current.lock().unwrap() = Config::builder().ball_color("blue").build();
+    // One poll cycle, plus slack for the fetch and the fan-out
+    client.expect_color("blue", POLL_INTERVAL + Duration::from_secs(1)).await;
+}